@@ -0,0 +1,3340 @@
+//! Seeded random MIDI melody generation, usable without the command line.
+//!
+//! Build a [`GenConfig`] (its `Default` matches the CLI defaults), call [`generate`] and
+//! inspect or edit `seq.notes`, then write it with [`save_sequence`]. The same config
+//! always produces the same file.
+
+use clap::ValueEnum;
+use midly::{Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+use rand::{Rng, RngCore, SeedableRng};
+use rand_chacha::{ChaCha20Rng, ChaCha8Rng};
+use rand_pcg::Pcg64;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+
+pub use crate::generate_sequence as generate;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ScaleOpt {
+    Major,
+    NaturalMinor,
+    MinorPentatonic,
+    MajorPentatonic,
+    Dorian,
+    Phrygian,
+    Lydian,
+    Mixolydian,
+    Locrian,
+}
+
+/// Direction an arpeggio walks the triad built on the current scale degree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Arp {
+    /// Root, third, fifth
+    Up,
+    /// Fifth, third, root
+    Down,
+    /// Root, third, fifth, third
+    Updown,
+}
+
+impl Arp {
+    /// Scale degrees above the chord root for arpeggio step `index`.
+    fn degree(self, index: u32) -> i32 {
+        let pattern: &[i32] = match self {
+            Arp::Up => &[0, 2, 4],
+            Arp::Down => &[4, 2, 0],
+            Arp::Updown => &[0, 2, 4, 2],
+        };
+        pattern[index as usize % pattern.len()]
+    }
+}
+
+/// What happens to a pitch outside `min_note..=max_note`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum RangePolicy {
+    /// Move it by octaves into range, keeping its pitch class
+    Fold,
+    /// Pin it to the nearest bound
+    Clamp,
+    /// Leave a rest in its place
+    Drop,
+}
+
+/// The shape of the `--humanize` jitter within its bounds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum HumanizeDist {
+    /// Any offset in the bounds equally likely
+    Uniform,
+    /// Bunched near the grid: a normal draw with the bound at three sigma
+    Gaussian,
+}
+
+impl HumanizeDist {
+    /// A draw in -1..=1 of this shape.
+    fn unit(self, rng: &mut impl Rng) -> f32 {
+        match self {
+            HumanizeDist::Uniform => rng.gen_range(-1.0f32..=1.0),
+            HumanizeDist::Gaussian => {
+                // Box-Muller; u1 stays off zero so its log is finite.
+                let u1 = rng.gen_range(f32::EPSILON..1.0);
+                let u2 = rng.gen_range(0.0f32..1.0);
+                let normal = (-2.0 * u1.ln()).sqrt() * (std::f32::consts::TAU * u2).cos();
+                (normal / 3.0).clamp(-1.0, 1.0)
+            }
+        }
+    }
+}
+
+/// How the random base velocity spreads over `velocity_min..velocity_max`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum VelocityCurve {
+    /// Uniform over the range
+    Linear,
+    /// Bunched toward the loud end
+    Exp,
+    /// Bunched toward the quiet end
+    Log,
+}
+
+impl VelocityCurve {
+    /// Reshapes a uniform draw from `min..max` onto the curve, keeping the ends.
+    fn shape(self, raw: u8, min: u8, max: u8) -> u8 {
+        let span = (max - min - 1) as f32;
+        if span <= 0.0 {
+            return raw;
+        }
+        let t = (raw - min) as f32 / span;
+        let t = match self {
+            VelocityCurve::Linear => return raw,
+            VelocityCurve::Exp => 1.0 - (1.0 - t) * (1.0 - t),
+            VelocityCurve::Log => t * t,
+        };
+        min + (t * span).round() as u8
+    }
+}
+
+/// Deterministic practice runs that replace the random melody.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Exercise {
+    /// The scale from the root up
+    ScaleUp,
+    /// Up the scale and back down
+    ScaleUpdown,
+    /// Root, third and fifth up and back down
+    Arpeggio,
+}
+
+/// How many stacked scale thirds each `--chords` chord holds, root included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ChordExtensions {
+    /// Root, third and fifth
+    Triad,
+    /// Adds the seventh
+    #[value(name = "7")]
+    Seventh,
+    /// Adds the seventh and ninth
+    #[value(name = "9")]
+    Ninth,
+    /// Up to the eleventh
+    #[value(name = "11")]
+    Eleventh,
+    /// Up to the thirteenth
+    #[value(name = "13")]
+    Thirteenth,
+}
+
+impl ChordExtensions {
+    /// Chord tones, root included.
+    pub fn tones(self) -> usize {
+        match self {
+            ChordExtensions::Triad => 3,
+            ChordExtensions::Seventh => 4,
+            ChordExtensions::Ninth => 5,
+            ChordExtensions::Eleventh => 6,
+            ChordExtensions::Thirteenth => 7,
+        }
+    }
+}
+
+/// Semitones above the scale root of the chord on `degree`: every other scale degree
+/// from it, an octave up each time the stack wraps past the top of the scale.
+pub fn build_chord(degree: usize, scale: &[i8], extensions: ChordExtensions) -> Vec<i16> {
+    let len = scale.len();
+    (0..extensions.tones())
+        .map(|i| {
+            let degree = degree + 2 * i;
+            scale[degree % len] as i16 + 12 * (degree / len) as i16
+        })
+        .collect()
+}
+
+/// Chord pitches, lowest first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chord(pub Vec<u8>);
+
+/// Parses a Roman numeral, `I` to `VII` (upper case major, lower case minor), into the
+/// chord on that degree of `scale` above `root`. A leading `b` or `#` lowers or raises
+/// it a semitone; after it `°` (or `o`) makes it diminished, `ø` half-diminished, `+`
+/// augmented, and `7` adds the scale's seventh, e.g. `V7`, `vii°7` or `bVII`.
+pub fn parse_roman(numeral: &str, root: Note, scale: &[i8]) -> Result<Chord, String> {
+    let text = numeral.trim();
+    let (shift, rest) = if let Some(rest) = text.strip_prefix(['b', '♭']) {
+        (-1, rest)
+    } else if let Some(rest) = text.strip_prefix(['#', '♯']) {
+        (1, rest)
+    } else {
+        (0, text)
+    };
+    let len = rest
+        .find(|c: char| !matches!(c, 'I' | 'V' | 'i' | 'v'))
+        .unwrap_or(rest.len());
+    let (roman, suffix) = rest.split_at(len);
+    let degree = ["I", "II", "III", "IV", "V", "VI", "VII"]
+        .iter()
+        .position(|&n| n.eq_ignore_ascii_case(roman))
+        .ok_or_else(|| format!("'{numeral}' is not a Roman numeral from I to VII"))?;
+    let upper = roman.chars().all(|c| c.is_ascii_uppercase());
+    if !upper && roman.chars().any(|c| c.is_ascii_uppercase()) {
+        return Err(format!("'{numeral}' mixes upper and lower case"));
+    }
+    if degree >= scale.len() {
+        return Err(format!(
+            "'{numeral}' needs a scale with at least {} degrees",
+            degree + 1
+        ));
+    }
+
+    // Third and fifth above the chord root, and the seventh for the diminished chords
+    // (`None` keeps the scale's own).
+    let (quality, seventh) = match suffix.strip_suffix('7') {
+        Some(quality) => (quality, true),
+        None => (suffix, false),
+    };
+    let (third, fifth, diminished_seventh) = match quality {
+        "" if upper => (4, 7, None),
+        "" => (3, 7, None),
+        "°" | "o" => (3, 6, Some(9)),
+        "ø" => (3, 6, Some(10)),
+        "+" => (4, 8, None),
+        _ => {
+            return Err(format!(
+                "'{numeral}' has an unknown chord suffix '{suffix}'"
+            ))
+        }
+    };
+    let extensions = if seventh || quality == "ø" {
+        ChordExtensions::Seventh
+    } else {
+        ChordExtensions::Triad
+    };
+    let mut tones = build_chord(degree, scale, extensions);
+    tones[1] = tones[0] + third;
+    tones[2] = tones[0] + fifth;
+    if let (Some(semis), 4) = (diminished_seventh, tones.len()) {
+        tones[3] = tones[0] + semis;
+    }
+    tones
+        .into_iter()
+        .map(|semis| {
+            let pitch = root.as_u8() as i16 + semis + shift;
+            u8::try_from(pitch)
+                .ok()
+                .filter(|&p| p <= 127)
+                .ok_or_else(|| format!("'{numeral}' on {root} runs outside MIDI notes 0-127"))
+        })
+        .collect::<Result<_, _>>()
+        .map(Chord)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Note(pub u8);
+
+impl Note {
+    pub fn as_u8(self) -> u8 {
+        self.0
+    }
+}
+
+/// Reads the same scientific pitch strings as the CLI, e.g. `"F#3"`.
+impl<'de> Deserialize<'de> for Note {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        text.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl std::str::FromStr for Note {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let s = input.trim();
+        if s.is_empty() {
+            return Err("empty note".into());
+        }
+
+        let mut it = s.chars();
+        let letter = it.next().ok_or_else(|| "empty note".to_string())?;
+        let base_pc: i32 = match letter.to_ascii_uppercase() {
+            'C' => 0,
+            'D' => 2,
+            'E' => 4,
+            'F' => 5,
+            'G' => 7,
+            'A' => 9,
+            'B' => 11,
+            _ => return Err(format!("bad note letter: {letter}")),
+        };
+
+        let mut pc = base_pc;
+        let mut octave_str = it.as_str();
+
+        if let Some(acc) = it.clone().next() {
+            match acc {
+                '#' | '♯' => {
+                    pc += 1;
+                    it.next();
+                    octave_str = it.as_str();
+                }
+                'b' | 'B' | '♭' => {
+                    pc -= 1;
+                    it.next();
+                    octave_str = it.as_str();
+                }
+                _ => {}
+            }
+        }
+
+        let octave_str = octave_str.trim();
+        if octave_str.is_empty() {
+            return Err("missing octave, expected like C#4".into());
+        }
+
+        let octave: i32 = octave_str
+            .parse()
+            .map_err(|_| format!("bad octave: {octave_str}"))?;
+
+        let midi: i32 = (octave + 1) * 12 + pc;
+
+        if !(0..=127).contains(&midi) {
+            return Err(format!("note out of MIDI range 0..127: {midi}"));
+        }
+
+        Ok(Note(midi as u8))
+    }
+}
+
+/// Scientific pitch name with sharps, e.g. `F#3`.
+impl std::fmt::Display for Note {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        const NAMES: [&str; 12] = [
+            "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+        ];
+        write!(
+            f,
+            "{}{}",
+            NAMES[(self.0 % 12) as usize],
+            self.0 as i32 / 12 - 1
+        )
+    }
+}
+
+/// Velocity boosts keyed by 1-based scale degree, parsed from `deg:boost` pairs.
+#[derive(Debug, Clone)]
+pub struct DegreeAccent(Vec<(usize, u8)>);
+
+impl DegreeAccent {
+    /// Boost for a 0-based degree index into the scale.
+    pub fn boost(&self, degree_index: usize) -> u8 {
+        self.0
+            .iter()
+            .filter(|(deg, _)| *deg == degree_index + 1)
+            .map(|(_, boost)| *boost)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+impl std::fmt::Display for DegreeAccent {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let pairs: Vec<String> = self.0.iter().map(|(d, b)| format!("{d}:{b}")).collect();
+        write!(f, "{}", pairs.join(","))
+    }
+}
+
+impl std::str::FromStr for DegreeAccent {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        input
+            .split(',')
+            .map(|pair| {
+                let (deg, boost) = pair
+                    .trim()
+                    .split_once(':')
+                    .ok_or_else(|| format!("expected deg:boost, got '{pair}'"))?;
+                let deg: usize = deg
+                    .trim()
+                    .parse()
+                    .ok()
+                    .filter(|d| *d >= 1)
+                    .ok_or_else(|| format!("bad scale degree '{deg}' (1-based)"))?;
+                let boost: u8 = boost
+                    .trim()
+                    .parse()
+                    .ok()
+                    .filter(|b| *b <= 127)
+                    .ok_or_else(|| format!("bad velocity boost '{boost}' (0..127)"))?;
+                Ok((deg, boost))
+            })
+            .collect::<Result<Vec<_>, String>>()
+            .map(DegreeAccent)
+    }
+}
+
+/// Time signature `num/den`; bars are laid out on a sixteenth-note grid, so the
+/// denominator is a power of two no finer than 16.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct TimeSig {
+    pub num: u8,
+    pub den: u8,
+}
+
+impl TimeSig {
+    pub const COMMON: TimeSig = TimeSig { num: 4, den: 4 };
+
+    /// Builds a signature, rejecting numerators outside 1..=32 and denominators that
+    /// aren't 1, 2, 4, 8 or 16.
+    pub fn new(num: u8, den: u8) -> Result<Self, String> {
+        if !(1..=32).contains(&num) {
+            return Err(format!(
+                "time signature numerator {num} is out of range 1..32"
+            ));
+        }
+        if !den.is_power_of_two() || den > 16 {
+            return Err(format!(
+                "time signature denominator {den} must be a power of two: 1, 2, 4, 8 or 16"
+            ));
+        }
+        Ok(TimeSig { num, den })
+    }
+
+    /// Sixteenth-note steps in one bar.
+    pub fn steps_per_bar(self) -> u32 {
+        self.num as u32 * 16 / self.den as u32
+    }
+
+    /// Ticks in one bar at `ppqn`, on the same sixteenth grid the generator uses.
+    pub fn bar_ticks(self, ppqn: u16) -> u32 {
+        ppqn as u32 / 4 * self.steps_per_bar()
+    }
+}
+
+impl std::fmt::Display for TimeSig {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}/{}", self.num, self.den)
+    }
+}
+
+impl std::str::FromStr for TimeSig {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let bad = || format!("bad time signature '{input}', expected N/D like 3/4 or 6/8");
+        let (num, den) = input.trim().split_once('/').ok_or_else(bad)?;
+        let num: u8 = num.trim().parse().map_err(|_| bad())?;
+        let den: u8 = den.trim().parse().map_err(|_| bad())?;
+        TimeSig::new(num, den)
+    }
+}
+
+/// A note length as a fraction of a whole note, with a weight for the random choice
+/// between lengths. Written `1/8`, `1/8.` (dotted), `1/4t` (triplet), optionally with a
+/// weight as `1/16:40` (1 to [`NoteLength::MAX_WEIGHT`], default 1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NoteLength {
+    pub num: u32,
+    pub den: u32,
+    /// Half as long again
+    pub dotted: bool,
+    /// Two thirds as long
+    pub triplet: bool,
+    pub weight: u32,
+}
+
+impl NoteLength {
+    /// Largest weight, small enough that 16 of them can't overflow the chooser's sum.
+    pub const MAX_WEIGHT: u32 = 10_000;
+
+    /// Length in ticks at `ppqn`, at least one.
+    pub fn ticks(self, ppqn: u16) -> u32 {
+        let (mul, div) = match (self.dotted, self.triplet) {
+            (true, _) => (3, 2),
+            (_, true) => (2, 3),
+            _ => (1, 1),
+        };
+        let ticks = ppqn as u64 * 4 * self.num as u64 * mul / (self.den as u64 * div);
+        ticks.clamp(1, u32::MAX as u64) as u32
+    }
+}
+
+/// The same form it is parsed from, e.g. `1/8.:3`.
+impl std::fmt::Display for NoteLength {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let suffix = match (self.dotted, self.triplet) {
+            (true, _) => ".",
+            (_, true) => "t",
+            _ => "",
+        };
+        write!(f, "{}/{}{}:{}", self.num, self.den, suffix, self.weight)
+    }
+}
+
+impl std::str::FromStr for NoteLength {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let bad = || {
+            format!(
+                "bad note length '{input}', expected like 1/8, 1/8., 1/4t or 1/16:40 \
+                 (weight 1-{})",
+                NoteLength::MAX_WEIGHT
+            )
+        };
+        let token = input.trim();
+        let (length, weight) = match token.split_once(':') {
+            Some((length, weight)) => (length, weight.trim().parse().map_err(|_| bad())?),
+            None => (token, 1),
+        };
+        let (length, dotted, triplet) = if let Some(rest) = length.strip_suffix('.') {
+            (rest, true, false)
+        } else if let Some(rest) = length.strip_suffix('t') {
+            (rest, false, true)
+        } else {
+            (length, false, false)
+        };
+        let (num, den) = length.split_once('/').ok_or_else(bad)?;
+        let num: u32 = num.trim().parse().map_err(|_| bad())?;
+        let den: u32 = den.trim().parse().map_err(|_| bad())?;
+        if num == 0 || den == 0 || num > 64 || den > 1024 {
+            return Err(bad());
+        }
+        if !(1..=NoteLength::MAX_WEIGHT).contains(&weight) {
+            return Err(bad());
+        }
+        Ok(NoteLength {
+            num,
+            den,
+            dotted,
+            triplet,
+            weight,
+        })
+    }
+}
+
+/// An octave transposition for every section with one form letter, written `B:+12`
+/// (whole octaves, -48 to +48 semitones).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SectionShift {
+    pub letter: char,
+    pub semitones: i8,
+}
+
+impl std::fmt::Display for SectionShift {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{:+}", self.letter, self.semitones)
+    }
+}
+
+impl std::str::FromStr for SectionShift {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let bad = || format!("bad section shift '{input}', expected like B:+12 (whole octaves)");
+        let (letter, semis) = input.trim().split_once(':').ok_or_else(bad)?;
+        let mut chars = letter.trim().chars();
+        let letter = match (chars.next(), chars.next()) {
+            (Some(c), None) if c.is_ascii_alphabetic() => c.to_ascii_uppercase(),
+            _ => return Err(bad()),
+        };
+        let semitones: i8 = semis
+            .trim()
+            .trim_start_matches('+')
+            .parse()
+            .ok()
+            .filter(|s: &i8| (-48..=48).contains(s) && s % 12 == 0)
+            .ok_or_else(bad)?;
+        Ok(SectionShift { letter, semitones })
+    }
+}
+
+/// Everything that shapes a generated file, mirroring the CLI's generation options.
+#[derive(Debug, Clone)]
+pub struct GenConfig {
+    pub seed: u64,
+    pub bpm: u32,
+    pub bars: u32,
+    pub ppqn: u16,
+    pub time_signature: TimeSig,
+    pub root: Note,
+    /// Register bounds; `range_policy` decides what happens to pitches outside
+    pub min_note: Option<Note>,
+    pub max_note: Option<Note>,
+    pub range_policy: RangePolicy,
+    /// A built-in scale name (see [`ScaleOpt`]) or a key of `user_scales`
+    pub scale: String,
+    /// Extra named scales, in semitones above the root
+    pub user_scales: BTreeMap<String, Vec<i8>>,
+    pub channel: u8,
+    pub program: u8,
+    /// Chance (0.0..=1.0) that a sixteenth step gets a note
+    pub density: f32,
+    /// Onset chance multipliers per sixteenth step of the bar, repeating if shorter
+    pub onset_weights: Vec<f32>,
+    /// Note lengths to choose from by weight instead of the built-in 1-4 steps (empty
+    /// keeps those)
+    pub durations: Vec<NoteLength>,
+    /// Every note this many sixteenth steps long
+    pub fixed_length: Option<u32>,
+    /// Hold each note until the next onset on its line, across rests up to `legato_max`
+    pub legato: bool,
+    /// Longest gap in sixteenth steps that legato fills; longer rests stay rests
+    pub legato_max: u32,
+    /// Chance that a whole bar is left silent
+    pub empty_bar_prob: Option<f32>,
+    /// Pitch classes (semitones above the root) removed from the scale
+    pub avoid_pcs: Vec<u8>,
+    /// Absolute pitch classes to draw from instead of `root`/`scale`
+    pub pcset: Vec<u8>,
+    pub degree_accent: Option<DegreeAccent>,
+    /// Base velocity draw, `velocity_min` up to (not including) `velocity_max`, before
+    /// accents
+    pub velocity_curve: VelocityCurve,
+    pub velocity_min: u8,
+    pub velocity_max: u8,
+    pub meter_accents: bool,
+    /// Melody pitches, in order, whose scale-degree transitions drive the degree walk in
+    /// place of the built-in weights (empty keeps the built-in walk)
+    pub corpus: Vec<u8>,
+    /// Stack the third and fifth scale degrees on every note
+    pub chords: bool,
+    /// Further thirds stacked on each chord
+    pub chord_extensions: ChordExtensions,
+    /// Sustain pedal (CC 64) on `channel` down at each chord onset, where two or more
+    /// notes of a track start together, and up a tick before the next
+    pub sustain_from_chords: bool,
+    /// Sound every step, walking the triad on the degree chosen at each beat
+    pub arp: Option<Arp>,
+    /// Restart the melody RNG at every bar from `seed + bar` (bar 0 keeps `seed`)
+    pub reseed_per_bar: bool,
+    pub ratchet_prob: Option<f32>,
+    pub ratchet_count: u32,
+    pub repeat_penalty: Option<f32>,
+    /// Timing and velocity jitter, 0..=100; at 100 onsets move up to a quarter step and
+    /// velocities up to 12 either way
+    pub humanize: Option<u32>,
+    /// Seeds for the humanize timing and velocity jitter (default: `seed`)
+    pub timing_humanize_seed: Option<u64>,
+    pub velocity_humanize_seed: Option<u64>,
+    /// -1..=1: how far the velocity jitter follows the timing jitter; positive makes
+    /// late notes louder and early ones softer, negative the reverse
+    pub humanize_correlation: f32,
+    pub humanize_dist: HumanizeDist,
+    /// Hard cap on the humanize timing offset in ticks, whatever the amount
+    pub humanize_max_ticks: Option<u32>,
+    /// Keep humanized onsets in grid order within each line
+    pub preserve_order: bool,
+    /// Humanize amounts for single channels, in place of `humanize` on them
+    pub humanize_channels: BTreeMap<u8, u32>,
+    /// Off-beat sixteenth delay, 0..=100; 100 puts them two thirds of the way through
+    /// each eighth
+    pub swing: u32,
+    /// Channels swing and humanize apply to; empty means all
+    pub groove_scope: Vec<u8>,
+    /// Write the MPE zone setup (notes must already be on member channels)
+    pub mpe: bool,
+    pub mpe_channels: u8,
+    /// Independent melodic lines; more than one writes Format 1 with a conductor track
+    pub voices: u32,
+    /// Section layout such as `AABA`: each letter is a motif of `section_bars` bars,
+    /// varied when it comes back (`bars` is then ignored)
+    pub form: Option<String>,
+    pub section_bars: u32,
+    /// Octave transpositions for every section with a given `form` letter
+    pub section_shifts: Vec<SectionShift>,
+    /// Write this exercise instead of a random melody (`bars` is then ignored)
+    pub exercise: Option<Exercise>,
+    pub exercise_octaves: u32,
+    pub safety_note_off: bool,
+    /// Track name meta at tick 0 of the first track
+    pub title: Option<String>,
+    /// Text meta at tick 0 of the first track, after the title
+    pub composer: Option<String>,
+    /// Pitch-bend portamento into each note that closely follows another
+    pub glide: bool,
+    /// Pitch-bend range in semitones, set by RPN when gliding
+    pub bend_range: u8,
+    /// Generator algorithm for the main draw and every sub-stream
+    pub rng: RngAlgo,
+    /// Controller numbers that each get a seeded automation sweep
+    pub cc_lanes: Vec<u8>,
+    /// Controller events per quarter note in each lane
+    pub cc_resolution: u32,
+    /// Written at tick 0 of the first track as a sequencer-specific meta event, a kind
+    /// that `--title`/`--composer` text never uses, so readers can find it unambiguously
+    pub embed_text: Option<String>,
+}
+
+impl Default for GenConfig {
+    fn default() -> Self {
+        GenConfig {
+            seed: 0xC0FFEE,
+            bpm: 120,
+            bars: 16,
+            ppqn: 480,
+            time_signature: TimeSig::COMMON,
+            root: Note(60),
+            min_note: None,
+            max_note: None,
+            range_policy: RangePolicy::Fold,
+            scale: "minor-pentatonic".to_string(),
+            user_scales: BTreeMap::new(),
+            channel: 0,
+            program: 0,
+            density: 0.45,
+            onset_weights: Vec::new(),
+            durations: Vec::new(),
+            fixed_length: None,
+            legato: false,
+            legato_max: 4,
+            empty_bar_prob: None,
+            avoid_pcs: Vec::new(),
+            pcset: Vec::new(),
+            degree_accent: None,
+            velocity_curve: VelocityCurve::Linear,
+            velocity_min: 55,
+            velocity_max: 95,
+            meter_accents: false,
+            corpus: Vec::new(),
+            chords: false,
+            chord_extensions: ChordExtensions::Triad,
+            sustain_from_chords: false,
+            arp: None,
+            reseed_per_bar: false,
+            ratchet_prob: None,
+            ratchet_count: 3,
+            repeat_penalty: None,
+            humanize: None,
+            timing_humanize_seed: None,
+            velocity_humanize_seed: None,
+            humanize_correlation: 0.0,
+            humanize_dist: HumanizeDist::Uniform,
+            humanize_max_ticks: None,
+            preserve_order: false,
+            humanize_channels: BTreeMap::new(),
+            swing: 0,
+            groove_scope: Vec::new(),
+            mpe: false,
+            mpe_channels: 15,
+            voices: 1,
+            form: None,
+            section_bars: 2,
+            section_shifts: Vec::new(),
+            exercise: None,
+            exercise_octaves: 1,
+            safety_note_off: false,
+            title: None,
+            composer: None,
+            rng: RngAlgo::Chacha8,
+            glide: false,
+            bend_range: 2,
+            cc_lanes: Vec::new(),
+            cc_resolution: 8,
+            embed_text: None,
+        }
+    }
+}
+
+impl GenConfig {
+    /// Whether groove (swing, humanize and the other feel passes) reaches `channel`.
+    pub fn grooves(&self, channel: u8) -> bool {
+        self.groove_scope.is_empty() || self.groove_scope.contains(&channel)
+    }
+
+    /// The humanize amount for notes on `channel`: its `humanize_channels` entry, else
+    /// `humanize`; none outside the groove scope.
+    pub fn humanize_for(&self, channel: u8) -> Option<u32> {
+        if !self.grooves(channel) {
+            return None;
+        }
+        self.humanize_channels
+            .get(&channel)
+            .copied()
+            .or(self.humanize)
+    }
+
+    /// Rejects settings no generation can honour: a density outside 0..=1, an empty
+    /// velocity range, `min_note` above `max_note`, more than 16 durations, section
+    /// shifts for letters the form lacks or a channel humanize amount over 100.
+    /// [`generate_sequence`] checks this first, so library callers get an `Err` rather
+    /// than a panic.
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=1.0).contains(&self.density) {
+            return Err(format!(
+                "--density {} is out of range, expected 0.0 to 1.0",
+                self.density
+            ));
+        }
+        if self.velocity_min >= self.velocity_max {
+            return Err(format!(
+                "--velocity-min {} must be below --velocity-max {}",
+                self.velocity_min, self.velocity_max
+            ));
+        }
+        if let (Some(min), Some(max)) = (self.min_note, self.max_note) {
+            if min.0 > max.0 {
+                return Err(format!("--min-note {min} is above --max-note {max}"));
+            }
+        }
+        if self.durations.len() > 16 {
+            return Err(format!(
+                "--durations takes at most 16 lengths, got {}",
+                self.durations.len()
+            ));
+        }
+        for (i, shift) in self.section_shifts.iter().enumerate() {
+            let form = self.form.as_deref().unwrap_or("");
+            if !form.contains(shift.letter) {
+                return Err(format!(
+                    "--octave-shift-sections names section {}, which the form '{form}' lacks",
+                    shift.letter
+                ));
+            }
+            if self.section_shifts[..i]
+                .iter()
+                .any(|s| s.letter == shift.letter)
+            {
+                return Err(format!(
+                    "--octave-shift-sections names section {} twice",
+                    shift.letter
+                ));
+            }
+        }
+        if let Some((channel, amount)) = self.humanize_channels.iter().find(|(_, &a)| a > 100) {
+            return Err(format!(
+                "humanize amount {amount} for channel {channel} is out of range, expected 0 to 100"
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct MidiNote {
+    pub pitch: u8,
+    pub start_tick: u32,
+    pub end_tick: u32,
+    pub velocity: u8,
+    pub channel: u8,
+    /// Output track; anything above 0 makes `save_sequence` write Format 1
+    pub track: u8,
+    /// Onset before swing, humanize or tempo wobble moved it
+    pub grid_tick: u32,
+    /// Velocity the humanize jitter added, after clamping to 1..=127
+    #[serde(skip)]
+    pub velocity_jitter: i16,
+}
+
+impl MidiNote {
+    /// Ticks the onset sits after (positive) or before its grid position.
+    pub fn grid_offset(&self) -> i64 {
+        self.start_tick as i64 - self.grid_tick as i64
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct MidiSequence {
+    pub notes: Vec<MidiNote>,
+    pub bpm: u32,
+    pub ppqn: u16,
+    pub total_ticks: u32,
+    pub time_signature: TimeSig,
+    /// `(tick, semitones above the root)` for each modulation, ascending by tick
+    pub key_changes: Vec<(u32, i8)>,
+}
+
+impl MidiSequence {
+    pub fn bar_ticks(&self) -> u32 {
+        self.time_signature.bar_ticks(self.ppqn)
+    }
+}
+
+/// RNG stream ids for post passes; each pass draws from its own stream of the seed.
+pub const LENGTH_HUMANIZE_STREAM: u64 = 1;
+pub const RATCHET_STREAM: u64 = 2;
+pub const REPEAT_PENALTY_STREAM: u64 = 3;
+pub const TEMPO_WOBBLE_STREAM: u64 = 4;
+pub const EMPTY_BAR_STREAM: u64 = 5;
+pub const HUMANIZE_STREAM: u64 = 6;
+pub const VELOCITY_HUMANIZE_STREAM: u64 = 7;
+pub const DRUM_STREAM: u64 = 8;
+pub const CC_LANE_STREAM: u64 = 9;
+pub const FORM_STREAM: u64 = 10;
+pub const DRUM_FEEL_STREAM: u64 = 11;
+pub const LAYER_HUMANIZE_STREAM: u64 = 12;
+pub const LAYER_VELOCITY_HUMANIZE_STREAM: u64 = 13;
+
+/// Largest value an SMF variable-length quantity can encode (28 bits). Delta times are
+/// varints, so a song longer than this can't be written faithfully.
+pub const MAX_SMF_TICKS: u64 = 0x0FFF_FFFF;
+
+pub fn bpm_to_us_per_quarter(bpm: u32) -> u32 {
+    60_000_000u32 / bpm.max(1)
+}
+
+/// Song length in ticks for `bars` of `time_sig` on a sixteenth grid, computed in `u64` and
+/// rejected if it wouldn't fit an SMF delta time (or if PPQN is too coarse for the grid).
+pub fn check_song_length(bars: u32, ppqn: u16, time_sig: TimeSig) -> Result<u32, String> {
+    if ppqn < 4 {
+        return Err(format!(
+            "--ppqn {ppqn} is too coarse for a sixteenth-note grid (minimum 4)"
+        ));
+    }
+    let ticks = bars as u64 * time_sig.steps_per_bar() as u64 * (ppqn as u64 / 4);
+    if ticks > MAX_SMF_TICKS {
+        return Err(format!(
+            "{bars} bars at {ppqn} PPQN is {ticks} ticks, over the SMF limit of {MAX_SMF_TICKS}; \
+             use fewer --bars or a lower --ppqn"
+        ));
+    }
+    Ok(ticks as u32)
+}
+
+const MAJOR_INTERVALS: [i8; 7] = [0, 2, 4, 5, 7, 9, 11];
+
+/// The diatonic mode starting on degree `degree` (0-based) of the major scale,
+/// re-measured from its own first note: 1 = Dorian, 2 = Phrygian, ... 6 = Locrian.
+fn major_mode(degree: usize) -> Vec<i8> {
+    let start = MAJOR_INTERVALS[degree % 7];
+    (0..7)
+        .map(|i| (MAJOR_INTERVALS[(degree + i) % 7] - start).rem_euclid(12))
+        .collect()
+}
+
+fn scale_semitones(s: ScaleOpt) -> Vec<i8> {
+    match s {
+        ScaleOpt::Major => MAJOR_INTERVALS.to_vec(),
+        ScaleOpt::NaturalMinor => vec![0, 2, 3, 5, 7, 8, 10],
+        ScaleOpt::MinorPentatonic => vec![0, 3, 5, 7, 10],
+        ScaleOpt::MajorPentatonic => vec![0, 2, 4, 7, 9],
+        ScaleOpt::Dorian => major_mode(1),
+        ScaleOpt::Phrygian => major_mode(2),
+        ScaleOpt::Lydian => major_mode(3),
+        ScaleOpt::Mixolydian => major_mode(4),
+        ScaleOpt::Locrian => major_mode(6),
+    }
+}
+
+/// Looks `name` up among the built-in scales first, then the user library.
+pub fn resolve_scale(
+    name: &str,
+    user_scales: &BTreeMap<String, Vec<i8>>,
+) -> Result<Vec<i8>, String> {
+    if let Ok(opt) = ScaleOpt::from_str(name, true) {
+        return Ok(scale_semitones(opt));
+    }
+    user_scales.get(name).cloned().ok_or_else(|| {
+        let mut known: Vec<String> = ScaleOpt::value_variants()
+            .iter()
+            .filter_map(|v| v.to_possible_value())
+            .map(|v| v.get_name().to_string())
+            .collect();
+        known.extend(user_scales.keys().cloned());
+        format!("unknown scale '{name}' (known: {})", known.join(", "))
+    })
+}
+
+/// Treats a scale as minor when it has a minor third and no major third.
+pub fn scale_is_minor(intervals: &[i8]) -> bool {
+    intervals.contains(&3) && !intervals.contains(&4)
+}
+
+/// SMF key signature for `intervals` on a tonic pitch class: accidentals (-7..=7, flats
+/// negative) and the minor flag for minor-type scales. A diatonic mode takes its parent
+/// major key's signature (D Dorian shares C major's); any other scale takes the major
+/// key's, or for minor-type scales the relative major's.
+fn key_signature(tonic_pc: u8, intervals: &[i8]) -> (i8, bool) {
+    // Accidentals of the major key on each pitch class, preferring the simpler spelling.
+    const MAJOR: [i8; 12] = [0, -5, 2, -3, 4, -1, -6, 1, -4, 3, -2, 5];
+    let minor = scale_is_minor(intervals);
+    // Semitones from the parent major tonic up to this one; natural minor is degree 5.
+    let above_parent = (0..7)
+        .find(|&degree| major_mode(degree) == intervals)
+        .map_or(if minor { 9 } else { 0 }, |degree| MAJOR_INTERVALS[degree]);
+    let major_pc = (tonic_pc as i16 - above_parent as i16).rem_euclid(12);
+    (MAJOR[major_pc as usize], minor)
+}
+
+/// General MIDI instrument names, indexed by program number. Leads, pads and effects
+/// carry a "Synth" prefix so `synth lead` finds them.
+pub const GM_INSTRUMENTS: [&str; 128] = [
+    "Acoustic Grand Piano",
+    "Bright Acoustic Piano",
+    "Electric Grand Piano",
+    "Honky-tonk Piano",
+    "Electric Piano 1",
+    "Electric Piano 2",
+    "Harpsichord",
+    "Clavinet",
+    "Celesta",
+    "Glockenspiel",
+    "Music Box",
+    "Vibraphone",
+    "Marimba",
+    "Xylophone",
+    "Tubular Bells",
+    "Dulcimer",
+    "Drawbar Organ",
+    "Percussive Organ",
+    "Rock Organ",
+    "Church Organ",
+    "Reed Organ",
+    "Accordion",
+    "Harmonica",
+    "Tango Accordion",
+    "Acoustic Guitar (nylon)",
+    "Acoustic Guitar (steel)",
+    "Electric Guitar (jazz)",
+    "Electric Guitar (clean)",
+    "Electric Guitar (muted)",
+    "Overdriven Guitar",
+    "Distortion Guitar",
+    "Guitar Harmonics",
+    "Acoustic Bass",
+    "Electric Bass (finger)",
+    "Electric Bass (pick)",
+    "Fretless Bass",
+    "Slap Bass 1",
+    "Slap Bass 2",
+    "Synth Bass 1",
+    "Synth Bass 2",
+    "Violin",
+    "Viola",
+    "Cello",
+    "Contrabass",
+    "Tremolo Strings",
+    "Pizzicato Strings",
+    "Orchestral Harp",
+    "Timpani",
+    "String Ensemble 1",
+    "String Ensemble 2",
+    "Synth Strings 1",
+    "Synth Strings 2",
+    "Choir Aahs",
+    "Voice Oohs",
+    "Synth Voice",
+    "Orchestra Hit",
+    "Trumpet",
+    "Trombone",
+    "Tuba",
+    "Muted Trumpet",
+    "French Horn",
+    "Brass Section",
+    "Synth Brass 1",
+    "Synth Brass 2",
+    "Soprano Sax",
+    "Alto Sax",
+    "Tenor Sax",
+    "Baritone Sax",
+    "Oboe",
+    "English Horn",
+    "Bassoon",
+    "Clarinet",
+    "Piccolo",
+    "Flute",
+    "Recorder",
+    "Pan Flute",
+    "Blown Bottle",
+    "Shakuhachi",
+    "Whistle",
+    "Ocarina",
+    "Synth Lead 1 (square)",
+    "Synth Lead 2 (sawtooth)",
+    "Synth Lead 3 (calliope)",
+    "Synth Lead 4 (chiff)",
+    "Synth Lead 5 (charang)",
+    "Synth Lead 6 (voice)",
+    "Synth Lead 7 (fifths)",
+    "Synth Lead 8 (bass + lead)",
+    "Synth Pad 1 (new age)",
+    "Synth Pad 2 (warm)",
+    "Synth Pad 3 (polysynth)",
+    "Synth Pad 4 (choir)",
+    "Synth Pad 5 (bowed)",
+    "Synth Pad 6 (metallic)",
+    "Synth Pad 7 (halo)",
+    "Synth Pad 8 (sweep)",
+    "Synth FX 1 (rain)",
+    "Synth FX 2 (soundtrack)",
+    "Synth FX 3 (crystal)",
+    "Synth FX 4 (atmosphere)",
+    "Synth FX 5 (brightness)",
+    "Synth FX 6 (goblins)",
+    "Synth FX 7 (echoes)",
+    "Synth FX 8 (sci-fi)",
+    "Sitar",
+    "Banjo",
+    "Shamisen",
+    "Koto",
+    "Kalimba",
+    "Bagpipe",
+    "Fiddle",
+    "Shanai",
+    "Tinkle Bell",
+    "Agogo",
+    "Steel Drums",
+    "Woodblock",
+    "Taiko Drum",
+    "Melodic Tom",
+    "Synth Drum",
+    "Reverse Cymbal",
+    "Guitar Fret Noise",
+    "Breath Noise",
+    "Seashore",
+    "Bird Tweet",
+    "Telephone Ring",
+    "Helicopter",
+    "Applause",
+    "Gunshot",
+];
+
+/// Program number for a General MIDI instrument name. Case and punctuation are ignored,
+/// and otherwise the lowest program whose words start with every given word matches, so
+/// `violin`, `piano` or `synth lead` work; anything else errors with the closest names.
+pub fn gm_program(name: &str) -> Result<u8, String> {
+    let words = |s: &str| -> Vec<String> {
+        s.split(|c: char| !c.is_ascii_alphanumeric())
+            .filter(|w| !w.is_empty())
+            .map(|w| w.to_ascii_lowercase())
+            .collect()
+    };
+    let query = words(name);
+    if query.is_empty() {
+        return Err("empty instrument name".into());
+    }
+    if let Some(program) = GM_INSTRUMENTS.iter().position(|n| words(n) == query) {
+        return Ok(program as u8);
+    }
+    let partial = GM_INSTRUMENTS.iter().position(|n| {
+        let candidate = words(n);
+        query
+            .iter()
+            .all(|q| candidate.iter().any(|w| w.starts_with(q.as_str())))
+    });
+    if let Some(program) = partial {
+        return Ok(program as u8);
+    }
+    let query = query.join(" ");
+    let mut ranked: Vec<(usize, &str)> = GM_INSTRUMENTS
+        .iter()
+        .map(|n| (edit_distance(&query, &words(n).join(" ")), *n))
+        .collect();
+    ranked.sort_by_key(|(distance, _)| *distance);
+    let close: Vec<&str> = ranked.iter().take(3).map(|(_, n)| *n).collect();
+    Err(format!(
+        "unknown instrument '{name}' (closest: {})",
+        close.join(", ")
+    ))
+}
+
+/// Levenshtein distance between two strings, by characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for j in 0..b.len() {
+            let substitute = diagonal + (ca != b[j]) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = substitute.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Random number generator behind every draw. A file reproduces only from the same
+/// algorithm and seed; ChaCha8 is the default and what older builds always used.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum RngAlgo {
+    #[default]
+    Chacha8,
+    Chacha20,
+    Pcg,
+}
+
+impl RngAlgo {
+    /// The main generator for `seed` (stream 0).
+    pub fn seeded(self, seed: u64) -> Box<dyn RngCore> {
+        self.sub_rng(seed, 0)
+    }
+
+    /// Independent RNG for a post pass, so enabling the pass never changes the melody.
+    pub fn sub_rng(self, seed: u64, stream: u64) -> Box<dyn RngCore> {
+        match self {
+            RngAlgo::Chacha8 => {
+                let mut rng = ChaCha8Rng::seed_from_u64(seed);
+                rng.set_stream(stream);
+                Box::new(rng)
+            }
+            RngAlgo::Chacha20 => {
+                let mut rng = ChaCha20Rng::seed_from_u64(seed);
+                rng.set_stream(stream);
+                Box::new(rng)
+            }
+            RngAlgo::Pcg => Box::new(Pcg64::new(seed as u128, stream as u128)),
+        }
+    }
+}
+
+fn weighted_choice<R: Rng>(rng: &mut R, items: &[(u8, u32)]) -> u8 {
+    let total: u32 = items.iter().map(|(_, w)| *w).sum();
+    let mut x = rng.gen_range(0..total.max(1));
+    for (v, w) in items {
+        if x < *w {
+            return *v;
+        }
+        x -= *w;
+    }
+    items.last().unwrap().0
+}
+
+/// Velocity boost for `step` from the metrical hierarchy of `time_sig` (numerator,
+/// denominator), with `subdivision` grid steps per denominator beat. Compound meters
+/// (6/8, 9/8, 12/8) group beats in threes; simple meters with four or more beats carry
+/// a secondary accent halfway through the bar.
+fn metrical_accent(step: u32, time_sig: (u32, u32), subdivision: u32) -> u8 {
+    let (beats, _) = time_sig;
+    let subdivision = subdivision.max(1);
+    if !step.is_multiple_of(subdivision) {
+        return 0;
+    }
+    let beat = (step / subdivision) % beats.max(1);
+    let compound = beats > 3 && beats.is_multiple_of(3);
+    if beat == 0 {
+        24
+    } else if (compound && beat.is_multiple_of(3)) || (!compound && beats >= 4 && beat * 2 == beats)
+    {
+        14
+    } else {
+        6
+    }
+}
+
+fn event_order_key(kind: &TrackEventKind) -> u8 {
+    match kind {
+        TrackEventKind::Midi { message, .. } => match message {
+            MidiMessage::NoteOff { .. } => 0,
+            MidiMessage::NoteOn { .. } => 2,
+            // Program, controller and bend messages (CC lanes included) follow the note-offs
+            // and set up the note-ons they share a tick with.
+            _ => 1,
+        },
+        TrackEventKind::Meta(_) => 3,
+        TrackEventKind::SysEx(_) | TrackEventKind::Escape(_) => 4,
+    }
+}
+
+/// Splits `note` into `count` equal repeats of its pitch covering the same span; the
+/// last repeat absorbs any remainder. Falls back to the note itself if it's too short.
+fn ratchet(note: &MidiNote, count: u32) -> Vec<MidiNote> {
+    let len = note.end_tick - note.start_tick;
+    let part = len / count.max(1);
+    if part == 0 {
+        return vec![note.clone()];
+    }
+    (0..count)
+        .map(|i| MidiNote {
+            start_tick: note.start_tick + i * part,
+            grid_tick: note.grid_tick + i * part,
+            end_tick: if i + 1 == count {
+                note.end_tick
+            } else {
+                note.start_tick + (i + 1) * part
+            },
+            ..note.clone()
+        })
+        .collect()
+}
+
+/// Generates a melody from `config`; the same config always gives the same notes.
+///
+/// With several `voices`, voice `i` is generated from `seed ^ i` on track `i + 1`, leaving
+/// track 0 to the conductor. Voice 0 is the same line a single-voice run gives.
+pub fn generate_sequence(config: &GenConfig) -> Result<MidiSequence, Box<dyn Error>> {
+    config.validate()?;
+    let mut seq = match (config.exercise, &config.form) {
+        (Some(exercise), _) => generate_exercise(config, exercise)?,
+        (None, Some(form)) => generate_form(config, form)?,
+        (None, None) => generate_voices(config, config.seed)?,
+    };
+    if config.legato {
+        apply_legato(&mut seq, config.legato_max * (config.ppqn as u32 / 4));
+    }
+    Ok(seq)
+}
+
+/// Makes each (track, channel) line continuous: every note ends on the next later onset
+/// of its line when the gap to it is at most `max_gap` ticks, and a note running past
+/// that onset is cut there, so the line stays monophonic and a repeated pitch gets its
+/// NoteOff on the tick of the next NoteOn instead of hanging over it. Callers that move
+/// onsets after generating should run it themselves once the onsets are final.
+pub fn apply_legato(seq: &mut MidiSequence, max_gap: u32) {
+    let mut lines: BTreeMap<(u8, u8), Vec<usize>> = BTreeMap::new();
+    for (i, note) in seq.notes.iter().enumerate() {
+        lines.entry((note.track, note.channel)).or_default().push(i);
+    }
+    for indices in lines.values() {
+        let mut onsets: Vec<u32> = indices.iter().map(|&i| seq.notes[i].start_tick).collect();
+        onsets.sort_unstable();
+        onsets.dedup();
+        for &i in indices {
+            let note = &mut seq.notes[i];
+            let next = onsets[onsets.partition_point(|&t| t <= note.start_tick)..].first();
+            if let Some(&next) = next {
+                if note.end_tick > next || next - note.end_tick <= max_gap {
+                    note.end_tick = next;
+                }
+            }
+        }
+    }
+}
+
+/// Every `--voices` line from `seed`; more than one leaves track 0 for the conductor.
+fn generate_voices(config: &GenConfig, seed: u64) -> Result<MidiSequence, Box<dyn Error>> {
+    if config.voices <= 1 {
+        return generate_line(config, seed, 0);
+    }
+    let mut seq = generate_line(config, seed, 1)?;
+    for voice in 1..config.voices {
+        let track = u8::try_from(voice + 1).map_err(|_| "too many voices")?;
+        let line = generate_line(config, seed ^ voice as u64, track)?;
+        seq.notes.extend(line.notes);
+    }
+    seq.notes.sort_by_key(|n| (n.start_tick, n.track));
+    Ok(seq)
+}
+
+/// Sections of `config.section_bars` bars laid out by `form` (letters, e.g. `AABA`). Each
+/// letter's motif is generated once, A from `seed` and later letters from seeds drawn
+/// off its own stream; a letter's repeats are varied by a scale-step transposition
+/// (sometimes) and small velocity changes. Letters in `section_shifts` move every one of
+/// their sections by whole octaves, placed back in range by the range policy.
+fn generate_form(config: &GenConfig, form: &str) -> Result<MidiSequence, Box<dyn Error>> {
+    let sections = form.len() as u32;
+    check_song_length(
+        config.section_bars.saturating_mul(sections),
+        config.ppqn,
+        config.time_signature,
+    )?;
+    let (scale, base_note) = config_scale(config)?;
+    let section_config = GenConfig {
+        bars: config.section_bars,
+        ..config.clone()
+    };
+    let section_ticks = config.time_signature.bar_ticks(config.ppqn) * config.section_bars;
+
+    let mut rng = config.rng.sub_rng(config.seed, FORM_STREAM);
+    let mut motifs: BTreeMap<char, MidiSequence> = BTreeMap::new();
+    let mut notes = Vec::new();
+    for (index, letter) in form.chars().enumerate() {
+        let repeat = motifs.contains_key(&letter);
+        if !repeat {
+            let seed = if motifs.is_empty() {
+                config.seed
+            } else {
+                rng.next_u64()
+            };
+            motifs.insert(letter, generate_voices(&section_config, seed)?);
+        }
+        let offset = index as u32 * section_ticks;
+        let shift = match rng.gen_range(0..100u32) {
+            _ if !repeat => 0,
+            0..=19 => -1,
+            20..=39 => 1,
+            _ => 0,
+        };
+        let octave = config
+            .section_shifts
+            .iter()
+            .find(|s| s.letter == letter)
+            .map_or(0, |s| s.semitones as i16);
+        let mut section = motifs[&letter].notes.clone();
+        let mut dropped = Vec::new();
+        for (i, note) in section.iter_mut().enumerate() {
+            note.start_tick += offset;
+            note.end_tick += offset;
+            note.grid_tick += offset;
+            if repeat || octave != 0 {
+                let shifted = shift_degrees(note.pitch, shift, &scale, base_note) + octave;
+                match range_pitch(config, shifted) {
+                    Some(pitch) => note.pitch = pitch,
+                    None => dropped.push(i),
+                }
+            }
+            if repeat {
+                // The nudge stays inside the velocity range, but notes an accent already
+                // lifted past it keep that accent.
+                let velocity = note.velocity as i16;
+                let low = velocity.min(config.velocity_min as i16);
+                let high = velocity.max(config.velocity_max as i16);
+                let nudge = rng.gen_range(-8..=8i16);
+                note.velocity = (velocity + nudge).clamp(low.max(1), high.min(127)) as u8;
+            }
+        }
+        for i in dropped.into_iter().rev() {
+            section.remove(i);
+        }
+        notes.extend(section);
+    }
+    notes.sort_by_key(|n| (n.start_tick, n.track));
+
+    Ok(MidiSequence {
+        notes,
+        bpm: config.bpm,
+        ppqn: config.ppqn,
+        total_ticks: section_ticks * sections,
+        time_signature: config.time_signature,
+        key_changes: Vec::new(),
+    })
+}
+
+/// Moves `pitch` by `steps` scale degrees, keeping its octave logic; pitches outside the
+/// scale are left as they are. The result may leave the note range; see [`fold_pitch`].
+fn shift_degrees(pitch: u8, steps: i32, scale: &[i8], base_note: i16) -> i16 {
+    let rel = pitch as i16 - base_note;
+    let pc = rel.rem_euclid(12);
+    let Some(degree) = scale.iter().position(|&s| s as i16 == pc) else {
+        return pitch as i16;
+    };
+    let len = scale.len() as i32;
+    let target = degree as i32 + steps;
+    let octave = target.div_euclid(len) as i16;
+    let semis = scale[target.rem_euclid(len) as usize] as i16;
+    base_note + rel.div_euclid(12) * 12 + octave * 12 + semis
+}
+
+/// Whole bars needed to last at least `seconds` at `bpm` in `time_sig` (at least one).
+pub fn bars_for_seconds(seconds: f64, bpm: u32, time_sig: TimeSig) -> u32 {
+    let quarters = seconds * bpm as f64 / 60.0;
+    let quarters_per_bar = time_sig.num as f64 * 4.0 / time_sig.den as f64;
+    // Trim float noise so an exact fit doesn't round up to an extra bar.
+    let bars = (quarters / quarters_per_bar * 1e6).round() / 1e6;
+    (bars.ceil() as u32).max(1)
+}
+
+/// The usable scale (semitones, ascending) and the MIDI note it is measured from.
+fn config_scale(config: &GenConfig) -> Result<(Vec<i8>, i16), String> {
+    let (mut scale, base_note) = if config.pcset.is_empty() {
+        (
+            resolve_scale(&config.scale, &config.user_scales)?,
+            config.root.as_u8() as i16,
+        )
+    } else {
+        // Absolute pitch classes, measured from the C of the root's octave.
+        let mut set: Vec<i8> = config.pcset.iter().map(|&pc| pc as i8).collect();
+        set.sort_unstable();
+        set.dedup();
+        (set, (config.root.as_u8() / 12 * 12) as i16)
+    };
+    scale.retain(|semis| !config.avoid_pcs.contains(&(*semis as u8)));
+    if scale.is_empty() {
+        return Err(format!(
+            "--avoid-pcs leaves no notes in scale '{}'",
+            config.scale
+        ));
+    }
+    Ok((scale, base_note))
+}
+
+/// First-order transition counts between scale degrees, `[from][to]`, over consecutive
+/// `pitches`. Each pitch counts as the degree nearest its pitch class above `base_note`.
+/// Empty when there are no pitches.
+fn degree_transitions(pitches: &[u8], base_note: i16, scale: &[i8]) -> Vec<Vec<u32>> {
+    if pitches.is_empty() {
+        return Vec::new();
+    }
+    let degree_of = |pitch: u8| {
+        let pc = (pitch as i16 - base_note).rem_euclid(12);
+        (0..scale.len())
+            .min_by_key(|&d| {
+                let diff = (pc - scale[d] as i16).rem_euclid(12);
+                diff.min(12 - diff)
+            })
+            .unwrap_or(0)
+    };
+    let mut counts = vec![vec![0u32; scale.len()]; scale.len()];
+    for pair in pitches.windows(2) {
+        counts[degree_of(pair[0])][degree_of(pair[1])] += 1;
+    }
+    counts
+}
+
+/// Root, third and fifth of `scale`, skipping any the scale lacks.
+fn triad(scale: &[i8]) -> Vec<i8> {
+    let third: &[i8] = if scale_is_minor(scale) {
+        &[3, 4]
+    } else {
+        &[4, 3]
+    };
+    let mut tones = vec![scale[0]];
+    for choices in [third, &[7, 6, 8]] {
+        tones.extend(choices.iter().copied().find(|semis| scale.contains(semis)));
+    }
+    tones
+}
+
+/// Moves `pitch` by octaves into `config.min_note..=config.max_note` so it keeps its
+/// pitch class; a range narrower than an octave that holds none of them clamps instead.
+/// Without either bound it is the plain 0..=127 clamp.
+fn fold_pitch(config: &GenConfig, pitch: i16) -> u8 {
+    if config.min_note.is_none() && config.max_note.is_none() {
+        return pitch.clamp(0, 127) as u8;
+    }
+    let lo = config.min_note.map_or(0, |n| n.0 as i16);
+    let hi = config.max_note.map_or(127, |n| n.0 as i16);
+    let mut p = pitch;
+    while p < lo {
+        p += 12;
+    }
+    while p > hi {
+        p -= 12;
+    }
+    p.clamp(lo, hi) as u8
+}
+
+/// `pitch` placed in the note range by `config.range_policy`, or `None` when the policy
+/// drops it. Without bounds the range is 0..=127.
+fn range_pitch(config: &GenConfig, pitch: i16) -> Option<u8> {
+    let lo = config.min_note.map_or(0, |n| n.0 as i16);
+    let hi = config.max_note.map_or(127, |n| n.0 as i16);
+    match config.range_policy {
+        RangePolicy::Fold => Some(fold_pitch(config, pitch)),
+        RangePolicy::Clamp => Some(pitch.clamp(lo, hi) as u8),
+        RangePolicy::Drop => (lo..=hi).contains(&pitch).then_some(pitch as u8),
+    }
+}
+
+/// Fixed scale or arpeggio run over `config.exercise_octaves` octaves from the root:
+/// no randomness, one velocity, every note `fixed_length` (default 2) sixteenths long.
+fn generate_exercise(
+    config: &GenConfig,
+    exercise: Exercise,
+) -> Result<MidiSequence, Box<dyn Error>> {
+    let (scale, base_note) = config_scale(config)?;
+    let tones = match exercise {
+        Exercise::ScaleUp | Exercise::ScaleUpdown => scale,
+        Exercise::Arpeggio => triad(&scale),
+    };
+    let mut pitches: Vec<i16> = (0..config.exercise_octaves as i16)
+        .flat_map(|octave| {
+            tones
+                .iter()
+                .map(move |&semis| base_note + octave * 12 + semis as i16)
+        })
+        .collect();
+    pitches.push(base_note + config.exercise_octaves as i16 * 12);
+    if !matches!(exercise, Exercise::ScaleUp) {
+        let descent: Vec<i16> = pitches.iter().rev().skip(1).copied().collect();
+        pitches.extend(descent);
+    }
+
+    let step_ticks = (config.ppqn as u32) / 4;
+    let note_ticks = config.fixed_length.unwrap_or(2) * step_ticks;
+    let bar_ticks = config.time_signature.bar_ticks(config.ppqn);
+    let notes: Vec<MidiNote> = pitches
+        .iter()
+        .enumerate()
+        .filter_map(|(i, &pitch)| {
+            Some(MidiNote {
+                pitch: range_pitch(config, pitch)?,
+                start_tick: i as u32 * note_ticks,
+                end_tick: (i as u32 + 1) * note_ticks,
+                grid_tick: i as u32 * note_ticks,
+                velocity_jitter: 0,
+                velocity: 80,
+                channel: config.channel,
+                track: 0,
+            })
+        })
+        .collect();
+    let end_tick = pitches.len() as u32 * note_ticks;
+
+    Ok(MidiSequence {
+        notes,
+        bpm: config.bpm,
+        ppqn: config.ppqn,
+        total_ticks: end_tick.div_ceil(bar_ticks.max(1)) * bar_ticks,
+        time_signature: config.time_signature,
+        key_changes: Vec::new(),
+    })
+}
+
+/// One note's `--humanize` jitter as `(timing offset in ticks, velocity change)`: up to
+/// a quarter step and 12 velocity either way at amount 100, shaped by `humanize_dist`. A
+/// nonzero `humanize_correlation` blends the timing draw into the velocity one, and
+/// `humanize_max_ticks` clamps the offset after every draw is made.
+fn humanize_draw(
+    config: &GenConfig,
+    amount: u32,
+    step_ticks: u32,
+    timing_rng: &mut impl Rng,
+    velocity_rng: &mut impl Rng,
+) -> (i64, i16) {
+    let max_offset = (step_ticks * amount / 400) as i64;
+    let max_vel = (12 * amount / 100) as i16;
+    let correlation = config.humanize_correlation;
+    let dist = config.humanize_dist;
+    let (offset, vel_delta) = if correlation == 0.0 && dist == HumanizeDist::Uniform {
+        // Independent integer draws, as humanize has always made them.
+        let offset = timing_rng.gen_range(-max_offset..=max_offset);
+        (offset, velocity_rng.gen_range(-max_vel..=max_vel))
+    } else {
+        let timing = dist.unit(timing_rng);
+        let own = dist.unit(velocity_rng);
+        let velocity = correlation * timing + (1.0 - correlation * correlation).sqrt() * own;
+        (
+            (timing * max_offset as f32).round() as i64,
+            (velocity.clamp(-1.0, 1.0) * max_vel as f32).round() as i16,
+        )
+    };
+    let cap = config.humanize_max_ticks.map_or(i64::MAX, i64::from);
+    (offset.clamp(-cap, cap), vel_delta)
+}
+
+/// Swings a layer added after generation, such as the drums, the way a line's own notes
+/// are: onsets and ends on an odd sixteenth move late by the `swing` delay, for notes
+/// inside the groove scope.
+pub fn swing_layer(notes: &mut [MidiNote], config: &GenConfig, ppqn: u16, total_ticks: u32) {
+    let step_ticks = (ppqn as u32 / 4).max(1);
+    let swing_ticks = step_ticks * config.swing / 300;
+    let swung = |tick: u32| {
+        let odd = tick % (2 * step_ticks) == step_ticks;
+        tick + if odd { swing_ticks } else { 0 }
+    };
+    for note in notes.iter_mut().filter(|n| config.grooves(n.channel)) {
+        note.start_tick = swung(note.start_tick).min(total_ticks.saturating_sub(1));
+        note.end_tick = swung(note.end_tick)
+            .min(total_ticks)
+            .max(note.start_tick + 1);
+    }
+}
+
+/// Humanizes a layer added after generation, such as the drums: each note inside the
+/// groove scope gets its channel's [`GenConfig::humanize_for`] amount of jitter, from
+/// streams apart from the melody's; notes with no amount are left alone.
+pub fn humanize_layer(notes: &mut [MidiNote], config: &GenConfig, ppqn: u16, total_ticks: u32) {
+    let mut timing_rng = config.rng.sub_rng(config.seed, LAYER_HUMANIZE_STREAM);
+    let mut velocity_rng = config
+        .rng
+        .sub_rng(config.seed, LAYER_VELOCITY_HUMANIZE_STREAM);
+    let step_ticks = ppqn as u32 / 4;
+    let last_tick = total_ticks.saturating_sub(1) as i64;
+    for note in notes.iter_mut() {
+        let Some(amount) = config.humanize_for(note.channel) else {
+            continue;
+        };
+        let (offset, vel_delta) = humanize_draw(
+            config,
+            amount,
+            step_ticks,
+            &mut timing_rng,
+            &mut velocity_rng,
+        );
+        let len = note.end_tick - note.start_tick;
+        note.start_tick = (note.start_tick as i64 + offset).clamp(0, last_tick) as u32;
+        note.end_tick = (note.start_tick + len)
+            .min(total_ticks)
+            .max(note.start_tick + 1);
+        let velocity = (note.velocity as i16 + vel_delta).clamp(1, 127);
+        note.velocity_jitter = velocity - note.velocity as i16;
+        note.velocity = velocity as u8;
+    }
+    if config.preserve_order {
+        let mut channels: Vec<u8> = notes.iter().map(|n| n.channel).collect();
+        channels.sort_unstable();
+        channels.dedup();
+        for channel in channels {
+            if config.humanize_for(channel).is_some() {
+                preserve_onset_order(notes.iter_mut().filter(|n| n.channel == channel));
+            }
+        }
+    }
+    notes.sort_by_key(|n| (n.start_tick, n.pitch));
+}
+
+/// Holds each onset at or after every onset from an earlier grid position, so humanize
+/// never swaps two notes' order; notes sharing a grid tick (chord tones, kit pieces) may
+/// still land in any order among themselves. Ends stay at least a tick past starts.
+fn preserve_onset_order<'a>(notes: impl IntoIterator<Item = &'a mut MidiNote>) {
+    let mut notes: Vec<&mut MidiNote> = notes.into_iter().collect();
+    notes.sort_by_key(|n| n.grid_tick);
+    let (mut grid, mut floor, mut latest) = (None, 0, 0);
+    for note in notes {
+        if grid != Some(note.grid_tick) {
+            grid = Some(note.grid_tick);
+            floor = latest;
+        }
+        note.start_tick = note.start_tick.max(floor);
+        note.end_tick = note.end_tick.max(note.start_tick + 1);
+        latest = latest.max(note.start_tick);
+    }
+}
+
+/// One melodic line from `seed`, with every note on `track`.
+fn generate_line(config: &GenConfig, seed: u64, track: u8) -> Result<MidiSequence, Box<dyn Error>> {
+    let mut rng = config.rng.seeded(seed);
+    let (scale, base_note) = config_scale(config)?;
+
+    // Validated up front so none of the u32 step/tick products below can wrap.
+    let time_sig = config.time_signature;
+    let song_len_ticks: u32 = check_song_length(config.bars, config.ppqn, time_sig)?;
+    let steps_per_bar = time_sig.steps_per_bar();
+    let step_ticks: u32 = (config.ppqn as u32) / 4;
+    let total_steps: u32 = config.bars * steps_per_bar;
+
+    let rest_threshold = ((1.0 - config.density) * 100.0) as u32;
+    // Swing delays odd sixteenths by up to a third of a step (a triplet feel at 100);
+    // ends land on the same swung grid, so a note never runs into the next step.
+    let swing = if config.grooves(config.channel) {
+        config.swing
+    } else {
+        0
+    };
+    let swing_ticks = step_ticks * swing / 300;
+    let swung = |step: u32| step * step_ticks + if step % 2 == 1 { swing_ticks } else { 0 };
+    let transitions = degree_transitions(&config.corpus, base_note, &scale);
+    // Weighted thresholds keep the plain one at weight 1, so the same rolls decide.
+    let step_threshold = |step: u32| match config.onset_weights.len() {
+        0 => rest_threshold,
+        len => {
+            let weight = config.onset_weights[(step % steps_per_bar) as usize % len];
+            let onset = ((100 - rest_threshold) as f32 * weight).round() as u32;
+            100 - onset.min(100)
+        }
+    };
+
+    let mut notes = Vec::new();
+    let mut last_degree: i32 = 0;
+    let mut arp_root: i32 = 0;
+    // Ratchet decisions use their own stream so the melody is the same with them off.
+    let mut ratchet_rng = config.rng.sub_rng(seed, RATCHET_STREAM);
+    let mut repeat_rng = config.rng.sub_rng(seed, REPEAT_PENALTY_STREAM);
+    let mut last_pitch: Option<u8> = None;
+    // Timing and velocity jitter are seeded apart so either can change on its own;
+    // `voice` keeps the lines of a --voices run distinct under a shared seed.
+    let voice = seed ^ config.seed;
+    let timing_seed = config.timing_humanize_seed.unwrap_or(config.seed) ^ voice;
+    let velocity_seed = config.velocity_humanize_seed.unwrap_or(config.seed) ^ voice;
+    let mut timing_rng = config.rng.sub_rng(timing_seed, HUMANIZE_STREAM);
+    let mut velocity_rng = config.rng.sub_rng(velocity_seed, VELOCITY_HUMANIZE_STREAM);
+    let mut silence_rng = config.rng.sub_rng(seed, EMPTY_BAR_STREAM);
+    let silent_bars: Vec<bool> = (0..config.bars)
+        .map(|_| {
+            config
+                .empty_bar_prob
+                .is_some_and(|p| silence_rng.gen::<f32>() < p)
+        })
+        .collect();
+
+    for step in 0..total_steps {
+        let t0 = swung(step);
+        if config.reseed_per_bar && step > 0 && step.is_multiple_of(steps_per_bar) {
+            rng = config
+                .rng
+                .seeded(seed.wrapping_add((step / steps_per_bar) as u64));
+        }
+
+        // The roll is still drawn under --arp so the degree walk is the same.
+        if rng.gen_range(0..100u32) < step_threshold(step) && config.arp.is_none() {
+            continue;
+        }
+
+        let max_deg = (scale.len() as i32).max(1);
+        let corpus_row = transitions
+            .get(last_degree as usize)
+            .filter(|row| row.iter().any(|&count| count > 0));
+        let mut deg = if let Some(row) = corpus_row {
+            let items: Vec<(u8, u32)> = row
+                .iter()
+                .enumerate()
+                .map(|(degree, &count)| (degree as u8, count))
+                .collect();
+            weighted_choice(&mut rng, &items) as i32
+        } else {
+            let target = if max_deg >= 3 {
+                weighted_choice(&mut rng, &[(0, 30), (1, 15), (2, 30), (3, 15), (4, 10)]) as i32
+            } else {
+                rng.gen_range(0..max_deg as u32) as i32
+            };
+            let target = target.clamp(0, max_deg - 1);
+
+            if rng.gen_range(0..100u32) < 65 {
+                let delta = match rng.gen_range(0..3u32) {
+                    0 => -1,
+                    1 => 0,
+                    _ => 1,
+                };
+                (last_degree + delta).clamp(0, max_deg - 1)
+            } else {
+                target
+            }
+        };
+        last_degree = deg;
+
+        let semis = match config.arp {
+            // The chord root holds for a beat while the walk moves on underneath it.
+            Some(arp) => {
+                if step.is_multiple_of(4) {
+                    arp_root = deg;
+                }
+                let len = scale.len() as i32;
+                let degree = arp_root + arp.degree(step);
+                scale[(degree % len) as usize] as i16 + 12 * (degree / len) as i16
+            }
+            None => scale[deg as usize] as i16,
+        };
+        let octave_shift: i16 = match rng.gen_range(0..100u32) {
+            _ if config.arp.is_some() => 0,
+            0..=9 => 12,
+            10..=14 => -12,
+            _ => 0,
+        };
+
+        // `None` when --range-policy drop rests instead of playing it.
+        let mut note_pitch = range_pitch(config, base_note + semis + octave_shift);
+
+        // Repeat penalty: step to a neighbouring degree instead of restriking the pitch.
+        if let (Some(penalty), None) = (config.repeat_penalty, config.arp) {
+            if last_pitch.is_some() && last_pitch == note_pitch && repeat_rng.gen::<f32>() < penalty
+            {
+                let neighbours: Vec<i32> = [deg - 1, deg + 1]
+                    .into_iter()
+                    .filter(|d| (0..max_deg).contains(d))
+                    .collect();
+                if !neighbours.is_empty() {
+                    let new_deg = neighbours[repeat_rng.gen_range(0..neighbours.len())];
+                    last_degree = new_deg;
+                    deg = new_deg;
+                    let semis = scale[deg as usize] as i16;
+                    note_pitch = range_pitch(config, base_note + semis + octave_shift);
+                }
+            }
+        }
+        last_pitch = note_pitch;
+
+        // A --durations list takes the place of the built-in step-length draw.
+        let (dur_steps, dur_ticks) = if config.durations.is_empty() {
+            let steps = weighted_choice(&mut rng, &[(1, 40), (2, 30), (3, 10), (4, 20)]);
+            (steps as u32, None)
+        } else {
+            let items: Vec<(u8, u32)> = (0..config.durations.len())
+                .map(|i| (i as u8, config.durations[i].weight))
+                .collect();
+            let length = config.durations[weighted_choice(&mut rng, &items) as usize];
+            (1, Some(length.ticks(config.ppqn)))
+        };
+        // Still drawn above so a fixed length keeps the same pitches and velocities.
+        let dur_steps = match config.arp {
+            Some(_) => config.fixed_length.unwrap_or(1),
+            None => config.fixed_length.unwrap_or(dur_steps),
+        };
+
+        let t1 = match dur_ticks {
+            Some(ticks) if config.fixed_length.is_none() && config.arp.is_none() => {
+                (t0 + ticks).min(song_len_ticks)
+            }
+            _ => swung(step + dur_steps).min(song_len_ticks),
+        };
+
+        let accent: u8 = if config.meter_accents {
+            let sig = (time_sig.num as u32, time_sig.den as u32);
+            metrical_accent(step, sig, 16 / time_sig.den as u32)
+        } else if step % 4 == 0 {
+            18
+        } else {
+            0
+        };
+        let degree_boost = config
+            .degree_accent
+            .as_ref()
+            .map_or(0, |a| a.boost(deg as usize));
+        // Drawn as i32, as the fixed 55..95 range was, so default runs keep their velocities.
+        let raw = rng.gen_range(config.velocity_min as i32..config.velocity_max as i32) as u8;
+        let base = config
+            .velocity_curve
+            .shape(raw, config.velocity_min, config.velocity_max);
+        let vel: u8 = (base as u16 + accent as u16 + degree_boost as u16).min(127) as u8;
+
+        let mut note = MidiNote {
+            pitch: note_pitch.unwrap_or(0),
+            start_tick: t0,
+            end_tick: t1,
+            velocity: vel,
+            channel: config.channel,
+            track,
+            grid_tick: step * step_ticks,
+            velocity_jitter: 0,
+        };
+        if let Some(amount) = config.humanize_for(config.channel) {
+            let (offset, vel_delta) = humanize_draw(
+                config,
+                amount,
+                step_ticks,
+                &mut timing_rng,
+                &mut velocity_rng,
+            );
+            let last_tick = song_len_ticks.saturating_sub(1) as i64;
+            note.start_tick = (t0 as i64 + offset).clamp(0, last_tick) as u32;
+            note.end_tick =
+                ((t1 as i64 + offset).min(song_len_ticks as i64) as u32).max(note.start_tick + 1);
+            note.velocity = (vel as i16 + vel_delta).clamp(1, 127) as u8;
+            note.velocity_jitter = note.velocity as i16 - vel as i16;
+        }
+        let ratcheted =
+            matches!(config.ratchet_prob, Some(prob) if ratchet_rng.gen::<f32>() < prob);
+        // Dropped only after every draw, so the other bars match a run without silence.
+        if silent_bars[(step / steps_per_bar) as usize] {
+            continue;
+        }
+        // Chord tones: the thirds stacked above the drawn degree; the melody note is the root.
+        let mut chord: Vec<MidiNote> = note_pitch.map(|_| note.clone()).into_iter().collect();
+        if config.chords {
+            for semis in build_chord(deg as usize, &scale, config.chord_extensions)
+                .into_iter()
+                .skip(1)
+            {
+                if let Some(pitch) = range_pitch(config, base_note + semis + octave_shift) {
+                    chord.push(MidiNote {
+                        pitch,
+                        ..note.clone()
+                    });
+                }
+            }
+        }
+        for tone in chord {
+            if ratcheted {
+                notes.extend(ratchet(&tone, config.ratchet_count));
+            } else {
+                notes.push(tone);
+            }
+        }
+    }
+    if config.preserve_order {
+        preserve_onset_order(&mut notes);
+    }
+
+    Ok(MidiSequence {
+        notes,
+        bpm: config.bpm,
+        ppqn: config.ppqn,
+        total_ticks: song_len_ticks,
+        time_signature: time_sig,
+        key_changes: Vec::new(),
+    })
+}
+
+/// MPE per-note pitch bend range in semitones (the spec's default for member channels).
+const MPE_BEND_RANGE: u8 = 48;
+
+/// `(track, channel, tick, bend)` pitch bends for `--glide`. A note starting within a
+/// sixteenth of the previous one's end, alone at its onset and no more than
+/// `bend_range` semitones away, starts bent to the previous pitch and slides to centre
+/// over its first third (at most a sixteenth); every other note starts at centre.
+/// Percussion (channel 9) is left alone.
+fn glide_bends(seq: &MidiSequence, bend_range: u8) -> Vec<(u8, u8, u32, i16)> {
+    let gap = (seq.ppqn as u32 / 4).max(1);
+    let mut lines: BTreeMap<(u8, u8), Vec<&MidiNote>> = BTreeMap::new();
+    for note in seq.notes.iter().filter(|n| n.channel != 9) {
+        lines
+            .entry((note.track, note.channel))
+            .or_default()
+            .push(note);
+    }
+
+    let mut bends = Vec::new();
+    for ((track, channel), mut notes) in lines {
+        notes.sort_by_key(|n| (n.start_tick, n.pitch));
+        for (i, note) in notes.iter().enumerate() {
+            let alone = notes
+                .iter()
+                .filter(|n| n.start_tick == note.start_tick)
+                .count()
+                == 1;
+            let from = i
+                .checked_sub(1)
+                .map(|p| notes[p])
+                .filter(|prev| alone && note.start_tick.saturating_sub(prev.end_tick) <= gap);
+            let distance = from.map_or(0, |prev| prev.pitch as i16 - note.pitch as i16);
+            if distance == 0 || distance.unsigned_abs() > bend_range as u16 {
+                bends.push((track, channel, note.start_tick, 0));
+                continue;
+            }
+            let len = note.end_tick - note.start_tick;
+            let glide_ticks = (len / 3).clamp(1, gap);
+            let steps = glide_ticks.min(8);
+            for k in 0..steps {
+                let remaining = distance as f32 * (steps - k) as f32 / steps as f32;
+                let bend = (remaining / bend_range as f32 * 8192.0).round() as i16;
+                bends.push((
+                    track,
+                    channel,
+                    note.start_tick + glide_ticks * k / steps,
+                    bend,
+                ));
+            }
+            bends.push((track, channel, note.start_tick + glide_ticks, 0));
+        }
+    }
+    // Where a slide's closing centre meets the next note's onset, the onset wins.
+    bends.reverse();
+    bends.sort_by_key(|&(track, channel, tick, _)| (track, channel, tick));
+    bends.dedup_by_key(|&mut (track, channel, tick, _)| (track, channel, tick));
+    bends
+}
+
+/// A slow seeded random walk for `controller` across `total_ticks`: a new target value
+/// every bar, linearly interpolated `per_quarter` times a quarter note. Only changes are
+/// returned, as `(tick, value)`.
+fn cc_lane(
+    algo: RngAlgo,
+    seed: u64,
+    controller: u8,
+    total_ticks: u32,
+    bar_ticks: u32,
+    ppqn: u16,
+    per_quarter: u32,
+) -> Vec<(u32, u8)> {
+    let mut rng = algo.sub_rng(seed ^ controller as u64, CC_LANE_STREAM);
+    let bar_ticks = bar_ticks.max(1);
+    let mut targets = vec![rng.gen_range(32..=96i32)];
+    for _ in 0..total_ticks.div_ceil(bar_ticks) {
+        let last = *targets.last().unwrap_or(&64);
+        targets.push((last + rng.gen_range(-24..=24)).clamp(0, 127));
+    }
+
+    let interval = (ppqn as u32 / per_quarter.max(1)).max(1);
+    let mut points = Vec::new();
+    let mut last_value: Option<u8> = None;
+    for tick in (0..total_ticks).step_by(interval as usize) {
+        let bar = (tick / bar_ticks) as usize;
+        let t = (tick % bar_ticks) as f32 / bar_ticks as f32;
+        let (from, to) = (targets[bar] as f32, targets[bar + 1] as f32);
+        let value = (from + (to - from) * t).round() as u8;
+        if last_value != Some(value) {
+            points.push((tick, value));
+            last_value = Some(value);
+        }
+    }
+    points
+}
+
+/// Sustain pedal `(tick, value)` changes on `channel`: down (127) at each chord onset, two
+/// or more notes of one track starting together, and up (0) a tick before the next
+/// chord, or when the last chord's notes have all ended.
+fn chord_pedal(seq: &MidiSequence, channel: u8) -> Vec<(u32, u8)> {
+    let mut starts: BTreeMap<(u32, u8), (u32, u32)> = BTreeMap::new();
+    for note in seq.notes.iter().filter(|n| n.channel == channel) {
+        let (count, end) = starts.entry((note.start_tick, note.track)).or_default();
+        *count += 1;
+        *end = (*end).max(note.end_tick);
+    }
+    let mut chords: BTreeMap<u32, u32> = BTreeMap::new();
+    for (&(tick, _), &(_, end)) in starts.iter().filter(|(_, &(count, _))| count >= 2) {
+        let last_end = chords.entry(tick).or_default();
+        *last_end = (*last_end).max(end);
+    }
+    let onsets: Vec<(u32, u32)> = chords.into_iter().collect();
+    let mut pedal = Vec::new();
+    for (i, &(tick, end)) in onsets.iter().enumerate() {
+        let up = onsets
+            .get(i + 1)
+            .map_or(end, |&(next, _)| (next - 1).max(tick));
+        pedal.extend([(tick, 127), (up, 0)]);
+    }
+    pedal
+}
+
+/// RPN write (101/100 select, 6/38 data entry, then null RPN) on `channel` at tick 0.
+fn push_rpn(abs_events: &mut Vec<(u32, TrackEventKind)>, channel: u8, rpn: u8, msb: u8) {
+    for (controller, value) in [
+        (101, 0),
+        (100, rpn),
+        (6, msb),
+        (38, 0),
+        (101, 127),
+        (100, 127),
+    ] {
+        abs_events.push((
+            0,
+            TrackEventKind::Midi {
+                channel: channel.into(),
+                message: MidiMessage::Controller {
+                    controller: controller.into(),
+                    value: value.into(),
+                },
+            },
+        ));
+    }
+}
+
+/// Appends All Notes Off (CC 123) for every channel in `abs_events` at the track's last tick,
+/// so they land just before EndOfTrack.
+fn push_all_notes_off(abs_events: &mut Vec<(u32, TrackEventKind)>) {
+    let end_tick = abs_events.iter().map(|(tick, _)| *tick).max().unwrap_or(0);
+    let mut channels: Vec<u8> = abs_events
+        .iter()
+        .filter_map(|(_, kind)| match kind {
+            TrackEventKind::Midi { channel, .. } => Some(channel.as_int()),
+            _ => None,
+        })
+        .collect();
+    channels.sort_unstable();
+    channels.dedup();
+    for channel in channels {
+        abs_events.push((
+            end_tick,
+            TrackEventKind::Midi {
+                channel: channel.into(),
+                message: MidiMessage::Controller {
+                    controller: 123.into(),
+                    value: 0.into(),
+                },
+            },
+        ));
+    }
+}
+
+/// Sorts absolute-tick events, converts them to deltas and terminates the track.
+fn encode_track(mut abs_events: Vec<(u32, TrackEventKind)>) -> Vec<TrackEvent> {
+    abs_events.sort_by(|(ta, ea), (tb, eb)| {
+        ta.cmp(tb)
+            .then_with(|| event_order_key(ea).cmp(&event_order_key(eb)))
+    });
+
+    let mut track: Vec<TrackEvent> = Vec::new();
+    let mut last_tick: u32 = 0;
+    for (tick, kind) in abs_events {
+        let delta = tick.saturating_sub(last_tick);
+        last_tick = tick;
+        track.push(TrackEvent {
+            delta: delta.into(),
+            kind,
+        });
+    }
+
+    track.push(TrackEvent {
+        delta: 0.into(),
+        kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+    });
+    track
+}
+
+/// Compact record of the main parameters, e.g.
+/// `gen seed=0xC0FFEE scale=minor-pentatonic root=60 bpm=120 version=0.1.0`.
+fn run_metadata(seq: &MidiSequence, config: &GenConfig) -> String {
+    // Only a non-default generator is named, so default files match older builds.
+    let rng = match config.rng.to_possible_value() {
+        Some(algo) if config.rng != RngAlgo::Chacha8 => format!(" rng={}", algo.get_name()),
+        _ => String::new(),
+    };
+    format!(
+        "gen seed={:#X}{} scale={} root={} bpm={} version={}",
+        config.seed,
+        rng,
+        config.scale,
+        config.root.as_u8(),
+        seq.bpm,
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+/// Encodes `seq` as a single-track file, or as Format 1 with one track per
+/// `MidiNote::track` index when notes use more than one. Tempo and meter live on track 0;
+/// the program change goes on the first track that plays `config.channel`.
+pub fn encode_sequence(seq: &MidiSequence, config: &GenConfig) -> Result<Vec<u8>, Box<dyn Error>> {
+    let track_count = seq
+        .notes
+        .iter()
+        .map(|n| n.track as usize + 1)
+        .max()
+        .unwrap_or(1);
+    let mut abs_tracks: Vec<Vec<(u32, TrackEventKind)>> = vec![Vec::new(); track_count];
+
+    if let Some(title) = &config.title {
+        abs_tracks[0].push((
+            0,
+            TrackEventKind::Meta(MetaMessage::TrackName(title.as_bytes())),
+        ));
+    }
+    if let Some(composer) = &config.composer {
+        abs_tracks[0].push((
+            0,
+            TrackEventKind::Meta(MetaMessage::Text(composer.as_bytes())),
+        ));
+    }
+    if let Some(text) = &config.embed_text {
+        abs_tracks[0].push((
+            0,
+            TrackEventKind::Meta(MetaMessage::SequencerSpecific(text.as_bytes())),
+        ));
+    }
+    let metadata = run_metadata(seq, config);
+    abs_tracks[0].push((
+        0,
+        TrackEventKind::Meta(MetaMessage::Text(metadata.as_bytes())),
+    ));
+
+    // The home key at tick 0 (unless a change lands there), then one per key change.
+    let intervals = resolve_scale(&config.scale, &config.user_scales)?;
+    let changes_at_zero = seq.key_changes.first().is_some_and(|&(tick, _)| tick == 0);
+    let home = (!changes_at_zero).then_some((0, 0));
+    for (tick, shift) in home.into_iter().chain(seq.key_changes.iter().copied()) {
+        let tonic_pc = (config.root.as_u8() as i16 + shift as i16).rem_euclid(12) as u8;
+        let (accidentals, minor) = key_signature(tonic_pc, &intervals);
+        abs_tracks[0].push((
+            tick,
+            TrackEventKind::Meta(MetaMessage::KeySignature(accidentals, minor)),
+        ));
+    }
+
+    // Denominator as a power of two; 24 MIDI clocks per click, 8 32nds per quarter.
+    let sig = seq.time_signature;
+    abs_tracks[0].push((
+        0,
+        TrackEventKind::Meta(MetaMessage::TimeSignature(
+            sig.num,
+            sig.den.trailing_zeros() as u8,
+            24,
+            8,
+        )),
+    ));
+
+    let us_per_qn = bpm_to_us_per_quarter(seq.bpm);
+    abs_tracks[0].push((
+        0,
+        TrackEventKind::Meta(MetaMessage::Tempo(us_per_qn.into())),
+    ));
+
+    let program_track = seq
+        .notes
+        .iter()
+        .filter(|n| n.channel == config.channel)
+        .map(|n| n.track as usize)
+        .min()
+        .unwrap_or(0);
+    abs_tracks[program_track].push((
+        0,
+        TrackEventKind::Midi {
+            channel: config.channel.into(),
+            message: MidiMessage::ProgramChange {
+                program: config.program.into(),
+            },
+        },
+    ));
+
+    let mut lanes = config.cc_lanes.clone();
+    lanes.sort_unstable();
+    lanes.dedup();
+    for controller in lanes {
+        let lane = cc_lane(
+            config.rng,
+            config.seed,
+            controller,
+            seq.total_ticks,
+            seq.bar_ticks(),
+            seq.ppqn,
+            config.cc_resolution,
+        );
+        for (tick, value) in lane {
+            abs_tracks[program_track].push((
+                tick,
+                TrackEventKind::Midi {
+                    channel: config.channel.into(),
+                    message: MidiMessage::Controller {
+                        controller: controller.into(),
+                        value: value.into(),
+                    },
+                },
+            ));
+        }
+    }
+
+    if config.sustain_from_chords {
+        for (tick, value) in chord_pedal(seq, config.channel) {
+            abs_tracks[program_track].push((
+                tick,
+                TrackEventKind::Midi {
+                    channel: config.channel.into(),
+                    message: MidiMessage::Controller {
+                        controller: 64.into(),
+                        value: value.into(),
+                    },
+                },
+            ));
+        }
+    }
+
+    if config.mpe {
+        // MPE Configuration Message (RPN 6) on the master channel, then bend range and
+        // program on every member channel.
+        push_rpn(&mut abs_tracks[0], 0, 6, config.mpe_channels);
+        for member in 1..=config.mpe_channels {
+            push_rpn(&mut abs_tracks[0], member, 0, MPE_BEND_RANGE);
+            abs_tracks[0].push((
+                0,
+                TrackEventKind::Midi {
+                    channel: member.into(),
+                    message: MidiMessage::ProgramChange {
+                        program: config.program.into(),
+                    },
+                },
+            ));
+        }
+    }
+
+    if config.glide {
+        let bends = glide_bends(seq, config.bend_range);
+        let mut channels: Vec<(u8, u8)> = bends.iter().map(|&(t, c, _, _)| (t, c)).collect();
+        channels.dedup();
+        for (track, channel) in channels {
+            push_rpn(
+                &mut abs_tracks[track as usize],
+                channel,
+                0,
+                config.bend_range,
+            );
+        }
+        for (track, channel, tick, bend) in bends {
+            abs_tracks[track as usize].push((
+                tick,
+                TrackEventKind::Midi {
+                    channel: channel.into(),
+                    message: MidiMessage::PitchBend {
+                        bend: midly::PitchBend::from_int(bend),
+                    },
+                },
+            ));
+        }
+    }
+
+    for note in &seq.notes {
+        let abs_events = &mut abs_tracks[note.track as usize];
+        if config.mpe && note.track == 0 {
+            // Member channels carry per-note bend; start each note from centre.
+            abs_events.push((
+                note.start_tick,
+                TrackEventKind::Midi {
+                    channel: note.channel.into(),
+                    message: MidiMessage::PitchBend {
+                        bend: midly::PitchBend::mid_raw_value(),
+                    },
+                },
+            ));
+        }
+        abs_events.push((
+            note.start_tick,
+            TrackEventKind::Midi {
+                channel: note.channel.into(),
+                message: MidiMessage::NoteOn {
+                    key: note.pitch.into(),
+                    vel: note.velocity.into(),
+                },
+            },
+        ));
+
+        abs_events.push((
+            note.end_tick,
+            TrackEventKind::Midi {
+                channel: note.channel.into(),
+                message: MidiMessage::NoteOff {
+                    key: note.pitch.into(),
+                    vel: 0.into(),
+                },
+            },
+        ));
+    }
+
+    if config.safety_note_off {
+        abs_tracks.iter_mut().for_each(push_all_notes_off);
+    }
+
+    let format = if track_count == 1 {
+        Format::SingleTrack
+    } else {
+        Format::Parallel
+    };
+    let header = Header::new(format, Timing::Metrical(seq.ppqn.into()));
+    let smf = Smf {
+        header,
+        tracks: abs_tracks.into_iter().map(encode_track).collect(),
+    };
+
+    let mut bytes = Vec::new();
+    smf.write_std(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Sample rate of [`render_wav`] output.
+#[cfg(feature = "wav")]
+pub const WAV_SAMPLE_RATE: u32 = 44_100;
+
+/// Renders `seq` as a mono 16-bit WAV preview: one sine per note at its equal-tempered
+/// frequency, loudness from velocity, shaped by a short attack/decay/sustain envelope
+/// with a release after the note ends. Percussion (channel 9) is left out. Not a synth,
+/// just enough to audition a seed.
+#[cfg(feature = "wav")]
+pub fn render_wav(seq: &MidiSequence, path: &str) -> Result<(), Box<dyn Error>> {
+    const ATTACK: f32 = 0.005;
+    const DECAY: f32 = 0.05;
+    const SUSTAIN: f32 = 0.7;
+    const RELEASE: f32 = 0.08;
+
+    let rate = WAV_SAMPLE_RATE as f32;
+    let seconds_per_tick = bpm_to_us_per_quarter(seq.bpm) as f32 / seq.ppqn as f32 / 1e6;
+    let sample_at = |tick: u32| (tick as f32 * seconds_per_tick * rate) as usize;
+    let len = sample_at(seq.total_ticks) + (RELEASE * rate) as usize + 1;
+    let mut mix = vec![0.0f32; len];
+
+    let level = |t: f32| {
+        if t < ATTACK {
+            t / ATTACK
+        } else if t < ATTACK + DECAY {
+            1.0 - (1.0 - SUSTAIN) * (t - ATTACK) / DECAY
+        } else {
+            SUSTAIN
+        }
+    };
+    for note in seq.notes.iter().filter(|n| n.channel != 9) {
+        let freq = 440.0 * 2f32.powf((note.pitch as f32 - 69.0) / 12.0);
+        let amp = note.velocity as f32 / 127.0 * 0.25;
+        let start = sample_at(note.start_tick);
+        let held = (sample_at(note.end_tick) - start) as f32 / rate;
+        let total = ((held + RELEASE) * rate) as usize;
+        for (i, out) in mix.iter_mut().skip(start).take(total).enumerate() {
+            let t = i as f32 / rate;
+            let env = if t < held {
+                level(t)
+            } else {
+                level(held) * (1.0 - (t - held) / RELEASE)
+            };
+            *out += amp * env * (std::f32::consts::TAU * freq * t).sin();
+        }
+    }
+
+    // Scaled down only if stacked notes would clip.
+    let peak = mix.iter().fold(0.0f32, |m, s| m.max(s.abs()));
+    let gain = if peak > 1.0 { 1.0 / peak } else { 1.0 };
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: WAV_SAMPLE_RATE,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::create(path, spec)?;
+    for sample in mix {
+        writer.write_sample((sample * gain * i16::MAX as f32) as i16)?;
+    }
+    writer.finalize()?;
+    Ok(())
+}
+
+pub fn write_file(out_path: &str, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+    if let Some(parent) = std::path::Path::new(out_path).parent() {
+        if !parent.as_os_str().is_empty() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+    fs::write(out_path, bytes)?;
+    Ok(())
+}
+
+/// Writes `seq` (tempo, timing and every note) as pretty-printed JSON.
+pub fn save_sequence_json(seq: &MidiSequence, out_path: &str) -> Result<(), Box<dyn Error>> {
+    let mut bytes = Vec::new();
+    serde_json::to_writer_pretty(&mut bytes, seq)?;
+    bytes.push(b'\n');
+    write_file(out_path, &bytes)
+}
+
+pub fn save_sequence(
+    seq: &MidiSequence,
+    config: &GenConfig,
+    out_path: &str,
+) -> Result<(), Box<dyn Error>> {
+    write_file(out_path, &encode_sequence(seq, config)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_configs_are_errors_not_panics() {
+        let invalid = [
+            GenConfig {
+                velocity_min: 100,
+                velocity_max: 60,
+                ..GenConfig::default()
+            },
+            GenConfig {
+                density: 1.5,
+                ..GenConfig::default()
+            },
+            GenConfig {
+                min_note: Some(Note(72)),
+                max_note: Some(Note(60)),
+                ..GenConfig::default()
+            },
+            GenConfig {
+                durations: vec!["1/8".parse().unwrap(); 17],
+                ..GenConfig::default()
+            },
+        ];
+        for config in invalid {
+            assert!(config.validate().is_err());
+            assert!(generate_sequence(&config).is_err());
+        }
+        assert!(GenConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn note_lengths_parse_to_ticks() {
+        let ticks = |text: &str| text.parse::<NoteLength>().unwrap().ticks(480);
+        assert_eq!(ticks("1/16"), 120);
+        assert_eq!(ticks("1/8."), 360);
+        assert_eq!(ticks("1/4t"), 320);
+        assert_eq!(ticks("3/8:5"), 720);
+        assert_eq!("1/16:40".parse::<NoteLength>().unwrap().weight, 40);
+    }
+
+    #[test]
+    fn bad_note_lengths_name_the_token() {
+        for token in [
+            "1/5x",
+            "1",
+            "0/4",
+            "1/0",
+            "/8",
+            "1/8:0",
+            "1/4:4294967295",
+            "1/2048",
+        ] {
+            let err = token.parse::<NoteLength>().unwrap_err();
+            assert!(err.contains(&format!("'{token}'")), "{err}");
+        }
+    }
+
+    #[test]
+    fn note_lengths_display_as_parsed() {
+        for text in ["1/1024t:1", "1/8.:3", "3/16:10000"] {
+            let length: NoteLength = text.parse().unwrap();
+            assert_eq!(length.to_string(), text);
+            assert_eq!(length.to_string().parse::<NoteLength>(), Ok(length));
+        }
+    }
+
+    #[test]
+    fn form_repeats_stay_in_note_and_velocity_range() {
+        for seed in 0..20 {
+            let config = GenConfig {
+                seed,
+                form: Some("ABAA".into()),
+                min_note: Some(Note(60)),
+                max_note: Some(Note(67)),
+                velocity_min: 50,
+                velocity_max: 90,
+                ..GenConfig::default()
+            };
+            let seq = generate_sequence(&config).unwrap();
+            let section_ticks = seq.bar_ticks() * config.section_bars;
+            let section = |index: u32| -> Vec<&MidiNote> {
+                let ticks = index * section_ticks..(index + 1) * section_ticks;
+                seq.notes
+                    .iter()
+                    .filter(|n| ticks.contains(&n.start_tick))
+                    .collect()
+            };
+            let first = section(0);
+            for repeat in [section(2), section(3)] {
+                assert_eq!(repeat.len(), first.len());
+                for (original, note) in first.iter().zip(repeat) {
+                    assert!(
+                        (60..=67).contains(&note.pitch),
+                        "seed {seed}: {}",
+                        note.pitch
+                    );
+                    let low = original.velocity.min(50);
+                    let high = original.velocity.max(90);
+                    assert!((low..=high).contains(&note.velocity), "seed {seed}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn range_policies_handle_notes_above_max() {
+        let free = generate_sequence(&GenConfig::default()).unwrap();
+        assert!(free.notes.iter().any(|n| n.pitch > 67));
+        let bounded = |range_policy| {
+            let config = GenConfig {
+                max_note: Some(Note(67)),
+                range_policy,
+                ..GenConfig::default()
+            };
+            generate_sequence(&config).unwrap().notes
+        };
+
+        let folded = bounded(RangePolicy::Fold);
+        assert_eq!(folded.len(), free.notes.len());
+        for (free, folded) in free.notes.iter().zip(&folded) {
+            assert!(folded.pitch <= 67 && folded.pitch % 12 == free.pitch % 12);
+        }
+
+        let clamped = bounded(RangePolicy::Clamp);
+        let expected: Vec<u8> = free.notes.iter().map(|n| n.pitch.min(67)).collect();
+        assert_eq!(
+            clamped.iter().map(|n| n.pitch).collect::<Vec<_>>(),
+            expected
+        );
+
+        let dropped = bounded(RangePolicy::Drop);
+        let kept: Vec<&MidiNote> = free.notes.iter().filter(|n| n.pitch <= 67).collect();
+        assert_eq!(dropped.iter().collect::<Vec<_>>(), kept);
+    }
+
+    #[test]
+    fn chord_extensions_stack_scale_thirds() {
+        let major = scale_semitones(ScaleOpt::Major);
+        for degree in 0..7 {
+            let seventh = build_chord(degree, &major, ChordExtensions::Seventh);
+            let ninth = build_chord(degree, &major, ChordExtensions::Ninth);
+            assert_eq!(seventh.len(), 4);
+            assert_eq!(ninth.len(), 5);
+            assert!(ninth.starts_with(&seventh));
+            for semis in ninth {
+                assert!(major.contains(&(semis.rem_euclid(12) as i8)), "{semis}");
+            }
+        }
+        assert_eq!(
+            build_chord(0, &major, ChordExtensions::Seventh),
+            [0, 4, 7, 11]
+        );
+        assert_eq!(
+            build_chord(4, &major, ChordExtensions::Ninth),
+            [7, 11, 14, 17, 21]
+        );
+
+        let config = GenConfig {
+            chords: true,
+            chord_extensions: ChordExtensions::Ninth,
+            ..GenConfig::default()
+        };
+        let first = generate_sequence(&config).unwrap();
+        let second = generate_sequence(&config).unwrap();
+        assert_eq!(first.notes, second.notes);
+        let mut onsets: BTreeMap<u32, usize> = BTreeMap::new();
+        for note in &first.notes {
+            *onsets.entry(note.start_tick).or_default() += 1;
+        }
+        assert!(onsets.values().all(|&tones| tones == 5));
+    }
+
+    #[test]
+    fn octave_shift_sections_move_whole_sections() {
+        let plain = GenConfig {
+            form: Some("AB".into()),
+            ..GenConfig::default()
+        };
+        let shifted = GenConfig {
+            section_shifts: vec!["b:+12".parse().unwrap()],
+            ..plain.clone()
+        };
+        let plain = generate_sequence(&plain).unwrap();
+        let shifted = generate_sequence(&shifted).unwrap();
+        let b_start = plain.total_ticks / 2;
+        assert_eq!(plain.notes.len(), shifted.notes.len());
+        for (before, after) in plain.notes.iter().zip(&shifted.notes) {
+            let octave = if before.start_tick >= b_start { 12 } else { 0 };
+            assert_eq!(after.pitch, before.pitch + octave);
+            assert_eq!(
+                (after.start_tick, after.velocity),
+                (before.start_tick, before.velocity)
+            );
+        }
+
+        for bad in ["B:+7", "B:60", "AB:+12", "B"] {
+            assert!(bad.parse::<SectionShift>().is_err(), "{bad}");
+        }
+        let missing = GenConfig {
+            form: Some("AA".into()),
+            section_shifts: vec!["B:-12".parse().unwrap()],
+            ..GenConfig::default()
+        };
+        assert!(generate_sequence(&missing).is_err());
+    }
+
+    #[test]
+    fn humanize_correlation_links_timing_and_velocity() {
+        let plain = generate_sequence(&GenConfig::default()).unwrap();
+        for correlation in [1.0f32, -1.0] {
+            let config = GenConfig {
+                humanize: Some(100),
+                humanize_correlation: correlation,
+                ..GenConfig::default()
+            };
+            let humanized = generate_sequence(&config).unwrap();
+            assert_eq!(humanized.notes, generate_sequence(&config).unwrap().notes);
+            let mut signed = 0;
+            for (base, note) in plain.notes.iter().zip(&humanized.notes) {
+                assert_eq!(base.grid_tick, note.grid_tick);
+                let timing = note.grid_offset().signum();
+                let velocity = (note.velocity as i64 - base.velocity as i64).signum();
+                if timing != 0 && velocity != 0 {
+                    assert_eq!(timing * velocity, correlation as i64, "{note:?}");
+                    signed += 1;
+                }
+            }
+            assert!(signed > plain.notes.len() / 2);
+        }
+    }
+
+    #[test]
+    fn gaussian_humanize_clusters_near_the_grid() {
+        let variance = |dist| {
+            let config = GenConfig {
+                bars: 64,
+                humanize: Some(100),
+                humanize_dist: dist,
+                ..GenConfig::default()
+            };
+            let seq = generate_sequence(&config).unwrap();
+            assert_eq!(seq.notes, generate_sequence(&config).unwrap().notes);
+            let max = (seq.ppqn as i64 / 4) / 4;
+            assert!(seq.notes.iter().all(|n| n.grid_offset().abs() <= max));
+            let offsets: Vec<f64> = seq.notes.iter().map(|n| n.grid_offset() as f64).collect();
+            offsets.iter().map(|o| o * o).sum::<f64>() / offsets.len() as f64
+        };
+        let uniform = variance(HumanizeDist::Uniform);
+        let gaussian = variance(HumanizeDist::Gaussian);
+        assert!(gaussian < uniform * 0.6, "{gaussian} vs {uniform}");
+    }
+
+    #[test]
+    fn preserve_order_keeps_humanized_onsets_in_grid_order() {
+        let layer = |preserve_order| {
+            let config = GenConfig {
+                humanize_channels: BTreeMap::from([(9, 100)]),
+                preserve_order,
+                ..GenConfig::default()
+            };
+            // Hits a few ticks apart, well inside the humanize offset.
+            let mut notes: Vec<MidiNote> = (0..64)
+                .map(|i| MidiNote {
+                    pitch: 42,
+                    start_tick: 100 + i * 4,
+                    end_tick: 102 + i * 4,
+                    velocity: 80,
+                    channel: 9,
+                    track: 0,
+                    grid_tick: 100 + i * 4,
+                    velocity_jitter: 0,
+                })
+                .collect();
+            humanize_layer(&mut notes, &config, 480, 10_000);
+            notes.sort_by_key(|n| n.grid_tick);
+            notes
+                .windows(2)
+                .all(|pair| pair[0].start_tick <= pair[1].start_tick)
+        };
+        assert!(!layer(false));
+        assert!(layer(true));
+
+        let config = GenConfig {
+            humanize: Some(100),
+            swing: 100,
+            preserve_order: true,
+            ..GenConfig::default()
+        };
+        let mut notes = generate_sequence(&config).unwrap().notes;
+        notes.sort_by_key(|n| n.grid_tick);
+        assert!(notes
+            .windows(2)
+            .all(|pair| pair[0].start_tick <= pair[1].start_tick));
+        assert!(notes.iter().all(|n| n.end_tick > n.start_tick));
+    }
+
+    #[test]
+    fn humanize_max_ticks_caps_the_offset() {
+        let config = GenConfig {
+            bars: 16,
+            humanize: Some(100),
+            ..GenConfig::default()
+        };
+        let free = generate_sequence(&config).unwrap();
+        assert!(free.notes.iter().any(|n| n.grid_offset().abs() > 5));
+        let capped = generate_sequence(&GenConfig {
+            humanize_max_ticks: Some(5),
+            ..config
+        })
+        .unwrap();
+        assert!(capped.notes.iter().all(|n| n.grid_offset().abs() <= 5));
+        assert!(capped.notes.iter().any(|n| n.grid_offset() != 0));
+    }
+
+    #[test]
+    fn song_length_past_the_smf_limit_is_rejected() {
+        // 4/4 at 480 PPQN is 1920 ticks a bar: 139810 bars fit in 28 bits, one more doesn't.
+        assert_eq!(
+            check_song_length(139_810, 480, TimeSig::COMMON),
+            Ok(268_435_200)
+        );
+        let err = check_song_length(139_811, 480, TimeSig::COMMON).unwrap_err();
+        assert!(err.contains("SMF limit"), "{err}");
+        let config = GenConfig {
+            bars: 139_811,
+            ppqn: 480,
+            ..GenConfig::default()
+        };
+        assert!(generate_sequence(&config).is_err());
+    }
+
+    #[test]
+    fn huge_bars_and_ppqn_error_instead_of_wrapping() {
+        // u32::MAX bars of 32/1 at 65535 PPQN is far past u32 ticks, let alone the SMF limit.
+        let widest = TimeSig::new(32, 1).unwrap();
+        for (bars, ppqn, time_sig) in [
+            (u32::MAX, u16::MAX, widest),
+            (u32::MAX, 4, TimeSig::COMMON),
+            (300_000, u16::MAX, TimeSig::COMMON),
+        ] {
+            assert!(check_song_length(bars, ppqn, time_sig).is_err());
+            let config = GenConfig {
+                bars,
+                ppqn,
+                time_signature: time_sig,
+                ..GenConfig::default()
+            };
+            assert!(generate_sequence(&config).is_err());
+        }
+    }
+
+    #[test]
+    fn degree_accent_lifts_the_tonic() {
+        let config = GenConfig {
+            bars: 32,
+            scale: "major".to_string(),
+            degree_accent: Some("1:16,3:8,5:8".parse().unwrap()),
+            ..GenConfig::default()
+        };
+        let seq = generate_sequence(&config).unwrap();
+        let root = config.root.as_u8();
+        let mean_velocity = |classes: &[u8]| {
+            let velocities: Vec<f32> = seq
+                .notes
+                .iter()
+                .filter(|n| classes.contains(&((n.pitch + 12 - root % 12) % 12)))
+                .map(|n| n.velocity as f32)
+                .collect();
+            assert!(!velocities.is_empty());
+            velocities.iter().sum::<f32>() / velocities.len() as f32
+        };
+        // Major scale: the tonic against the 2nd, 4th, 6th and 7th degrees.
+        assert!(mean_velocity(&[0]) > mean_velocity(&[2, 5, 9, 11]) + 8.0);
+    }
+
+    #[test]
+    fn avoided_pitch_classes_never_sound() {
+        let config = GenConfig {
+            bars: 16,
+            scale: "major".to_string(),
+            root: Note(62),
+            avoid_pcs: vec![4, 11],
+            chords: true,
+            ..GenConfig::default()
+        };
+        let seq = generate_sequence(&config).unwrap();
+        assert!(!seq.notes.is_empty());
+        for note in &seq.notes {
+            let class = (note.pitch + 12 - 62 % 12) % 12;
+            assert!(![4, 11].contains(&class), "{note:?}");
+        }
+    }
+
+    #[test]
+    fn meter_accents_rank_the_beats_of_four_four() {
+        let accent = |beat: u32| metrical_accent(beat * 4, (4, 4), 4);
+        assert!(accent(0) > accent(2));
+        assert!(accent(2) > accent(1) && accent(1) == accent(3));
+        assert_eq!(metrical_accent(1, (4, 4), 4), 0);
+
+        // Across a whole run, downbeat notes average louder than beat-3 notes, which
+        // average louder than beats 2 and 4.
+        let config = GenConfig {
+            bars: 64,
+            density: 1.0,
+            meter_accents: true,
+            ..GenConfig::default()
+        };
+        let seq = generate_sequence(&config).unwrap();
+        let quarter = seq.ppqn as u32;
+        let mean = |beats: &[u32]| {
+            let velocities: Vec<f32> = seq
+                .notes
+                .iter()
+                .filter(|n| n.grid_tick % quarter == 0)
+                .filter(|n| beats.contains(&(n.grid_tick / quarter % 4)))
+                .map(|n| n.velocity as f32)
+                .collect();
+            velocities.iter().sum::<f32>() / velocities.len() as f32
+        };
+        assert!(mean(&[0]) > mean(&[2]));
+        assert!(mean(&[2]) > mean(&[1, 3]));
+    }
+
+    #[test]
+    fn ratchets_split_a_step_into_equal_repeats() {
+        let note = MidiNote {
+            pitch: 64,
+            start_tick: 480,
+            end_tick: 600,
+            velocity: 90,
+            channel: 0,
+            track: 0,
+            grid_tick: 480,
+            velocity_jitter: 0,
+        };
+        let hits = ratchet(&note, 4);
+        assert_eq!(hits.len(), 4);
+        for (i, hit) in hits.iter().enumerate() {
+            assert_eq!(hit.pitch, 64);
+            assert_eq!(hit.start_tick, 480 + i as u32 * 30);
+            assert_eq!(hit.end_tick - hit.start_tick, 30);
+        }
+        assert_eq!(hits.last().unwrap().end_tick, note.end_tick);
+
+        let config = GenConfig {
+            bars: 4,
+            ratchet_prob: Some(1.0),
+            ratchet_count: 3,
+            ..GenConfig::default()
+        };
+        let plain = generate_sequence(&GenConfig {
+            ratchet_prob: None,
+            ..config.clone()
+        })
+        .unwrap();
+        let ratcheted = generate_sequence(&config).unwrap();
+        assert_eq!(ratcheted.notes.len(), plain.notes.len() * 3);
+        for (original, hits) in plain.notes.iter().zip(ratcheted.notes.chunks(3)) {
+            assert!(hits.iter().all(|h| h.pitch == original.pitch));
+            assert_eq!(hits[0].start_tick, original.start_tick);
+            assert_eq!(hits[2].end_tick, original.end_tick);
+            let len = hits[0].end_tick - hits[0].start_tick;
+            assert!(hits[..2].iter().all(|h| h.end_tick - h.start_tick == len));
+        }
+    }
+
+    #[test]
+    fn full_repeat_penalty_never_repeats_a_pitch() {
+        let repeats = |repeat_penalty| {
+            let config = GenConfig {
+                bars: 16,
+                density: 0.9,
+                repeat_penalty,
+                ..GenConfig::default()
+            };
+            let seq = generate_sequence(&config).unwrap();
+            seq.notes
+                .windows(2)
+                .filter(|pair| pair[0].pitch == pair[1].pitch)
+                .count()
+        };
+        assert!(repeats(None) > 0);
+        assert_eq!(repeats(Some(1.0)), 0);
+    }
+
+    #[test]
+    fn pcset_pitches_stay_in_the_set() {
+        let config = GenConfig {
+            bars: 16,
+            pcset: vec![1, 3, 6, 8, 10],
+            ..GenConfig::default()
+        };
+        let seq = generate_sequence(&config).unwrap();
+        assert!(!seq.notes.is_empty());
+        assert!(seq
+            .notes
+            .iter()
+            .all(|n| config.pcset.contains(&(n.pitch % 12))));
+    }
+
+    #[test]
+    fn fixed_length_sets_every_duration() {
+        for steps in [1, 3] {
+            let config = GenConfig {
+                bars: 8,
+                density: 0.9,
+                fixed_length: Some(steps),
+                ..GenConfig::default()
+            };
+            let seq = generate_sequence(&config).unwrap();
+            let step_ticks = config.ppqn as u32 / 4;
+            assert!(!seq.notes.is_empty());
+            for note in &seq.notes {
+                let len = note.end_tick - note.start_tick;
+                // The last note may be cut off at the song end.
+                let cut = note.end_tick == seq.total_ticks;
+                assert!(len == steps * step_ticks || cut, "{note:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn empty_bar_prob_silences_whole_bars() {
+        let run = |empty_bar_prob| {
+            let config = GenConfig {
+                bars: 16,
+                density: 0.8,
+                empty_bar_prob,
+                ..GenConfig::default()
+            };
+            let seq = generate_sequence(&config).unwrap();
+            let mut onsets = vec![0; 16];
+            for note in &seq.notes {
+                onsets[(note.start_tick / seq.bar_ticks()) as usize] += 1;
+            }
+            onsets
+        };
+        assert!(run(Some(1.0)).iter().all(|&count| count == 0));
+        let sparse = run(Some(0.5));
+        assert!(sparse.contains(&0));
+        assert!(sparse.iter().any(|&count| count > 0));
+        // Bars that keep their notes play what a run without silence plays there.
+        let full = run(None);
+        for (kept, all) in sparse.iter().zip(&full) {
+            assert!(*kept == 0 || kept == all);
+        }
+    }
+
+    #[test]
+    fn onset_weights_cluster_notes_on_the_downbeat() {
+        let mut onset_weights = vec![0.1; 16];
+        onset_weights[0] = 8.0;
+        let config = GenConfig {
+            bars: 32,
+            density: 0.3,
+            onset_weights,
+            ..GenConfig::default()
+        };
+        let seq = generate_sequence(&config).unwrap();
+        let step_ticks = seq.ppqn as u32 / 4;
+        let mut per_step = [0u32; 16];
+        for note in &seq.notes {
+            per_step[(note.grid_tick / step_ticks % 16) as usize] += 1;
+        }
+        let busiest_other = per_step[1..].iter().max().copied().unwrap();
+        assert!(per_step[0] > 5 * busiest_other.max(1), "{per_step:?}");
+    }
+
+    #[test]
+    fn scale_up_exercise_climbs_the_octaves() {
+        let config = GenConfig {
+            root: Note(57),
+            scale: "dorian".to_string(),
+            exercise: Some(Exercise::ScaleUp),
+            exercise_octaves: 2,
+            ..GenConfig::default()
+        };
+        let pitches: Vec<u8> = generate_sequence(&config)
+            .unwrap()
+            .notes
+            .iter()
+            .map(|n| n.pitch)
+            .collect();
+        assert_eq!(pitches.len(), 2 * 7 + 1);
+        assert_eq!((pitches[0], pitches[14]), (57, 57 + 24));
+        assert!(pitches.windows(2).all(|pair| pair[0] < pair[1]));
+        let dorian = resolve_scale("dorian", &BTreeMap::new()).unwrap();
+        assert!(pitches
+            .iter()
+            .all(|&p| dorian.contains(&(((p - 57) % 12) as i8))));
+    }
+
+    #[test]
+    fn reseed_per_bar_varies_bars_reproducibly() {
+        let config = GenConfig {
+            bars: 4,
+            reseed_per_bar: true,
+            ..GenConfig::default()
+        };
+        let seq = generate_sequence(&config).unwrap();
+        assert_eq!(seq.notes, generate_sequence(&config).unwrap().notes);
+        let bar_ticks = seq.bar_ticks();
+        let bar = |index: u32| {
+            seq.notes
+                .iter()
+                .filter(|n| n.start_tick / bar_ticks == index)
+                .map(|n| (n.start_tick - index * bar_ticks, n.pitch, n.velocity))
+                .collect::<Vec<_>>()
+        };
+        for a in 0..4 {
+            for b in a + 1..4 {
+                assert_ne!(bar(a), bar(b), "bars {a} and {b}");
+            }
+        }
+        let plain = generate_sequence(&GenConfig {
+            reseed_per_bar: false,
+            ..config
+        })
+        .unwrap();
+        assert_ne!(plain.notes, seq.notes);
+    }
+
+    #[test]
+    fn title_and_composer_open_the_first_track() {
+        let config = GenConfig {
+            title: Some("Night Drive".to_string()),
+            composer: Some("A. Person".to_string()),
+            voices: 2,
+            ..GenConfig::default()
+        };
+        let seq = generate_sequence(&config).unwrap();
+        let bytes = encode_sequence(&seq, &config).unwrap();
+        let smf = Smf::parse(&bytes).unwrap();
+        assert!(smf.tracks.len() > 1);
+        let first = &smf.tracks[0];
+        assert!(first[..2].iter().all(|e| e.delta == 0));
+        assert_eq!(
+            first[0].kind,
+            TrackEventKind::Meta(MetaMessage::TrackName(b"Night Drive"))
+        );
+        assert_eq!(
+            first[1].kind,
+            TrackEventKind::Meta(MetaMessage::Text(b"A. Person"))
+        );
+        let elsewhere = smf.tracks[1..].iter().flatten().any(|e| {
+            matches!(
+                e.kind,
+                TrackEventKind::Meta(MetaMessage::TrackName(b"Night Drive"))
+            )
+        });
+        assert!(!elsewhere);
+    }
+
+    #[test]
+    fn timing_humanize_seed_moves_onsets_only() {
+        let config = GenConfig {
+            humanize: Some(100),
+            timing_humanize_seed: Some(1),
+            ..GenConfig::default()
+        };
+        let a = generate_sequence(&config).unwrap();
+        let b = generate_sequence(&GenConfig {
+            timing_humanize_seed: Some(2),
+            ..config
+        })
+        .unwrap();
+        assert_eq!(a.notes.len(), b.notes.len());
+        let velocities =
+            |seq: &MidiSequence| seq.notes.iter().map(|n| n.velocity).collect::<Vec<_>>();
+        assert_eq!(velocities(&a), velocities(&b));
+        let onsets =
+            |seq: &MidiSequence| seq.notes.iter().map(|n| n.start_tick).collect::<Vec<_>>();
+        assert_ne!(onsets(&a), onsets(&b));
+    }
+
+    #[test]
+    fn grid_offset_measures_swing_from_the_grid() {
+        let config = GenConfig {
+            swing: 100,
+            ..GenConfig::default()
+        };
+        let seq = generate_sequence(&config).unwrap();
+        let step_ticks = config.ppqn as u32 / 4;
+        assert!(seq
+            .notes
+            .iter()
+            .any(|n| (n.grid_tick / step_ticks) % 2 == 1));
+        for note in &seq.notes {
+            assert_eq!(note.grid_tick % step_ticks, 0);
+            if (note.grid_tick / step_ticks) % 2 == 1 {
+                assert!(note.grid_offset() > 0, "{note:?}");
+            } else {
+                assert_eq!(note.grid_offset(), 0, "{note:?}");
+            }
+        }
+        let early = MidiNote {
+            pitch: 60,
+            start_tick: 110,
+            end_tick: 200,
+            velocity: 90,
+            channel: 0,
+            track: 0,
+            grid_tick: 120,
+            velocity_jitter: 0,
+        };
+        assert_eq!(early.grid_offset(), -10);
+    }
+
+    #[test]
+    fn humanize_layer_falls_back_to_the_global_amount() {
+        let hits = || -> Vec<MidiNote> {
+            (0..32)
+                .map(|i| MidiNote {
+                    pitch: 36,
+                    start_tick: 240 + i * 120,
+                    end_tick: 300 + i * 120,
+                    velocity: 100,
+                    channel: 9,
+                    track: 1,
+                    grid_tick: 240 + i * 120,
+                    velocity_jitter: 0,
+                })
+                .collect()
+        };
+        let moved = |config: &GenConfig| {
+            let mut notes = hits();
+            humanize_layer(&mut notes, config, 480, 10_000);
+            notes.iter().filter(|n| n.grid_offset() != 0).count()
+        };
+        // No entry for the drum channel: the global amount applies.
+        let global = GenConfig {
+            humanize: Some(80),
+            ..GenConfig::default()
+        };
+        assert!(moved(&global) > 0);
+        let mut tight = global.clone();
+        tight.humanize_channels.insert(9, 0);
+        assert_eq!(moved(&tight), 0);
+        let scoped = GenConfig {
+            groove_scope: vec![0],
+            ..global
+        };
+        assert_eq!(moved(&scoped), 0);
+        assert_eq!(moved(&GenConfig::default()), 0);
+    }
+
+    #[test]
+    fn modes_take_their_parent_major_key_signature() {
+        let signature = |root: &str, scale: &str| {
+            let config = GenConfig {
+                root: root.parse().unwrap(),
+                scale: scale.to_string(),
+                bars: 1,
+                ..GenConfig::default()
+            };
+            let seq = generate_sequence(&config).unwrap();
+            let bytes = encode_sequence(&seq, &config).unwrap();
+            let smf = Smf::parse(&bytes).unwrap();
+            smf.tracks[0]
+                .iter()
+                .find_map(|e| match e.kind {
+                    TrackEventKind::Meta(MetaMessage::KeySignature(sharps, minor)) => {
+                        Some((sharps, minor))
+                    }
+                    _ => None,
+                })
+                .unwrap()
+        };
+        assert_eq!(signature("D4", "dorian"), (0, true));
+        assert_eq!(signature("G4", "mixolydian"), (0, false));
+        assert_eq!(signature("F4", "lydian"), (0, false));
+        assert_eq!(signature("E4", "phrygian"), (0, true));
+        assert_eq!(signature("B3", "locrian"), (0, true));
+        assert_eq!(signature("A4", "dorian"), (1, true));
+        assert_eq!(signature("D4", "major"), (2, false));
+        assert_eq!(signature("D4", "natural-minor"), (-1, true));
+        assert_eq!(signature("A4", "minor-pentatonic"), (0, true));
+    }
+
+    #[test]
+    fn roman_numerals_build_diatonic_chords() {
+        let major = resolve_scale("major", &BTreeMap::new()).unwrap();
+        let minor = resolve_scale("natural-minor", &BTreeMap::new()).unwrap();
+        let c4 = Note(60);
+        let chord = |numeral: &str, scale: &[i8]| parse_roman(numeral, c4, scale).unwrap().0;
+        assert_eq!(chord("I", &major), [60, 64, 67]);
+        assert_eq!(chord("V7", &major), [67, 71, 74, 77]);
+        assert_eq!(chord("vi", &major), [69, 72, 76]);
+        assert_eq!(chord("vii°", &major), [71, 74, 77]);
+        assert_eq!(chord("viiø7", &major), [71, 74, 77, 81]);
+        assert_eq!(chord("bVII", &major), [70, 74, 77]);
+        // Case sets the quality, so V in minor is the major dominant.
+        assert_eq!(chord("V7", &minor), [67, 71, 74, 77]);
+        assert_eq!(chord("i", &minor), [60, 63, 67]);
+        assert_eq!(chord("vii°7", &minor), [70, 73, 76, 79]);
+        for bad in ["", "VIII", "Vi", "V9", "x"] {
+            assert!(parse_roman(bad, c4, &major).is_err(), "{bad}");
+        }
+        let pentatonic = resolve_scale("minor-pentatonic", &BTreeMap::new()).unwrap();
+        assert!(parse_roman("vi", c4, &pentatonic).is_err());
+    }
+
+    #[test]
+    fn sustain_from_chords_pedals_each_chord() {
+        let config = GenConfig {
+            chords: true,
+            sustain_from_chords: true,
+            bars: 4,
+            ..GenConfig::default()
+        };
+        let seq = generate_sequence(&config).unwrap();
+        let bytes = encode_sequence(&seq, &config).unwrap();
+        let smf = Smf::parse(&bytes).unwrap();
+        let mut tick = 0;
+        let mut pedal = Vec::new();
+        for event in &smf.tracks[0] {
+            tick += event.delta.as_int();
+            if let TrackEventKind::Midi {
+                channel,
+                message: MidiMessage::Controller { controller, value },
+            } = event.kind
+            {
+                assert_eq!((channel.as_int(), controller.as_int()), (0, 64));
+                pedal.push((tick, value.as_int()));
+            }
+        }
+        let mut onsets: Vec<u32> = seq.notes.iter().map(|n| n.start_tick).collect();
+        onsets.sort_unstable();
+        onsets.dedup();
+        assert!(onsets.len() > 1);
+        let downs: Vec<u32> = pedal
+            .iter()
+            .step_by(2)
+            .map(|&(t, v)| {
+                assert_eq!(v, 127);
+                t
+            })
+            .collect();
+        assert_eq!(downs, onsets);
+        for (i, &(up, value)) in pedal.iter().skip(1).step_by(2).enumerate() {
+            assert_eq!(value, 0);
+            assert!(up >= onsets[i]);
+            match onsets.get(i + 1) {
+                Some(&next) => assert_eq!(up, next - 1),
+                None => assert!(up <= seq.total_ticks),
+            }
+        }
+
+        let plain = GenConfig {
+            sustain_from_chords: true,
+            ..GenConfig::default()
+        };
+        let seq = generate_sequence(&plain).unwrap();
+        let bytes = encode_sequence(&seq, &plain).unwrap();
+        assert_eq!(bytes, encode_sequence(&seq, &GenConfig::default()).unwrap());
+    }
+}
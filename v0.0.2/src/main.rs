@@ -1,6 +1,16 @@
-use clap::{Parser, ValueEnum};
-use midly::{Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
-use rand_chacha::ChaCha8Rng;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+use random_midi_gen::{
+    apply_legato, bars_for_seconds, bpm_to_us_per_quarter, check_song_length, encode_sequence,
+    generate_sequence, gm_program, humanize_layer, parse_roman, resolve_scale, save_sequence,
+    save_sequence_json, scale_is_minor, swing_layer, write_file, Arp, Chord, ChordExtensions,
+    DegreeAccent, Exercise, GenConfig, HumanizeDist, MidiNote, MidiSequence, Note, NoteLength,
+    RangePolicy, RngAlgo, ScaleOpt, SectionShift, TimeSig, VelocityCurve, DRUM_FEEL_STREAM,
+    DRUM_STREAM, LENGTH_HUMANIZE_STREAM, MAX_SMF_TICKS, TEMPO_WOBBLE_STREAM,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs;
 use std::error::Error;
 
@@ -9,45 +19,71 @@ use macroquad::prelude::*;
 use midir::{MidiOutput, MidiOutputConnection};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
 // Import rand traits explicitly to avoid macroquad conflict
-use ::rand::{Rng, SeedableRng};
+use ::rand::Rng;
 
-#[derive(Debug, Clone, Copy, ValueEnum)]
-enum ScaleOpt {
-    Major,
-    NaturalMinor,
-    MinorPentatonic,
-    MajorPentatonic,
-}
-
-#[derive(Debug, Clone, Copy)]
-struct Note(u8);
-
-#[derive(Debug, Parser)]
+#[derive(Debug, Clone, Parser)]
 #[command(
     name = "midi-seed-gen",
     version,
     about = "Seeded random MIDI (format 0) generator"
 )]
 struct Cli {
+    /// TOML file of defaults for seed, bpm, bars, ppqn, root, scale, channel and program;
+    /// flags given on the command line win over it
+    #[arg(long)]
+    config: Option<String>,
+
+    /// Keep running and regenerate --out whenever this params TOML (the --config keys)
+    /// changes; a file that fails to load leaves the last good output in place
+    #[arg(long, requires = "out", conflicts_with_all = ["config", "gui", "seed_grid", "count", "dry_run"])]
+    watch: Option<String>,
+
     /// Output .mid path (if omitted, a timestamped name is generated)
     #[arg(short, long)]
     out: Option<String>,
 
+    /// Write the sequence as a standard MIDI file or as JSON (notes, tempo and timing)
+    /// instead
+    #[arg(long, value_enum, default_value_t = OutputFormat::Midi, conflicts_with_all = ["split_bars", "split_channels", "round_trip_check"])]
+    format: OutputFormat,
+
     /// RNG seed (same seed => same MIDI)
     #[arg(long, default_value_t = 0xC0FFEEu64)]
     seed: u64,
 
+    /// Random number generator: chacha8 (default), chacha20 or pcg. A file reproduces
+    /// only from the same algorithm and seed
+    #[arg(long, value_enum, default_value_t = RngAlgo::Chacha8)]
+    rng: RngAlgo,
+
+    /// Use the current time (nanoseconds since the Unix epoch) as the seed; it is printed
+    /// so the run can be repeated with --seed
+    #[arg(long, conflicts_with = "seed")]
+    seed_from_time: bool,
+
     /// Tempo in BPM
     #[arg(long, default_value_t = 120u32)]
     bpm: u32,
 
-    /// Bars (assumes 4/4)
+    /// Bars
     #[arg(long, default_value_t = 16u32)]
     bars: u32,
 
+    /// Time signature as N/D, e.g. `3/4` or `6/8` (denominator 1, 2, 4, 8 or 16)
+    #[arg(long, default_value = "4/4")]
+    time_signature: TimeSig,
+
+    /// Grow the bar count until at least this many notes are generated (overrides --bars)
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    bars_from_notes: Option<u32>,
+
+    /// Target length in seconds; uses the fewest whole bars at --bpm that last this long
+    #[arg(long, conflicts_with_all = ["bars", "bars_from_notes"])]
+    length_seconds: Option<f64>,
+
     /// Ticks per quarter note (PPQN)
     #[arg(long, default_value_t = 480u16)]
     ppqn: u16,
@@ -56,9 +92,40 @@ struct Cli {
     #[arg(long, default_value = "C4")]
     root: Note,
 
-    /// Scale / mode
-    #[arg(long, value_enum, default_value_t = ScaleOpt::MinorPentatonic)]
-    scale: ScaleOpt,
+    /// Lowest note to generate, e.g. E2; see --range-policy for lower pitches
+    #[arg(long)]
+    min_note: Option<Note>,
+
+    /// Highest note to generate, e.g. C6; see --range-policy for higher pitches
+    #[arg(long)]
+    max_note: Option<Note>,
+
+    /// What happens to pitches outside --min-note/--max-note: fold (move by octaves,
+    /// keeping the scale), clamp (pin to the bound) or drop (leave a rest)
+    #[arg(long, value_enum, default_value_t = RangePolicy::Fold)]
+    range_policy: RangePolicy,
+
+    /// Scale / mode: major, natural-minor, minor-pentatonic, major-pentatonic, dorian,
+    /// phrygian, lydian, mixolydian, locrian, or a name defined in --scales-file
+    #[arg(long, default_value = "minor-pentatonic")]
+    scale: String,
+
+    /// TOML file of named scales, e.g. `hirajoshi = [0, 2, 3, 7, 8]`
+    #[arg(long)]
+    scales_file: Option<String>,
+
+    /// Scales loaded from --scales-file
+    #[arg(skip)]
+    user_scales: BTreeMap<String, Vec<i8>>,
+
+    /// .mid whose melody trains the scale-degree transitions (a first-order Markov chain)
+    /// used instead of the built-in degree weights
+    #[arg(long, value_name = "FILE")]
+    corpus: Option<String>,
+
+    /// Melody pitches read from --corpus
+    #[arg(skip)]
+    corpus_pitches: Vec<u8>,
 
     /// MIDI channel (0..15)
     #[arg(long, default_value_t = 0u8)]
@@ -68,559 +135,4585 @@ struct Cli {
     #[arg(long, default_value_t = 0u8)]
     program: u8,
 
-    /// Launch GUI piano roll viewer
-    #[arg(long, default_value_t = false)]
-    gui: bool,
-}
+    /// General MIDI instrument by name instead of --program, e.g. `violin` or
+    /// `"acoustic grand piano"`
+    #[arg(long, value_name = "NAME", value_parser = gm_program, conflicts_with = "program")]
+    instrument: Option<u8>,
 
-impl Note {
-    fn as_u8(self) -> u8 {
-        self.0
-    }
-}
+    /// Chance (0.0..=1.0) that a sixteenth step gets a note rather than a rest
+    #[arg(long, default_value_t = 0.45f32)]
+    density: f32,
 
-impl std::str::FromStr for Note {
-    type Err = String;
+    /// Onset chance multipliers for the sixteenth steps of a bar, e.g. `4,1,2,1` (repeats
+    /// within the bar if shorter)
+    #[arg(long, value_delimiter = ',', value_parser = parse_weight)]
+    onset_weights: Vec<f32>,
 
-    fn from_str(input: &str) -> Result<Self, Self::Err> {
-        let s = input.trim();
-        if s.is_empty() {
-            return Err("empty note".into());
-        }
-
-        let mut it = s.chars();
-        let letter = it.next().ok_or_else(|| "empty note".to_string())?;
-        let base_pc: i32 = match letter.to_ascii_uppercase() {
-            'C' => 0,
-            'D' => 2,
-            'E' => 4,
-            'F' => 5,
-            'G' => 7,
-            'A' => 9,
-            'B' => 11,
-            _ => return Err(format!("bad note letter: {letter}")),
-        };
+    /// Chance (0..1) that a whole bar is left silent
+    #[arg(long, value_parser = parse_unit)]
+    empty_bar_prob: Option<f32>,
 
-        let mut pc = base_pc;
-        let mut octave_str = it.as_str();
+    /// Make every note exactly this many sixteenth steps long
+    #[arg(long, value_name = "STEPS", value_parser = clap::value_parser!(u32).range(1..=64))]
+    fixed_length: Option<u32>,
 
-        if let Some(acc) = it.clone().next() {
-            match acc {
-                '#' | '♯' => {
-                    pc += 1;
-                    it.next();
-                    octave_str = it.as_str();
-                }
-                'b' | 'B' | '♭' => {
-                    pc -= 1;
-                    it.next();
-                    octave_str = it.as_str();
-                }
-                _ => {}
-            }
-        }
+    /// Note lengths to draw from instead of one to four sixteenths, as whole-note fractions
+    /// with optional weights, e.g. `1/16:2,1/8,1/8.,1/4t` (`.` dotted, `t` triplet)
+    #[arg(long, value_delimiter = ',', conflicts_with = "fixed_length")]
+    durations: Vec<NoteLength>,
 
-        let octave_str = octave_str.trim();
-        if octave_str.is_empty() {
-            return Err("missing octave, expected like C#4".into());
-        }
+    /// Multiply every note position, duration and the song length by this factor
+    /// (e.g. `2`, `0.5`, `3/2`, from 1/64 to 64); unlike --bpm this changes the written rhythm
+    #[arg(long, conflicts_with_all = ["double_time", "half_time"])]
+    time_stretch: Option<Ratio>,
 
-        let octave: i32 = octave_str
-            .parse()
-            .map_err(|_| format!("bad octave: {octave_str}"))?;
+    /// Shorthand for --time-stretch 1/2: notes move twice as fast against the tempo
+    #[arg(long, conflicts_with = "half_time")]
+    double_time: bool,
 
-        let midi: i32 = (octave + 1) * 12 + pc;
+    /// Shorthand for --time-stretch 2: notes move half as fast against the tempo
+    #[arg(long)]
+    half_time: bool,
 
-        if !(0..=127).contains(&midi) {
-            return Err(format!("note out of MIDI range 0..127: {midi}"));
-        }
+    /// After stretching, keep the original song length (truncating notes past it)
+    /// instead of scaling it with the material
+    #[arg(long)]
+    keep_length: bool,
 
-        Ok(Note(midi as u8))
-    }
-}
+    /// Add a Format 1 guide track with a click on every beat (accented downbeats)
+    #[arg(long)]
+    guide_track: bool,
 
-#[derive(Clone, Debug)]
-struct MidiNote {
-    pitch: u8,
-    start_tick: u32,
-    end_tick: u32,
-    velocity: u8,
-}
+    /// MIDI channel for --guide-track (default 9, GM percussion)
+    #[arg(long, default_value_t = 9u8, value_parser = clap::value_parser!(u8).range(0..=15))]
+    guide_channel: u8,
 
-#[derive(Clone)]
-struct MidiSequence {
-    notes: Vec<MidiNote>,
-    bpm: u32,
-    ppqn: u16,
-    total_ticks: u32,
-}
+    /// Add a seeded GM drum track on channel 9: kick on beats, snare on backbeats,
+    /// closed hats on eighths
+    #[arg(long)]
+    drums: bool,
 
-fn default_out_path(seed: u64) -> String {
-    let ts = chrono::Local::now().format("%Y%m%d_%H%M%S").to_string();
-    format!("out/seeded_{ts}_{seed}.mid")
-}
+    /// Play a named groove on --drums instead of the plain beat: rock, funk, house, dnb
+    /// or bossa
+    #[arg(long, value_enum, requires = "drums")]
+    drum_groove: Option<DrumGroove>,
 
-fn bpm_to_us_per_quarter(bpm: u32) -> u32 {
-    60_000_000u32 / bpm.max(1)
-}
+    /// Per-limb timing for --drums: tight, loose or pushed (kick, snare and hats each
+    /// get their own lean against the grid plus a little jitter)
+    #[arg(long, value_enum, requires = "drums")]
+    drum_feel: Option<DrumFeel>,
 
-fn scale_semitones(s: ScaleOpt) -> &'static [i8] {
-    match s {
-        ScaleOpt::Major => &[0, 2, 4, 5, 7, 9, 11],
-        ScaleOpt::NaturalMinor => &[0, 2, 3, 5, 7, 8, 10],
-        ScaleOpt::MinorPentatonic => &[0, 3, 5, 7, 10],
-        ScaleOpt::MajorPentatonic => &[0, 2, 4, 7, 9],
-    }
-}
+    /// Hold the tonic an octave below the root for the whole song (following --key-change)
+    #[arg(long)]
+    drone: bool,
 
-fn weighted_choice<R: Rng>(rng: &mut R, items: &[(u8, u32)]) -> u8 {
-    let total: u32 = items.iter().map(|(_, w)| *w).sum();
-    let mut x = rng.gen_range(0..total.max(1));
-    for (v, w) in items {
-        if x < *w {
-            return *v;
-        }
-        x -= *w;
-    }
-    items.last().unwrap().0
-}
+    /// Add the fifth above the tonic to --drone
+    #[arg(long, requires = "drone")]
+    drone_fifth: bool,
 
-fn event_order_key(kind: &TrackEventKind) -> u8 {
-    match kind {
-        TrackEventKind::Midi { message, .. } => match message {
-            MidiMessage::NoteOff { .. } => 0,
-            MidiMessage::NoteOn { .. } => 1,
-            _ => 2,
-        },
-        TrackEventKind::Meta(_) => 3,
-        TrackEventKind::SysEx(_) | TrackEventKind::Escape(_) => 4,
-    }
-}
+    /// MIDI channel for --drone (default: same as --channel)
+    #[arg(long, requires = "drone", value_parser = clap::value_parser!(u8).range(0..=15))]
+    drone_channel: Option<u8>,
 
-fn generate_sequence(cli: &Cli) -> Result<MidiSequence, Box<dyn Error>> {
-    let mut rng = ChaCha8Rng::seed_from_u64(cli.seed);
-    let scale = scale_semitones(cli.scale);
-    let base_note = cli.root.as_u8() as i16;
+    /// Hold a chord per bar under the melody, cycling through these Roman numerals
+    /// relative to --root and --scale, e.g. `"I vi IV V"` (upper case major, lower case
+    /// minor; `7`, `°`, `ø` and `+` after a numeral, `b` or `#` before it)
+    #[arg(long, value_name = "NUMERALS")]
+    roman: Option<String>,
 
-    let steps_per_bar = 16u32;
-    let step_ticks: u32 = (cli.ppqn as u32) / 4;
-    let total_steps: u32 = cli.bars * steps_per_bar;
-    let song_len_ticks: u32 = total_steps * step_ticks;
+    /// Pedal the chords: sustain (CC 64) down at each --chords or --roman chord on
+    /// --channel and up just before the next one
+    #[arg(long, conflicts_with = "mpe")]
+    sustain_from_chords: bool,
 
-    let mut notes = Vec::new();
-    let mut last_degree: i32 = 0;
+    /// Pitch classes to leave out of the scale, in semitones above the root (e.g. `5` or `1,6`)
+    #[arg(long, value_delimiter = ',', value_parser = clap::value_parser!(u8).range(0..12))]
+    avoid_pcs: Vec<u8>,
 
-    for step in 0..total_steps {
-        let t0 = step * step_ticks;
+    /// Draw from these absolute pitch classes (C = 0, e.g. `0,1,4,6`) instead of
+    /// --root/--scale (which are ignored, except that the root picks the octave)
+    #[arg(long, value_delimiter = ',', conflicts_with = "avoid_pcs", value_parser = clap::value_parser!(u8).range(0..12))]
+    pcset: Vec<u8>,
 
-        if rng.gen_range(0..100u32) < 55 {
-            continue;
-        }
+    /// End every track with All Notes Off (CC 123) on each channel it uses, so hardware
+    /// synths are guaranteed silent afterwards
+    #[arg(long)]
+    safety_note_off: bool,
 
-        let max_deg = (scale.len() as i32).max(1);
-        let target = if max_deg >= 3 {
-            weighted_choice(&mut rng, &[(0, 30), (1, 15), (2, 30), (3, 15), (4, 10)]) as i32
-        } else {
-            rng.gen_range(0..max_deg as u32) as i32
-        };
-        let target = target.clamp(0, max_deg - 1);
+    /// Write each bar as its own clip (`out_001.mid`, `out_002.mid`, ... numbered from bar 1)
+    /// instead of one file; notes are cut at the bar line
+    #[arg(long, conflicts_with = "count")]
+    split_bars: bool,
 
-        let deg = if rng.gen_range(0..100u32) < 65 {
-            let delta = match rng.gen_range(0..3u32) {
-                0 => -1,
-                1 => 0,
-                _ => 1,
-            };
-            (last_degree + delta).clamp(0, max_deg - 1)
-        } else {
-            target
-        };
-        last_degree = deg;
+    /// MPE (lower zone): master on channel 1, each note on its own member channel from
+    /// channel 2 up, with the bend range set by RPN at the start
+    #[arg(long)]
+    mpe: bool,
 
-        let semis = scale[deg as usize] as i16;
-        let octave_shift: i16 = match rng.gen_range(0..100u32) {
-            0..=9 => 12,
-            10..=14 => -12,
-            _ => 0,
-        };
+    /// Slide into each note that closely follows the previous one with pitch bend
+    /// (portamento), within --bend-range
+    #[arg(long, conflicts_with = "mpe")]
+    glide: bool,
 
-        let note_i16 = base_note + semis + octave_shift;
-        let note_u8 = note_i16.clamp(0, 127) as u8;
+    /// Pitch-bend range in semitones for --glide (1-24), written as RPN 0
+    #[arg(long, default_value_t = 2u8, requires = "glide", value_parser = clap::value_parser!(u8).range(1..=24))]
+    bend_range: u8,
 
-        let dur_steps: u32 =
-            weighted_choice(&mut rng, &[(1, 40), (2, 30), (3, 10), (4, 20)]) as u32;
+    /// Hold each note until the next one starts, turning short rests into ties and
+    /// cutting overlaps so the line stays monophonic
+    #[arg(long)]
+    legato: bool,
 
-        let t1 = (t0 + dur_steps * step_ticks).min(song_len_ticks);
+    /// Longest rest in sixteenth steps that --legato fills (1-64); longer rests are kept
+    #[arg(long, default_value_t = 4u32, requires = "legato", value_parser = clap::value_parser!(u32).range(1..=64))]
+    legato_max: u32,
 
-        let accent: u8 = if step % 4 == 0 { 18 } else { 0 };
-        let vel: u8 = (rng.gen_range(55..95) as u16 + accent as u16).min(127) as u8;
+    /// Number of MPE member channels (1-15)
+    #[arg(long, default_value_t = 15u8, requires = "mpe", value_parser = clap::value_parser!(u8).range(1..=15))]
+    mpe_channels: u8,
 
-        notes.push(MidiNote {
-            pitch: note_u8,
-            start_tick: t0,
-            end_tick: t1,
-            velocity: vel,
-        });
-    }
+    /// Independent melodic lines, each from its own seed (`seed ^ voice`); more than one
+    /// writes Format 1 with a conductor track plus one track per voice
+    #[arg(long, default_value_t = 1u32, conflicts_with = "mpe", value_parser = clap::value_parser!(u32).range(1..=15))]
+    voices: u32,
 
-    Ok(MidiSequence {
-        notes,
-        bpm: cli.bpm,
-        ppqn: cli.ppqn,
-        total_ticks: song_len_ticks,
-    })
-}
+    /// Song form as section letters, e.g. AABA or ABAB: each letter is a motif of
+    /// --section-bars bars, varied a little when repeated (--bars is ignored)
+    #[arg(long, value_parser = parse_form, conflicts_with_all = ["exercise", "bars_from_notes", "length_seconds"])]
+    repeat: Option<String>,
 
-fn save_sequence(seq: &MidiSequence, cli: &Cli, out_path: &str) -> Result<(), Box<dyn Error>> {
-    let mut abs_events: Vec<(u32, TrackEventKind)> = Vec::new();
+    /// Bars per --repeat section (1-16)
+    #[arg(long, default_value_t = 2u32, requires = "repeat", value_parser = clap::value_parser!(u32).range(1..=16))]
+    section_bars: u32,
 
-    let us_per_qn = bpm_to_us_per_quarter(seq.bpm);
-    abs_events.push((
-        0,
-        TrackEventKind::Meta(MetaMessage::Tempo(us_per_qn.into())),
-    ));
+    /// Move every section with a --repeat letter by whole octaves, e.g. `B:+12`
+    /// (repeatable or comma-separated, -48 to +48); the range policy still applies
+    #[arg(long, value_delimiter = ',', requires = "repeat")]
+    octave_shift_sections: Vec<SectionShift>,
 
-    abs_events.push((
-        0,
-        TrackEventKind::Midi {
-            channel: cli.channel.into(),
-            message: MidiMessage::ProgramChange {
-                program: cli.program.into(),
-            },
-        },
-    ));
+    /// Write a fixed practice run in the key instead of a random melody (--bars is ignored)
+    #[arg(long, value_enum, conflicts_with = "voices")]
+    exercise: Option<Exercise>,
 
-    for note in &seq.notes {
-        abs_events.push((
-            note.start_tick,
-            TrackEventKind::Midi {
-                channel: cli.channel.into(),
-                message: MidiMessage::NoteOn {
-                    key: note.pitch.into(),
-                    vel: note.velocity.into(),
-                },
-            },
-        ));
+    /// Octaves the --exercise run spans (1-4)
+    #[arg(long, default_value_t = 1u32, requires = "exercise", value_parser = clap::value_parser!(u32).range(1..=4))]
+    exercise_octaves: u32,
 
-        abs_events.push((
-            note.end_tick,
-            TrackEventKind::Midi {
-                channel: cli.channel.into(),
-                message: MidiMessage::NoteOff {
-                    key: note.pitch.into(),
-                    vel: 0.into(),
-                },
-            },
-        ));
-    }
+    /// Also write the melody as LilyPond source to this path
+    #[arg(long, value_name = "PATH")]
+    export_ly: Option<String>,
 
-    abs_events.sort_by(|(ta, ea), (tb, eb)| {
-        ta.cmp(tb)
-            .then_with(|| event_order_key(ea).cmp(&event_order_key(eb)))
-    });
+    /// Also write a rough sine-wave WAV preview to this path (needs the `wav` feature)
+    #[cfg(feature = "wav")]
+    #[arg(long, value_name = "PATH")]
+    render_wav: Option<String>,
 
-    let mut track: Vec<TrackEvent> = Vec::new();
-    let mut last_tick: u32 = 0;
-    for (tick, kind) in abs_events {
-        let delta = tick.saturating_sub(last_tick);
-        last_tick = tick;
-        track.push(TrackEvent {
-            delta: delta.into(),
-            kind,
-        });
-    }
+    /// Write one single-track file per channel used (`out_ch00.mid`, `out_ch09.mid`, ...)
+    /// instead of one file, each with its own tempo and program change
+    #[arg(long, conflicts_with_all = ["count", "split_bars", "mpe"])]
+    split_channels: bool,
 
-    track.push(TrackEvent {
-        delta: 0.into(),
-        kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
-    });
+    /// Reload every written .mid and fail unless it holds exactly the generated notes
+    #[arg(long, conflicts_with_all = ["split_bars", "split_channels"])]
+    round_trip_check: bool,
 
-    let header = Header::new(Format::SingleTrack, Timing::Metrical(seq.ppqn.into()));
-    let smf = Smf {
-        header,
-        tracks: vec![track],
-    };
+    /// Print the peak number of simultaneous notes and the tick where it first occurs
+    #[arg(long)]
+    poly_report: bool,
 
-    if let Some(parent) = std::path::Path::new(out_path).parent() {
-        if !parent.as_os_str().is_empty() {
-            fs::create_dir_all(parent)?;
-        }
-    }
-    smf.save(out_path)?;
-    Ok(())
-}
+    /// Write each note's grid tick, timing offset, humanize velocity change and channel to
+    /// this path as JSON, for plotting the groove
+    #[arg(long)]
+    humanize_report_json: Option<String>,
 
-// ============================================================================
-// GUI MODE
-// ============================================================================
+    /// Print note count, pitch range, length and average velocity, then exit without
+    /// writing anything
+    #[arg(long, conflicts_with_all = ["gui", "save", "seed_grid", "count", "export_ly"])]
+    dry_run: bool,
 
-struct PlaybackState {
-    playing: bool,
-    current_tick: u32,
-}
+    /// Song title, written as the first track's name
+    #[arg(long)]
+    title: Option<String>,
 
-fn setup_midi_output() -> Result<MidiOutputConnection, Box<dyn Error>> {
-    let midi_out = MidiOutput::new("MIDI Seed Gen")?;
-    let out_ports = midi_out.ports();
-    
-    if out_ports.is_empty() {
-        return Err("No MIDI output ports available".into());
-    }
-    
-    // Use first available port
-    let out_port = &out_ports[0];
-    let port_name = midi_out.port_name(out_port).unwrap_or_else(|_| "Unknown".to_string());
-    println!("Connected to MIDI output: {}", port_name);
-    
-    let conn = midi_out.connect(out_port, "midi-gen-output")?;
-    Ok(conn)
-}
+    /// Composer, written as a text meta event at tick 0
+    #[arg(long)]
+    composer: Option<String>,
 
-fn spawn_playback_thread(
-    seq: MidiSequence,
-    channel: u8,
-    state: Arc<Mutex<PlaybackState>>,
-) {
-    thread::spawn(move || {
-        let mut midi_out = match setup_midi_output() {
-            Ok(m) => m,
-            Err(e) => {
-                eprintln!("Failed to setup MIDI: {}", e);
-                return;
-            }
-        };
+    /// Embed the full command that reproduces this file as a text meta event at tick 0
+    #[arg(long)]
+    embed_command: bool,
 
-        loop {
-            let (playing, current_tick) = {
-                let s = state.lock().unwrap();
-                (s.playing, s.current_tick)
-            };
+    /// Regenerate a file from its --embed-command text and verify it matches byte for byte
+    /// (writes the regenerated copy to --out if given)
+    #[arg(long)]
+    reproduce: Option<String>,
 
-            if !playing {
-                thread::sleep(Duration::from_millis(50));
-                continue;
-            }
+    /// Transpose from a 1-based bar onward, e.g. `9:+2` (repeatable; changes add up)
+    #[arg(long)]
+    key_change: Vec<KeyChange>,
 
-            // Play notes that start at current tick
-            for note in &seq.notes {
-                if note.start_tick == current_tick {
-                    let note_on = [0x90 | channel, note.pitch, note.velocity];
-                    midi_out.send(&note_on).ok();
-                }
-                if note.end_tick == current_tick {
-                    let note_off = [0x80 | channel, note.pitch, 0];
-                    midi_out.send(&note_off).ok();
-                }
-            }
+    /// Add a seeded automation sweep for this controller number (0-119), e.g. 1 for the
+    /// mod wheel or 74 for brightness (repeatable, one lane each)
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=119))]
+    cc: Vec<u8>,
 
-            // Advance tick
-            {
-                let mut s = state.lock().unwrap();
-                s.current_tick += 1;
-                if s.current_tick >= seq.total_ticks {
-                    s.current_tick = 0;
-                }
-            }
+    /// Controller events per quarter note in each --cc lane (1-96)
+    #[arg(long, default_value_t = 8u32, value_parser = clap::value_parser!(u32).range(1..=96))]
+    cc_resolution: u32,
 
-            // Calculate sleep duration based on BPM and PPQN
-            let microseconds_per_tick = (bpm_to_us_per_quarter(seq.bpm) as f64) / (seq.ppqn as f64);
-            let sleep_duration = Duration::from_micros(microseconds_per_tick as u64);
-            thread::sleep(sleep_duration);
-        }
-    });
-}
+    /// Randomly vary each note's length by up to this fraction (0..1); starts stay on the grid
+    #[arg(long, value_parser = parse_unit)]
+    length_humanize: Option<f32>,
 
-async fn run_gui(mut cli: Cli, mut seq: MidiSequence) {
-    let state = Arc::new(Mutex::new(PlaybackState {
-        playing: false,
-        current_tick: 0,
-    }));
+    /// Load notes from this .mid instead of generating (the post passes below still apply)
+    #[arg(long)]
+    input: Option<String>,
 
-    spawn_playback_thread(seq.clone(), cli.channel, Arc::clone(&state));
+    /// Combine these .mid files into one multitrack file, one track per input; PPQN
+    /// follows the first file (others are resampled) and so does the tempo
+    #[arg(long, num_args = 1.., conflicts_with = "input")]
+    merge: Vec<String>,
 
-    loop {
-        clear_background(Color::from_rgba(15, 15, 20, 255));
+    /// Detect the key of --input and transpose it to this tonic (e.g. `G`, `F#`, `Bb`),
+    /// keeping its mode and moving by the smallest interval
+    #[arg(long, requires = "input", value_parser = parse_pitch_class)]
+    transpose_to_key: Option<u8>,
 
-        // Calculate dimensions
-        let panel_height = 100.0;
-        let piano_roll_y = panel_height;
-        let piano_roll_height = screen_height() - panel_height;
+    /// Grade beat accents by the meter (downbeat strongest, then beat 3, then the other
+    /// beats) instead of the same boost on every beat
+    #[arg(long)]
+    meter_accents: bool,
 
-        // Find pitch range
-        let min_pitch = seq.notes.iter().map(|n| n.pitch).min().unwrap_or(60) - 2;
-        let max_pitch = seq.notes.iter().map(|n| n.pitch).max().unwrap_or(72) + 2;
-        let pitch_range = (max_pitch - min_pitch + 1) as f32;
+    /// Play every note as a triad: the third and fifth scale degrees stacked above it
+    #[arg(long)]
+    chords: bool,
 
-        // Time scaling
-        let time_scale = (screen_width() - 100.0) / seq.total_ticks as f32;
+    /// How far --chords stacks thirds: triad, 7, 9, 11 or 13
+    #[arg(long, value_enum, default_value_t = ChordExtensions::Triad, requires = "chords")]
+    chord_extensions: ChordExtensions,
 
-        // ===== CONTROL PANEL =====
-        draw_rectangle(0.0, 0.0, screen_width(), panel_height, Color::from_rgba(25, 25, 30, 255));
+    /// Arpeggiate instead of a random melody: every sixteenth steps through the triad on
+    /// the scale degree chosen each beat (notes one step long unless --fixed-length)
+    #[arg(long, value_enum, conflicts_with = "chords")]
+    arp: Option<Arp>,
 
-        // Title
-        draw_text(
-            &format!("MIDI SEED GENERATOR - Seed: 0x{:X}", cli.seed),
-            20.0,
-            30.0,
-            24.0,
-            WHITE,
-        );
-        draw_text(
-            &format!("BPM: {} | Scale: {:?} | Root: {}", seq.bpm, cli.scale, cli.root.as_u8()),
-            20.0,
-            55.0,
-            18.0,
-            LIGHTGRAY,
-        );
+    /// Reseed the melody at every bar from `seed + bar` for more independent bars
+    /// (still reproducible; the melodic line carries on across the bar line)
+    #[arg(long)]
+    reseed_per_bar: bool,
 
-        // Buttons
-        let play_btn_x = 20.0;
-        let play_btn_y = 70.0;
-        let btn_w = 100.0;
-        let btn_h = 25.0;
+    /// Chance (0..1) of moving to a neighbouring scale degree when a note would repeat the
+    /// previous pitch
+    #[arg(long, value_parser = parse_unit)]
+    repeat_penalty: Option<f32>,
 
-        let (playing, current_tick) = {
-            let s = state.lock().unwrap();
-            (s.playing, s.current_tick)
-        };
+    /// Timing and velocity jitter, 0-100 (at 100: onsets up to a quarter step early or
+    /// late, velocity up to 12 either way)
+    #[arg(long, value_parser = clap::value_parser!(u32).range(0..=100))]
+    humanize: Option<u32>,
 
-        // Play/Stop button
-        let play_color = if playing {
-            Color::from_rgba(255, 60, 60, 255)
-        } else {
-            Color::from_rgba(0, 255, 128, 255)
-        };
-        draw_rectangle(play_btn_x, play_btn_y, btn_w, btn_h, play_color);
-        let play_text = if playing { "STOP" } else { "PLAY" };
-        draw_text(play_text, play_btn_x + 25.0, play_btn_y + 18.0, 20.0, BLACK);
+    /// Seed for the --humanize timing jitter alone (default: --seed)
+    #[arg(long, requires = "humanize")]
+    timing_humanize_seed: Option<u64>,
 
-        if is_mouse_button_pressed(MouseButton::Left) {
-            let (mx, my) = mouse_position();
-            if mx >= play_btn_x && mx <= play_btn_x + btn_w && my >= play_btn_y && my <= play_btn_y + btn_h {
-                let mut s = state.lock().unwrap();
-                s.playing = !s.playing;
-                if s.playing {
-                    s.current_tick = 0;
-                }
-            }
-        }
+    /// Seed for the --humanize velocity jitter alone (default: --seed)
+    #[arg(long, requires = "humanize")]
+    velocity_humanize_seed: Option<u64>,
 
-        // Regenerate button
-        let regen_btn_x = play_btn_x + btn_w + 10.0;
-        draw_rectangle(regen_btn_x, play_btn_y, btn_w + 20.0, btn_h, Color::from_rgba(60, 150, 255, 255));
-        draw_text("REGENERATE", regen_btn_x + 10.0, play_btn_y + 18.0, 18.0, BLACK);
+    /// Link the --humanize velocity jitter to the timing jitter, -1 to 1: positive makes
+    /// late notes louder and early ones softer, negative rushed notes louder
+    #[arg(long, default_value_t = 0.0, requires = "humanize", allow_negative_numbers = true, value_parser = parse_signed_unit)]
+    humanize_correlation: f32,
 
-        if is_mouse_button_pressed(MouseButton::Left) {
-            let (mx, my) = mouse_position();
-            if mx >= regen_btn_x && mx <= regen_btn_x + btn_w + 20.0 && my >= play_btn_y && my <= play_btn_y + btn_h {
-                cli.seed = ::rand::random();
-                seq = generate_sequence(&cli).unwrap();
-                let mut s = state.lock().unwrap();
-                s.playing = false;
-                s.current_tick = 0;
-            }
-        }
+    /// Shape of the --humanize jitter: uniform, or gaussian to bunch it near the grid
+    #[arg(long, value_enum, default_value_t = HumanizeDist::Uniform, requires = "humanize")]
+    humanize_dist: HumanizeDist,
 
-        // ===== PIANO ROLL =====
-        // Draw background
-        draw_rectangle(0.0, piano_roll_y, screen_width(), piano_roll_height, Color::from_rgba(20, 20, 25, 255));
+    /// Never move a humanized onset more than this many ticks off its grid position,
+    /// whatever the --humanize amount
+    #[arg(long)]
+    humanize_max_ticks: Option<u32>,
 
-        // Draw piano keys (left side)
-        let key_width = 80.0;
-        for pitch in min_pitch..=max_pitch {
-            let y = piano_roll_y + ((max_pitch - pitch) as f32 / pitch_range) * piano_roll_height;
-            let row_height = piano_roll_height / pitch_range;
+    /// Keep humanized onsets in their grid order: a note jittered early never lands
+    /// before the one before it
+    #[arg(long)]
+    preserve_order: bool,
 
-            // White/black key coloring
-            let note_class = pitch % 12;
-            let is_black = matches!(note_class, 1 | 3 | 6 | 8 | 10);
-            let key_color = if is_black {
-                Color::from_rgba(30, 30, 35, 255)
-            } else {
-                Color::from_rgba(45, 45, 50, 255)
-            };
+    /// Humanize amount for one channel, e.g. `9:5` for tight --drums and `0:40` for a
+    /// looser melody (repeatable; 0-100 like --humanize, which the other channels use)
+    #[arg(long)]
+    humanize_channel: Vec<ChannelAmount>,
 
-            draw_rectangle(0.0, y, key_width, row_height, key_color);
-            draw_line(0.0, y, screen_width(), y, 1.0, Color::from_rgba(40, 40, 45, 255));
+    /// Shuffle, 0-100: delays the off-beat sixteenths, with 100 giving a triplet feel
+    #[arg(long, default_value_t = 0u32, value_parser = clap::value_parser!(u32).range(0..=100))]
+    swing: u32,
 
-            // Note name
-            let note_name = note_to_string(pitch);
-            draw_text(&note_name, 10.0, y + row_height / 2.0 + 5.0, 16.0, LIGHTGRAY);
-        }
+    /// Only apply swing, humanize, --length-humanize and --drum-feel to these channels,
+    /// e.g. `0` to leave --drums straight (comma-separated; default: every channel)
+    #[arg(long, value_delimiter = ',', value_parser = clap::value_parser!(u8).range(0..=15))]
+    groove_scope: Vec<u8>,
 
-        // Draw time grid
-        let quarters = (seq.total_ticks / seq.ppqn as u32) as usize;
-        for q in 0..=quarters {
-            let x = key_width + (q as f32 * seq.ppqn as f32 * time_scale);
-            let color = if q % 4 == 0 {
-                Color::from_rgba(80, 80, 90, 255)
-            } else {
-                Color::from_rgba(50, 50, 55, 255)
-            };
-            draw_line(x, piano_roll_y, x, screen_height(), 1.0, color);
-        }
+    /// Slow sinusoidal timing drift: DEPTH is the peak offset in beats (0..1), RATE the
+    /// cycles per bar; the written tempo is untouched
+    #[arg(long, num_args = 2, value_names = ["DEPTH", "RATE"])]
+    tempo_wobble: Vec<f32>,
 
-        // Draw notes
-        for note in &seq.notes {
-            let y = piano_roll_y + ((max_pitch - note.pitch) as f32 / pitch_range) * piano_roll_height;
-            let row_height = piano_roll_height / pitch_range;
-            let x = key_width + (note.start_tick as f32 * time_scale);
-            let width = ((note.end_tick - note.start_tick) as f32 * time_scale).max(2.0);
+    /// Pull velocities toward their mean: 1 leaves them as they are, 0 makes them all equal
+    #[arg(long, value_name = "RATIO", value_parser = parse_unit)]
+    compress_velocity: Option<f32>,
 
-            // Velocity to opacity
-            let alpha = (note.velocity as f32 / 127.0 * 0.6 + 0.4) as u8;
-            
-            let note_color = Color::from_rgba(0, 180, 255, alpha.saturating_mul(255));
-            draw_rectangle(x, y + 2.0, width, row_height - 4.0, note_color);
-            draw_rectangle_lines(x, y + 2.0, width, row_height - 4.0, 1.0, Color::from_rgba(100, 200, 255, 200));
-        }
+    /// Velocity added per semitone above the root (negative makes high notes softer),
+    /// applied before --compress-velocity
+    #[arg(long, value_name = "PER_SEMITONE", allow_negative_numbers = true)]
+    velocity_pitch_tilt: Option<f32>,
 
-        // Draw playhead
-        if playing {
-            let playhead_x = key_width + (current_tick as f32 * time_scale);
-            draw_line(playhead_x, piano_roll_y, playhead_x, screen_height(), 2.0, Color::from_rgba(255, 60, 60, 255));
-        }
+    /// Chance (0..1) that a note is played as a ratchet: fast repeats of the same pitch
+    /// filling its length
+    #[arg(long, value_parser = parse_unit)]
+    ratchet_prob: Option<f32>,
 
-        next_frame().await
+    /// Repeats per ratchet (2-4)
+    #[arg(long, default_value_t = 3u32, requires = "ratchet_prob", value_parser = clap::value_parser!(u32).range(2..=4))]
+    ratchet_count: u32,
+
+    /// Velocity boost per 1-based scale degree, e.g. `1:16,3:8,5:8` (the bare flag uses that map)
+    #[arg(long, num_args = 0..=1, default_missing_value = "1:16,3:8,5:8")]
+    degree_accent: Option<DegreeAccent>,
+
+    /// Spread of the base velocity between --velocity-min and --velocity-max: linear
+    /// (uniform), exp (mostly loud) or log (mostly quiet); accents add on top
+    #[arg(long, value_enum, default_value_t = VelocityCurve::Linear)]
+    velocity_curve: VelocityCurve,
+
+    /// Lowest base velocity (1-126)
+    #[arg(long, default_value_t = 55u8, value_parser = clap::value_parser!(u8).range(1..=126))]
+    velocity_min: u8,
+
+    /// Base velocities stay below this (2-128)
+    #[arg(long, default_value_t = 95u8, value_parser = clap::value_parser!(u8).range(2..=128))]
+    velocity_max: u8,
+
+    /// Launch GUI piano roll viewer
+    #[arg(long, default_value_t = false)]
+    gui: bool,
+
+    /// With --gui, also write the .mid (to --out or the default name) before the window opens
+    #[arg(long, requires = "gui")]
+    save: bool,
+
+    /// Start the GUI with the grid overlay on: a faint mark at each note's grid position
+    /// joined to its actual (swung, humanized) onset (toggle with the GRID button)
+    #[arg(long)]
+    humanize_visualize: bool,
+
+    /// Spell piano roll note names with sharps or flats
+    #[arg(long, value_enum, default_value_t = Accidentals::Sharp)]
+    accidentals: Accidentals,
+
+    /// GUI playback port: an index, or part of the port name (default: the first port)
+    #[arg(long, value_name = "PORT")]
+    midi_port: Option<String>,
+
+    /// Print the MIDI output ports with their --midi-port indices and exit
+    #[arg(long)]
+    list_midi_ports: bool,
+
+    /// Only loop the first N bars during GUI playback (the saved file keeps its full length)
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    preview_bars: Option<u32>,
+
+    /// Open a <cols>x<rows> grid of thumbnails for seeds seed..seed+cols*rows (implies --gui)
+    #[arg(long)]
+    seed_grid: Option<SeedGrid>,
+
+    /// Number of files to generate; file i uses seed + i
+    #[arg(long, visible_alias = "batch", default_value_t = 1u32, value_parser = clap::value_parser!(u32).range(1..))]
+    count: u32,
+
+    /// Worker threads for --count batches (output does not depend on this)
+    #[arg(long, default_value_t = 1usize)]
+    jobs: usize,
+
+    /// After a --count batch, write a manifest of each file and its seed to this path
+    /// (CSV if it ends in `.csv`, JSON otherwise)
+    #[arg(long, value_name = "PATH")]
+    manifest: Option<String>,
+}
+
+impl Cli {
+    /// The library config for this command line; the embedded text is the normalized
+    /// command when --embed-command is set.
+    fn gen_config(&self) -> GenConfig {
+        GenConfig {
+            seed: self.seed,
+            bpm: self.bpm,
+            bars: self.bars,
+            ppqn: self.ppqn,
+            time_signature: self.time_signature,
+            root: self.root,
+            min_note: self.min_note,
+            max_note: self.max_note,
+            range_policy: self.range_policy,
+            scale: self.scale.clone(),
+            user_scales: self.user_scales.clone(),
+            corpus: self.corpus_pitches.clone(),
+            channel: self.channel,
+            program: self.program,
+            density: self.density,
+            onset_weights: self.onset_weights.clone(),
+            fixed_length: self.fixed_length,
+            durations: self.durations.clone(),
+            empty_bar_prob: self.empty_bar_prob,
+            avoid_pcs: self.avoid_pcs.clone(),
+            pcset: self.pcset.clone(),
+            degree_accent: self.degree_accent.clone(),
+            velocity_curve: self.velocity_curve,
+            velocity_min: self.velocity_min,
+            velocity_max: self.velocity_max,
+            meter_accents: self.meter_accents,
+            chords: self.chords,
+            chord_extensions: self.chord_extensions,
+            sustain_from_chords: self.sustain_from_chords,
+            reseed_per_bar: self.reseed_per_bar,
+            ratchet_prob: self.ratchet_prob,
+            ratchet_count: self.ratchet_count,
+            repeat_penalty: self.repeat_penalty,
+            humanize: self.humanize,
+            timing_humanize_seed: self.timing_humanize_seed,
+            velocity_humanize_seed: self.velocity_humanize_seed,
+            humanize_correlation: self.humanize_correlation,
+            humanize_dist: self.humanize_dist,
+            humanize_max_ticks: self.humanize_max_ticks,
+            preserve_order: self.preserve_order,
+            humanize_channels: self
+                .humanize_channel
+                .iter()
+                .map(|c| (c.channel, c.amount))
+                .collect(),
+            swing: self.swing,
+            groove_scope: self.groove_scope.clone(),
+            mpe: self.mpe,
+            mpe_channels: self.mpe_channels,
+            voices: self.voices,
+            arp: self.arp,
+            form: self.repeat.clone(),
+            section_bars: self.section_bars,
+            section_shifts: self.octave_shift_sections.clone(),
+            exercise: self.exercise,
+            exercise_octaves: self.exercise_octaves,
+            safety_note_off: self.safety_note_off,
+            title: self.title.clone(),
+            composer: self.composer.clone(),
+            rng: self.rng,
+            glide: self.glide,
+            bend_range: self.bend_range,
+            legato: self.legato,
+            legato_max: self.legato_max,
+            cc_lanes: self.cc.clone(),
+            cc_resolution: self.cc_resolution,
+            embed_text: self.embed_command.then(|| normalized_command(self)),
+        }
     }
 }
 
-fn note_to_string(pitch: u8) -> String {
-    let note_names = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
-    let octave = (pitch / 12) as i32 - 1;
-    let note = note_names[(pitch % 12) as usize];
-    format!("{}{}", note, octave)
+/// What the main output file holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Midi,
+    Json,
 }
 
-// ============================================================================
-// MAIN
-// ============================================================================
+/// How black-key note names are spelled in the piano roll.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Accidentals {
+    Sharp,
+    Flat,
+}
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let cli = Cli::parse();
+/// Named `--drum-groove` templates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum DrumGroove {
+    Rock,
+    Funk,
+    House,
+    Dnb,
+    Bossa,
+}
 
-    let seq = generate_sequence(&cli)?;
+/// `--drum-feel` presets: how each limb of the drummer leans against the grid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum DrumFeel {
+    /// Close to the grid, hats a hair ahead
+    Tight,
+    /// Loose timing, snare laid back and hats ahead
+    Loose,
+    /// Everything ahead of the beat, the hats most
+    Pushed,
+}
 
-    if cli.gui {
-        // Launch GUI - macroquad::Window::new takes a label, not Conf
-        // We set window config via environment variables before launching
-        std::env::set_var("MACROQUAD_WINDOW_WIDTH", "1400");
-        std::env::set_var("MACROQUAD_WINDOW_HEIGHT", "700");
-        
-        macroquad::Window::new("MIDI Seed Generator", async move {
-            run_gui(cli, seq).await;
-        });
-        
-        Ok(())
+impl DrumFeel {
+    /// `(bias, jitter)` in hundredths of a sixteenth for the limb playing `note`: the
+    /// kick foot, the snare hand, or the cymbal hand (hats and ride).
+    fn limb(self, note: u8) -> (i64, i64) {
+        let limbs = match self {
+            DrumFeel::Tight => [(0, 2), (0, 3), (-3, 3)],
+            DrumFeel::Loose => [(0, 10), (6, 12), (-4, 12)],
+            DrumFeel::Pushed => [(-4, 4), (-6, 5), (-12, 5)],
+        };
+        match note {
+            KICK => limbs[0],
+            SNARE | SIDE_STICK => limbs[1],
+            _ => limbs[2],
+        }
+    }
+}
+
+/// `--key-change` entry parsed from `bar:semitones`, with `bar` 1-based.
+#[derive(Debug, Clone, Copy)]
+struct KeyChange {
+    bar: u32,
+    semitones: i8,
+}
+
+impl std::str::FromStr for KeyChange {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let (bar, semis) = input
+            .trim()
+            .split_once(':')
+            .ok_or_else(|| format!("expected bar:semitones, got '{input}'"))?;
+        let bar: u32 = bar
+            .trim()
+            .parse()
+            .ok()
+            .filter(|b| *b >= 1)
+            .ok_or_else(|| format!("bad bar '{bar}' (1-based)"))?;
+        let semitones: i8 = semis
+            .trim()
+            .trim_start_matches('+')
+            .parse()
+            .ok()
+            .filter(|s: &i8| (-24..=24).contains(s))
+            .ok_or_else(|| format!("bad semitones '{semis}' (-24..24)"))?;
+        Ok(KeyChange { bar, semitones })
+    }
+}
+
+/// `--humanize-channel` entry parsed from `channel:amount`.
+#[derive(Debug, Clone, Copy)]
+struct ChannelAmount {
+    channel: u8,
+    amount: u32,
+}
+
+impl std::str::FromStr for ChannelAmount {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let (channel, amount) = input
+            .trim()
+            .split_once(':')
+            .ok_or_else(|| format!("expected channel:amount, got '{input}'"))?;
+        let channel: u8 = channel
+            .trim()
+            .parse()
+            .ok()
+            .filter(|c| *c <= 15)
+            .ok_or_else(|| format!("bad channel '{channel}' (0-15)"))?;
+        let amount: u32 = amount
+            .trim()
+            .parse()
+            .ok()
+            .filter(|a| *a <= 100)
+            .ok_or_else(|| format!("bad humanize amount '{amount}' (0-100)"))?;
+        Ok(ChannelAmount { channel, amount })
+    }
+}
+
+/// Positive rational factor parsed from `3/2`, `2` or `0.5`, kept exact for tick math.
+#[derive(Debug, Clone, Copy)]
+struct Ratio {
+    num: u64,
+    den: u64,
+}
+
+impl Ratio {
+    /// Scales `tick`, rounding half up so every position uses the same rule. Errors
+    /// when the result doesn't fit a Standard MIDI File.
+    fn scale(self, tick: u32) -> Result<u32, String> {
+        let (num, den) = (self.num as u128, self.den as u128);
+        let scaled = (tick as u128 * num + den / 2) / den;
+        if scaled > MAX_SMF_TICKS as u128 {
+            return Err(format!(
+                "time stretch makes tick {tick} into {scaled}, over the SMF limit of {MAX_SMF_TICKS}"
+            ));
+        }
+        Ok(scaled as u32)
+    }
+}
+
+impl std::fmt::Display for Ratio {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}/{}", self.num, self.den)
+    }
+}
+
+impl std::str::FromStr for Ratio {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let s = input.trim();
+        let bad = || format!("bad factor '{input}', expected e.g. 2, 0.5 or 3/2");
+        let (num, den) = if let Some((n, d)) = s.split_once('/') {
+            let n: u64 = n.trim().parse().map_err(|_| bad())?;
+            let d: u64 = d.trim().parse().map_err(|_| bad())?;
+            (n, d)
+        } else {
+            let (int, frac) = s.split_once('.').unwrap_or((s, ""));
+            if frac.len() > 6 || !frac.chars().all(|c| c.is_ascii_digit()) {
+                return Err(bad());
+            }
+            let digits = |v: &str| match v {
+                "" => Ok(0),
+                _ => v.parse::<u64>().map_err(|_| bad()),
+            };
+            let den = 10u64.pow(frac.len() as u32);
+            (digits(int)? * den + digits(frac)?, den)
+        };
+        if num == 0 || den == 0 {
+            return Err(format!("factor must be positive, got '{input}'"));
+        }
+        let (n, d) = (num as u128, den as u128);
+        if n * 64 < d || n > d * 64 {
+            return Err(format!(
+                "factor '{input}' is out of range, expected 1/64 to 64"
+            ));
+        }
+        let (mut a, mut b) = (num, den);
+        while b != 0 {
+            (a, b) = (b, a % b);
+        }
+        Ok(Ratio {
+            num: num / a,
+            den: den / a,
+        })
+    }
+}
+
+/// Upper bound for `--bars-from-notes` so a tiny density can't loop forever.
+const MAX_AUTO_BARS: u32 = 1024;
+
+fn default_out_path(seed: u64) -> String {
+    let ts = chrono::Local::now().format("%Y%m%d_%H%M%S").to_string();
+    format!("out/seeded_{ts}_{seed}.mid")
+}
+
+/// Saves `seq` in the --format chosen; a default out path gets the matching extension.
+fn save_output(seq: &MidiSequence, cli: &Cli, out_path: &str) -> Result<String, Box<dyn Error>> {
+    match cli.format {
+        OutputFormat::Midi => {
+            save_sequence(seq, &cli.gen_config(), out_path)?;
+            Ok(out_path.to_string())
+        }
+        OutputFormat::Json => {
+            let json_path = match &cli.out {
+                Some(_) => out_path.to_string(),
+                None => std::path::Path::new(out_path)
+                    .with_extension("json")
+                    .to_string_lossy()
+                    .into_owned(),
+            };
+            save_sequence_json(seq, &json_path)?;
+            Ok(json_path)
+        }
+    }
+}
+
+/// Playing time of the whole sequence in seconds.
+fn sequence_seconds(seq: &MidiSequence) -> f64 {
+    seq.total_ticks as f64 / seq.ppqn.max(1) as f64 * bpm_to_us_per_quarter(seq.bpm) as f64
+        / 1_000_000.0
+}
+
+/// Length of the sequence in bars (fractional after a time stretch).
+fn sequence_bars(seq: &MidiSequence) -> f64 {
+    seq.total_ticks as f64 / seq.bar_ticks().max(1) as f64
+}
+
+/// One-line `--dry-run` summary on stdout.
+fn print_summary(seq: &MidiSequence, seed: u64) {
+    let bars = seq.total_ticks as f64 / seq.bar_ticks().max(1) as f64;
+    let pitches = seq.notes.iter().map(|n| n.pitch);
+    let range = match (pitches.clone().min(), pitches.max()) {
+        (Some(lo), Some(hi)) => format!("{}..{}", note_to_string(lo), note_to_string(hi)),
+        _ => "-".to_string(),
+    };
+    let velocity_sum: u64 = seq.notes.iter().map(|n| n.velocity as u64).sum();
+    let avg_velocity = velocity_sum as f64 / seq.notes.len().max(1) as f64;
+    println!(
+        "seed=0x{:X} notes={} range={} bars={} avg_velocity={:.1}",
+        seed,
+        seq.notes.len(),
+        range,
+        bars,
+        avg_velocity
+    );
+}
+
+/// One note in `--humanize-report-json`.
+#[derive(Debug, Serialize)]
+struct GrooveEntry {
+    /// Grid onset before swing, humanize or feel moved it
+    original_tick: u32,
+    /// Ticks the written onset sits after (positive) or before `original_tick`
+    timing_offset: i64,
+    velocity_delta: i16,
+    channel: u8,
+}
+
+/// The `--humanize-report-json` entries, one per note in onset order.
+fn groove_report(seq: &MidiSequence) -> Vec<GrooveEntry> {
+    seq.notes
+        .iter()
+        .map(|note| GrooveEntry {
+            original_tick: note.grid_tick,
+            timing_offset: note.grid_offset(),
+            velocity_delta: note.velocity_jitter,
+            channel: note.channel,
+        })
+        .collect()
+}
+
+/// Peak count of notes sounding at once and the first tick it is reached. A note
+/// ending on the tick another starts doesn't overlap it.
+fn max_polyphony(seq: &MidiSequence) -> (u32, u32) {
+    let mut edges: Vec<(u32, i32)> = Vec::with_capacity(seq.notes.len() * 2);
+    for note in &seq.notes {
+        edges.push((note.start_tick, 1));
+        edges.push((note.end_tick, -1));
+    }
+    // Offs (-1) sort before ons at the same tick.
+    edges.sort_unstable();
+
+    let (mut voices, mut peak, mut peak_tick) = (0i32, 0i32, 0u32);
+    for (tick, delta) in edges {
+        voices += delta;
+        if voices > peak {
+            peak = voices;
+            peak_tick = tick;
+        }
+    }
+    (peak as u32, peak_tick)
+}
+
+/// Intervals must be semitones above the root, strictly ascending within one octave.
+fn validate_scale(name: &str, intervals: &[i8]) -> Result<(), String> {
+    if intervals.is_empty() {
+        return Err(format!("scale '{name}' has no intervals"));
+    }
+    if let Some(bad) = intervals.iter().find(|&&i| !(0..12).contains(&i)) {
+        return Err(format!("scale '{name}': interval {bad} outside 0..11"));
+    }
+    if intervals.windows(2).any(|w| w[0] >= w[1]) {
+        return Err(format!("scale '{name}': intervals must ascend"));
+    }
+    Ok(())
+}
+
+/// Reads the files named by --scales-file and --corpus into `cli`.
+fn load_cli_files(cli: &mut Cli) -> Result<(), Box<dyn Error>> {
+    if let Some(path) = &cli.scales_file {
+        cli.user_scales = load_scales_file(path)?;
+    }
+    if let Some(path) = &cli.corpus {
+        cli.corpus_pitches = corpus_pitches(&load_sequence(path)?);
+        if cli.corpus_pitches.len() < 2 {
+            return Err(format!("--corpus {path} needs at least two melody notes").into());
+        }
+    }
+    Ok(())
+}
+
+/// The melody line of `seq` for training: the highest non-drum pitch at each onset.
+fn corpus_pitches(seq: &MidiSequence) -> Vec<u8> {
+    let mut onsets: BTreeMap<u32, u8> = BTreeMap::new();
+    for note in seq.notes.iter().filter(|n| n.channel != DRUM_CHANNEL) {
+        let top = onsets.entry(note.start_tick).or_insert(note.pitch);
+        *top = (*top).max(note.pitch);
+    }
+    onsets.into_values().collect()
+}
+
+/// The `--config` keys, each optional. Unknown keys are an error.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    seed: Option<u64>,
+    bpm: Option<u32>,
+    bars: Option<u32>,
+    ppqn: Option<u16>,
+    root: Option<Note>,
+    scale: Option<String>,
+    channel: Option<u8>,
+    program: Option<u8>,
+}
+
+/// Fills `cli` from the --config file wherever the flag wasn't given on the command
+/// line, so the precedence is command line, then file, then built-in defaults.
+fn apply_config_file(cli: &mut Cli, matches: &ArgMatches) -> Result<(), Box<dyn Error>> {
+    let Some(path) = &cli.config else {
+        return Ok(());
+    };
+    let text = fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+    let file: ConfigFile = toml::from_str(&text).map_err(|e| format!("{path}: {e}"))?;
+    let unset = |id: &str| matches.value_source(id) != Some(ValueSource::CommandLine);
+    if let (Some(seed), true) = (file.seed, unset("seed")) {
+        cli.seed = seed;
+    }
+    if let (Some(bpm), true) = (file.bpm, unset("bpm")) {
+        cli.bpm = bpm;
+    }
+    if let (Some(bars), true) = (file.bars, unset("bars")) {
+        cli.bars = bars;
+    }
+    if let (Some(ppqn), true) = (file.ppqn, unset("ppqn")) {
+        cli.ppqn = ppqn;
+    }
+    if let (Some(root), true) = (file.root, unset("root")) {
+        cli.root = root;
+    }
+    if let (Some(scale), true) = (file.scale, unset("scale")) {
+        cli.scale = scale;
+    }
+    if let (Some(channel), true) = (file.channel, unset("channel")) {
+        cli.channel = channel;
+    }
+    if let (Some(program), true) = (file.program, unset("program")) {
+        cli.program = program;
+    }
+    Ok(())
+}
+
+/// How often `--watch` polls the params file, and how long it must stay unchanged
+/// before a regenerate (editors often save in several writes).
+const WATCH_POLL: Duration = Duration::from_millis(100);
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// `--watch` change detection: a new modification time (or the file appearing or
+/// vanishing) starts the debounce, and it fires once the time has held still that long.
+struct WatchDebounce {
+    seen: Option<SystemTime>,
+    changed_at: Option<Instant>,
+    debounce: Duration,
+}
+
+impl WatchDebounce {
+    fn new(mtime: Option<SystemTime>, debounce: Duration) -> Self {
+        WatchDebounce {
+            seen: mtime,
+            changed_at: None,
+            debounce,
+        }
+    }
+
+    /// Records one poll; true when a settled change is due a regenerate.
+    fn observe(&mut self, mtime: Option<SystemTime>, now: Instant) -> bool {
+        if mtime != self.seen {
+            self.seen = mtime;
+            self.changed_at = Some(now);
+        }
+        match self.changed_at {
+            Some(at) if now.duration_since(at) >= self.debounce => {
+                self.changed_at = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// One `--watch` regenerate: `cli` as given on the command line with `path` as its
+/// --config, saved to --out only once everything has loaded and generated.
+fn regenerate_watched(
+    cli: &Cli,
+    matches: &ArgMatches,
+    path: &str,
+) -> Result<String, Box<dyn Error>> {
+    let mut cli = Cli {
+        config: Some(path.to_string()),
+        ..cli.clone()
+    };
+    apply_config_file(&mut cli, matches)?;
+    if let Some(program) = cli.instrument {
+        cli.program = program;
+    }
+    load_cli_files(&mut cli)?;
+    resolve_scale(&cli.scale, &cli.user_scales)?;
+    check_song_length(cli.bars, cli.ppqn, cli.time_signature)?;
+    let seq = generate_from_cli(&mut cli)?;
+    let out_path = cli.out.clone().ok_or("--watch needs --out")?;
+    save_output(&seq, &cli, &out_path)
+}
+
+/// Regenerates from `path` now and on every settled change, until interrupted.
+fn watch_params(cli: &Cli, matches: &ArgMatches, path: &str) -> Result<(), Box<dyn Error>> {
+    let mtime = || fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut debounce = WatchDebounce::new(mtime(), WATCH_DEBOUNCE);
+    let regenerate = || match regenerate_watched(cli, matches, path) {
+        Ok(out_path) => eprintln!("Wrote {}", out_path),
+        Err(e) => eprintln!("Failed to regenerate: {e}; keeping the last good output"),
+    };
+    eprintln!("Watching {path} (Ctrl-C to stop)");
+    regenerate();
+    loop {
+        thread::sleep(WATCH_POLL);
+        if debounce.observe(mtime(), Instant::now()) {
+            regenerate();
+        }
+    }
+}
+
+fn load_scales_file(path: &str) -> Result<BTreeMap<String, Vec<i8>>, Box<dyn Error>> {
+    let text = fs::read_to_string(path)?;
+    let scales: BTreeMap<String, Vec<i8>> =
+        toml::from_str(&text).map_err(|e| format!("{path}: {e}"))?;
+    for (name, intervals) in &scales {
+        if ScaleOpt::from_str(name, true).is_ok() {
+            return Err(format!("{path}: '{name}' shadows a built-in scale").into());
+        }
+        validate_scale(name, intervals).map_err(|e| format!("{path}: {e}"))?;
+    }
+    Ok(scales)
+}
+
+/// clap value parser for a --repeat form: 1 to 16 section letters, case-insensitive.
+fn parse_form(input: &str) -> Result<String, String> {
+    let form = input.trim().to_ascii_uppercase();
+    if form.is_empty() || form.len() > 16 || !form.chars().all(|c| c.is_ascii_uppercase()) {
+        return Err(format!("'{input}' is not a form like AABA (1-16 letters)"));
+    }
+    Ok(form)
+}
+
+/// clap value parser for amounts in 0.0..=1.0.
+fn parse_unit(input: &str) -> Result<f32, String> {
+    let value: f32 = input
+        .trim()
+        .parse()
+        .map_err(|_| format!("'{input}' is not a number"))?;
+    if !(0.0..=1.0).contains(&value) {
+        return Err(format!("{value} is outside 0.0..=1.0"));
+    }
+    Ok(value)
+}
+
+/// clap value parser for amounts in -1.0..=1.0.
+fn parse_signed_unit(input: &str) -> Result<f32, String> {
+    let value: f32 = input
+        .trim()
+        .parse()
+        .map_err(|_| format!("'{input}' is not a number"))?;
+    if !(-1.0..=1.0).contains(&value) {
+        return Err(format!("{value} is outside -1.0..=1.0"));
+    }
+    Ok(value)
+}
+
+/// clap value parser for a non-negative multiplier.
+fn parse_weight(input: &str) -> Result<f32, String> {
+    let value: f32 = input
+        .trim()
+        .parse()
+        .map_err(|_| format!("'{input}' is not a number"))?;
+    if !value.is_finite() || value < 0.0 {
+        return Err(format!("{value} is not a non-negative weight"));
+    }
+    Ok(value)
+}
+
+/// clap value parser for a tonic given with or without an octave (`G`, `Bb`, `F#3`).
+fn parse_pitch_class(input: &str) -> Result<u8, String> {
+    use std::str::FromStr;
+    Note::from_str(input)
+        .or_else(|_| Note::from_str(&format!("{}4", input.trim())))
+        .map(|note| note.as_u8() % 12)
+}
+
+/// Smallest bar count whose generation reaches `target` notes.
+/// The generator walks steps in order, so each extra bar only appends to the previous result
+/// and the count can be binary searched, up to [`MAX_AUTO_BARS`] or the SMF length limit.
+fn bars_for_note_count(cli: &Cli, target: u32) -> Result<u32, Box<dyn Error>> {
+    let bar_ticks = check_song_length(1, cli.ppqn, cli.time_signature)? as u64;
+    let max_bars = MAX_AUTO_BARS.min((MAX_SMF_TICKS / bar_ticks.max(1)) as u32);
+    let mut trial = cli.clone();
+    let mut reaches = |bars: u32| -> Result<bool, Box<dyn Error>> {
+        trial.bars = bars;
+        Ok(generate_sequence(&trial.gen_config())?.notes.len() as u32 >= target)
+    };
+    if max_bars == 0 || !reaches(max_bars)? {
+        return Err(format!("could not reach {target} notes within {max_bars} bars").into());
+    }
+    let (mut low, mut high) = (1, max_bars);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if reaches(mid)? {
+            high = mid;
+        } else {
+            low = mid + 1;
+        }
+    }
+    Ok(high)
+}
+
+/// Multiplies every tick (note starts, ends and the song length) by `factor`.
+/// Notes keep at least one tick of duration after rounding.
+fn time_stretch(seq: &mut MidiSequence, factor: Ratio) -> Result<(), String> {
+    let total = factor.scale(seq.total_ticks)?;
+    for note in &mut seq.notes {
+        let start = factor.scale(note.start_tick)?;
+        note.end_tick = factor.scale(note.end_tick)?.max(start + 1);
+        note.start_tick = start;
+        note.grid_tick = factor.scale(note.grid_tick)?;
+    }
+    for (tick, _) in &mut seq.key_changes {
+        *tick = factor.scale(*tick)?;
+    }
+    seq.total_ticks = total;
+    Ok(())
+}
+
+/// Transposes every note starting at or after each change's bar by the running total
+/// of all changes so far, and records the points for key-signature metas.
+fn apply_key_changes(seq: &mut MidiSequence, changes: &[KeyChange]) {
+    let bar_ticks = seq.bar_ticks();
+    let mut points: Vec<(u32, i8)> = changes
+        .iter()
+        .map(|c| ((c.bar - 1).saturating_mul(bar_ticks), c.semitones))
+        .collect();
+    points.sort_by_key(|(tick, _)| *tick);
+
+    let mut total: i8 = 0;
+    for (_, semis) in &mut points {
+        total = total.saturating_add(*semis);
+        *semis = total;
+    }
+
+    for note in &mut seq.notes {
+        let shift = points
+            .iter()
+            .take_while(|(tick, _)| *tick <= note.start_tick)
+            .last()
+            .map_or(0, |(_, shift)| *shift);
+        note.pitch = (note.pitch as i16 + shift as i16).clamp(0, 127) as u8;
+    }
+    points.retain(|(tick, _)| *tick < seq.total_ticks);
+    seq.key_changes = points;
+}
+
+/// Moves each note's end by up to `amount` of its length (either way), leaving starts alone.
+/// Lengths stay at least one tick and never run past the song end.
+fn humanize_lengths(seq: &mut MidiSequence, amount: f32, config: &GenConfig) {
+    let mut rng = config.rng.sub_rng(config.seed, LENGTH_HUMANIZE_STREAM);
+    for note in seq.notes.iter_mut().filter(|n| config.grooves(n.channel)) {
+        let len = (note.end_tick - note.start_tick) as f32;
+        let offset = (rng.gen_range(-1.0f32..=1.0) * amount * len).round() as i64;
+        note.end_tick = (note.end_tick as i64 + offset).clamp(
+            note.start_tick as i64 + 1,
+            seq.total_ticks.max(note.start_tick + 1) as i64,
+        ) as u32;
+    }
+}
+
+/// Onset offset in ticks at `tick` for a drift of `depth_ticks` peak, `rate` cycles per
+/// bar and starting `phase` (radians).
+fn wobble_offset(tick: u32, bar_ticks: u32, depth_ticks: f64, rate: f64, phase: f64) -> f64 {
+    let bars = tick as f64 / bar_ticks.max(1) as f64;
+    depth_ticks * (std::f64::consts::TAU * rate * bars + phase).sin()
+}
+
+/// Shifts every note (keeping its length) by a slow sine of the song position, as if
+/// the player's clock drifted. The phase comes from the seed.
+fn tempo_wobble(seq: &mut MidiSequence, depth: f32, rate: f32, seed: u64, algo: RngAlgo) {
+    let phase = algo
+        .sub_rng(seed, TEMPO_WOBBLE_STREAM)
+        .gen_range(0.0..std::f64::consts::TAU);
+    let bar_ticks = seq.bar_ticks();
+    let depth_ticks = depth as f64 * seq.ppqn as f64;
+    for note in &mut seq.notes {
+        let len = note.end_tick - note.start_tick;
+        let offset = wobble_offset(note.start_tick, bar_ticks, depth_ticks, rate as f64, phase);
+        let latest = seq.total_ticks.saturating_sub(len);
+        note.start_tick = (note.start_tick as f64 + offset)
+            .round()
+            .clamp(0.0, latest as f64) as u32;
+        note.end_tick = note.start_tick + len;
+    }
+}
+
+/// Adds `per_semitone` velocity for every semitone a note sits above `root` (subtracting
+/// below it). Results stay within 1..=127.
+fn tilt_velocities(seq: &mut MidiSequence, root: u8, per_semitone: f32) {
+    for note in &mut seq.notes {
+        let semitones = note.pitch as f32 - root as f32;
+        let v = note.velocity as f32 + semitones * per_semitone;
+        note.velocity = v.round().clamp(1.0, 127.0) as u8;
+    }
+}
+
+/// Pulls every velocity toward the rounded mean: `ratio` 1.0 leaves them as they are,
+/// 0.0 sets them all to the mean. Results stay within 1..=127.
+fn compress_velocities(seq: &mut MidiSequence, ratio: f32) {
+    if seq.notes.is_empty() {
+        return;
+    }
+    let sum: u32 = seq.notes.iter().map(|n| n.velocity as u32).sum();
+    let mean = (sum as f32 / seq.notes.len() as f32).round();
+    for note in &mut seq.notes {
+        let v = mean + (note.velocity as f32 - mean) * ratio;
+        note.velocity = v.round().clamp(1.0, 127.0) as u8;
+    }
+}
+
+/// Krumhansl-Kessler key profiles, indexed by semitones above the tonic.
+const MAJOR_PROFILE: [f64; 12] = [
+    6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88,
+];
+const MINOR_PROFILE: [f64; 12] = [
+    6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17,
+];
+
+/// Channel 9 is GM percussion: no pitch content, never transposed.
+const DRUM_CHANNEL: u8 = 9;
+
+fn pitch_class_name(pc: u8) -> String {
+    note_to_string(60 + pc % 12)
+        .trim_end_matches(|c: char| c.is_ascii_digit())
+        .to_string()
+}
+
+/// Estimates `(tonic pitch class, minor)` by correlating the duration-weighted
+/// pitch-class histogram against every rotation of the Krumhansl-Kessler profiles.
+fn estimate_key(seq: &MidiSequence) -> (u8, bool) {
+    let mut histogram = [0f64; 12];
+    for note in seq.notes.iter().filter(|n| n.channel != DRUM_CHANNEL) {
+        histogram[(note.pitch % 12) as usize] += (note.end_tick - note.start_tick) as f64;
+    }
+
+    let correlate = |profile: &[f64; 12], tonic: usize| {
+        let rotated: Vec<f64> = (0..12).map(|pc| profile[(pc + 12 - tonic) % 12]).collect();
+        let mean_h = histogram.iter().sum::<f64>() / 12.0;
+        let mean_p = rotated.iter().sum::<f64>() / 12.0;
+        let (mut cov, mut var_h, mut var_p) = (0.0, 0.0, 0.0);
+        for (h, p) in histogram.iter().zip(&rotated) {
+            cov += (h - mean_h) * (p - mean_p);
+            var_h += (h - mean_h).powi(2);
+            var_p += (p - mean_p).powi(2);
+        }
+        if var_h == 0.0 {
+            0.0
+        } else {
+            cov / (var_h * var_p).sqrt()
+        }
+    };
+
+    let mut best = (0u8, false, f64::MIN);
+    for tonic in 0..12 {
+        for (minor, profile) in [(false, &MAJOR_PROFILE), (true, &MINOR_PROFILE)] {
+            let r = correlate(profile, tonic);
+            if r > best.2 {
+                best = (tonic as u8, minor, r);
+            }
+        }
+    }
+    (best.0, best.1)
+}
+
+/// Transposes all pitched notes so the estimated tonic becomes `target_pc`, by the
+/// smallest interval (-5..=+6). Returns the detected key and the shift applied.
+fn transpose_to_key(seq: &mut MidiSequence, target_pc: u8) -> (u8, bool, i16) {
+    let (tonic, minor) = estimate_key(seq);
+    let mut shift = (target_pc as i16 - tonic as i16).rem_euclid(12);
+    if shift > 6 {
+        shift -= 12;
+    }
+    for note in seq.notes.iter_mut().filter(|n| n.channel != DRUM_CHANNEL) {
+        note.pitch = (note.pitch as i16 + shift).clamp(0, 127) as u8;
+    }
+    (tonic, minor, shift)
+}
+
+/// Drops notes starting at or after `ticks` and cuts the rest off at that point.
+fn truncate_to(seq: &mut MidiSequence, ticks: u32) {
+    seq.notes.retain(|n| n.start_tick < ticks);
+    for note in &mut seq.notes {
+        note.end_tick = note.end_tick.min(ticks);
+    }
+    seq.key_changes.retain(|(tick, _)| *tick < ticks);
+    seq.total_ticks = ticks;
+}
+
+/// The combined stretch factor from --time-stretch / --double-time / --half-time.
+fn stretch_factor(cli: &Cli) -> Option<Ratio> {
+    if cli.double_time {
+        Some(Ratio { num: 1, den: 2 })
+    } else if cli.half_time {
+        Some(Ratio { num: 2, den: 1 })
+    } else {
+        cli.time_stretch
+    }
+}
+
+/// One click per beat (the time signature's denominator) across `total_ticks` on its
+/// own track: GM hi wood block on downbeats, low wood block elsewhere. Fully deterministic.
+fn guide_notes(
+    total_ticks: u32,
+    ppqn: u16,
+    time_sig: TimeSig,
+    channel: u8,
+    track: u8,
+) -> Vec<MidiNote> {
+    let beat_ticks = (ppqn as u32 * 4 / time_sig.den as u32).max(1);
+    let click_ticks = (beat_ticks / 4).max(1);
+    (0..total_ticks / beat_ticks)
+        .map(|beat| {
+            let downbeat = beat.is_multiple_of(time_sig.num as u32);
+            let start_tick = beat * beat_ticks;
+            MidiNote {
+                pitch: if downbeat { 76 } else { 77 },
+                start_tick,
+                end_tick: start_tick + click_ticks,
+                velocity: if downbeat { 110 } else { 80 },
+                channel,
+                track,
+                grid_tick: start_tick,
+                velocity_jitter: 0,
+            }
+        })
+        .collect()
+}
+
+const KICK: u8 = 36;
+const SIDE_STICK: u8 = 37;
+const SNARE: u8 = 38;
+const CLOSED_HAT: u8 = 42;
+const RIDE: u8 = 51;
+
+/// The onsets of each drum in `kind` over one bar of `steps_per_bar` sixteenths, as
+/// `(note, hit on step)`. Templates are one 4/4 bar, cut short or repeated for other meters.
+fn drum_groove(kind: DrumGroove, steps_per_bar: u32) -> Vec<(u8, Vec<bool>)> {
+    let lanes: &[(u8, &str)] = match kind {
+        DrumGroove::Rock => &[
+            (KICK, "x.......x.x....."),
+            (SNARE, "....x.......x..."),
+            (CLOSED_HAT, "x.x.x.x.x.x.x.x."),
+        ],
+        DrumGroove::Funk => &[
+            (KICK, "x.........x..x.."),
+            (SNARE, "....x..x.x..x..."),
+            (CLOSED_HAT, "xxxxxxxxxxxxxxxx"),
+        ],
+        DrumGroove::House => &[
+            (KICK, "x...x...x...x..."),
+            (SNARE, "....x.......x..."),
+            (CLOSED_HAT, "..x...x...x...x."),
+        ],
+        DrumGroove::Dnb => &[
+            (KICK, "x.........x....."),
+            (SNARE, "....x......x...."),
+            (CLOSED_HAT, "x.x.x.x.x.x.x.x."),
+        ],
+        DrumGroove::Bossa => &[
+            (KICK, "x..xx..xx..xx..x"),
+            (SIDE_STICK, "x..x..x...x..x.."),
+            (RIDE, "x.x.x.x.x.x.x.x."),
+        ],
+    };
+    lanes
+        .iter()
+        .map(|&(note, pattern)| {
+            let pattern = pattern.as_bytes();
+            let onsets = (0..steps_per_bar as usize)
+                .map(|step| pattern[step % pattern.len()] == b'x')
+                .collect();
+            (note, onsets)
+        })
+        .collect()
+}
+
+/// A simple seeded beat on [`DRUM_CHANNEL`] across `total_ticks`: a kick on every beat
+/// (always on the downbeat), snare on the backbeats and closed hats on the eighths, each
+/// hit left out now and then and its velocity varied. With `groove` the hits follow
+/// [`drum_groove`] instead; only its hats and ride are left out now and then.
+fn drum_notes(
+    total_ticks: u32,
+    ppqn: u16,
+    time_sig: TimeSig,
+    seed: u64,
+    algo: RngAlgo,
+    track: u8,
+    groove: Option<DrumGroove>,
+) -> Vec<MidiNote> {
+    let mut rng = algo.sub_rng(seed, DRUM_STREAM);
+    let beat_ticks = (ppqn as u32 * 4 / time_sig.den as u32).max(1);
+    let eighth_ticks = (ppqn as u32 / 2).clamp(1, beat_ticks);
+    let hit_ticks = (ppqn as u32 / 4).max(1);
+    let bar_ticks = time_sig.bar_ticks(ppqn).max(1);
+
+    let mut notes = Vec::new();
+    let mut hit = |pitch: u8, start_tick: u32, velocity: u8| {
+        notes.push(MidiNote {
+            pitch,
+            start_tick,
+            end_tick: (start_tick + hit_ticks).min(total_ticks),
+            velocity,
+            channel: DRUM_CHANNEL,
+            track,
+            grid_tick: start_tick,
+            velocity_jitter: 0,
+        });
+    };
+    if let Some(kind) = groove {
+        let steps_per_bar = time_sig.steps_per_bar();
+        let lanes = drum_groove(kind, steps_per_bar);
+        for start_tick in (0..total_ticks).step_by(hit_ticks as usize) {
+            let step = start_tick / hit_ticks;
+            for (note, onsets) in &lanes {
+                if !onsets[(step % steps_per_bar) as usize] {
+                    continue;
+                }
+                let cymbal = matches!(*note, CLOSED_HAT | RIDE);
+                if cymbal && rng.gen_range(0..100u32) >= 90 {
+                    continue;
+                }
+                let velocity = match *note {
+                    KICK => 100..=115,
+                    SNARE => 95..=110,
+                    SIDE_STICK => 75..=90,
+                    _ if step.is_multiple_of(2) => 70..=85,
+                    _ => 55..=70,
+                };
+                hit(*note, start_tick, rng.gen_range(velocity));
+            }
+        }
+        return notes;
+    }
+    for start_tick in (0..total_ticks).step_by(eighth_ticks as usize) {
+        if start_tick.is_multiple_of(beat_ticks) {
+            let beat = start_tick % bar_ticks / beat_ticks;
+            if beat == 0 || rng.gen_range(0..100u32) < 80 {
+                hit(KICK, start_tick, rng.gen_range(100..=115));
+            }
+            if beat % 2 == 1 && rng.gen_range(0..100u32) < 90 {
+                hit(SNARE, start_tick, rng.gen_range(95..=110));
+            }
+        }
+        if rng.gen_range(0..100u32) < 85 {
+            let on_beat = start_tick.is_multiple_of(beat_ticks);
+            let velocity = if on_beat {
+                rng.gen_range(70..=85)
+            } else {
+                rng.gen_range(55..=70)
+            };
+            hit(CLOSED_HAT, start_tick, velocity);
+        }
+    }
+    notes
+}
+
+/// Moves each drum hit by its limb's lean under `feel` plus seeded jitter, keeping its
+/// length; onsets stay inside the song and `grid_tick` keeps the unplayed position.
+fn apply_drum_feel(
+    notes: &mut [MidiNote],
+    feel: DrumFeel,
+    ppqn: u16,
+    total_ticks: u32,
+    seed: u64,
+    algo: RngAlgo,
+) {
+    let mut rng = algo.sub_rng(seed, DRUM_FEEL_STREAM);
+    let step_ticks = (ppqn / 4) as i64;
+    let last_tick = total_ticks.saturating_sub(1) as i64;
+    for note in notes.iter_mut() {
+        let (bias, jitter) = feel.limb(note.pitch);
+        let jitter = step_ticks * jitter / 100;
+        let offset = step_ticks * bias / 100 + rng.gen_range(-jitter..=jitter);
+        let len = note.end_tick - note.start_tick;
+        let start = (note.start_tick as i64 + offset).clamp(0, last_tick) as u32;
+        note.start_tick = start;
+        note.end_tick = (start + len).min(total_ticks).max(start + 1);
+    }
+    notes.sort_by_key(|n| (n.start_tick, n.pitch));
+}
+
+/// Track of the first melodic line: 0, or 1 when track 0 is the --voices conductor.
+fn melody_track(seq: &MidiSequence) -> u8 {
+    seq.notes.iter().map(|n| n.track).min().unwrap_or(0)
+}
+
+const DRONE_VELOCITY: u8 = 40;
+
+/// Sustained tonic (and optional fifth) an octave below `root` from 0 to `total_ticks`,
+/// re-struck at every key change so it follows the modulation. Fully deterministic.
+fn drone_notes(seq: &MidiSequence, root: u8, fifth: bool, channel: u8) -> Vec<MidiNote> {
+    let mut segments: Vec<(u32, i8)> = vec![(0, 0)];
+    for &(tick, shift) in &seq.key_changes {
+        match segments.last_mut() {
+            Some(last) if last.0 == tick => last.1 = shift,
+            _ if tick < seq.total_ticks => segments.push((tick, shift)),
+            _ => {}
+        }
+    }
+
+    let tonic = root.saturating_sub(12);
+    let intervals: &[i16] = if fifth { &[0, 7] } else { &[0] };
+    let mut notes = Vec::new();
+    for (i, &(start_tick, shift)) in segments.iter().enumerate() {
+        let end_tick = segments.get(i + 1).map_or(seq.total_ticks, |s| s.0);
+        for interval in intervals {
+            notes.push(MidiNote {
+                pitch: (tonic as i16 + shift as i16 + interval).clamp(0, 127) as u8,
+                start_tick,
+                end_tick: end_tick.max(start_tick + 1),
+                velocity: DRONE_VELOCITY,
+                channel,
+                track: melody_track(seq),
+                grid_tick: start_tick,
+                velocity_jitter: 0,
+            });
+        }
+    }
+    notes
+}
+
+const ROMAN_VELOCITY: u8 = 60;
+
+/// `chords` held a bar each on `channel` from the start of the song, cycling, each moved
+/// by the key change in force at its bar.
+fn roman_notes(seq: &MidiSequence, chords: &[Chord], channel: u8) -> Vec<MidiNote> {
+    let bar_ticks = seq.bar_ticks().max(1);
+    let mut notes = Vec::new();
+    for (bar, start_tick) in (0..seq.total_ticks).step_by(bar_ticks as usize).enumerate() {
+        let shift = seq
+            .key_changes
+            .iter()
+            .take_while(|&&(tick, _)| tick <= start_tick)
+            .last()
+            .map_or(0, |&(_, shift)| shift);
+        let end_tick = (start_tick + bar_ticks).min(seq.total_ticks);
+        for &pitch in &chords[bar % chords.len()].0 {
+            notes.push(MidiNote {
+                pitch: (pitch as i16 + shift as i16).clamp(0, 127) as u8,
+                start_tick,
+                end_tick,
+                velocity: ROMAN_VELOCITY,
+                channel,
+                track: melody_track(seq),
+                grid_tick: start_tick,
+                velocity_jitter: 0,
+            });
+        }
+    }
+    notes
+}
+
+/// Gives every melody-track note its own MPE member channel (1..=`members`), rotating
+/// round-robin and reusing a channel only once its previous note has ended. When all
+/// are busy the channel released longest ago is taken.
+fn assign_mpe_channels(seq: &mut MidiSequence, members: u8) {
+    let mut busy_until = vec![0u32; members as usize];
+    let mut next = 0usize;
+    let mut order: Vec<usize> = (0..seq.notes.len())
+        .filter(|&i| seq.notes[i].track == 0)
+        .collect();
+    order.sort_by_key(|&i| seq.notes[i].start_tick);
+
+    for i in order {
+        let start = seq.notes[i].start_tick;
+        let slot = (0..busy_until.len())
+            .map(|offset| (next + offset) % busy_until.len())
+            .find(|&slot| busy_until[slot] <= start)
+            .unwrap_or_else(|| {
+                (0..busy_until.len())
+                    .min_by_key(|&slot| busy_until[slot])
+                    .unwrap_or(0)
+            });
+        busy_until[slot] = seq.notes[i].end_tick;
+        next = (slot + 1) % busy_until.len();
+        seq.notes[i].channel = slot as u8 + 1;
+    }
+}
+
+/// Resolves any auto-length options into `cli.bars`, generates (or loads --input),
+/// then runs the post passes.
+fn generate_from_cli(cli: &mut Cli) -> Result<MidiSequence, Box<dyn Error>> {
+    let loads_files = cli.input.is_some() || !cli.merge.is_empty();
+    if let (Some(seconds), false) = (cli.length_seconds, loads_files) {
+        cli.bars = bars_for_seconds(seconds, cli.bpm, cli.time_signature);
+    }
+    if let (Some(target), false) = (cli.bars_from_notes, loads_files) {
+        cli.bars = bars_for_note_count(cli, target)?;
+    }
+    let mut seq = match &cli.input {
+        Some(path) => load_sequence(path)?,
+        None if !cli.merge.is_empty() => merge_sequences(&cli.merge)?,
+        // Legato waits for the timing passes below so it trims against the final onsets.
+        None => generate_sequence(&GenConfig {
+            legato: false,
+            ..cli.gen_config()
+        })?,
+    };
+    if let Some(target_pc) = cli.transpose_to_key {
+        let (tonic, minor, shift) = transpose_to_key(&mut seq, target_pc);
+        eprintln!(
+            "Detected {} {}, transposed by {:+} to {}",
+            pitch_class_name(tonic),
+            if minor { "minor" } else { "major" },
+            shift,
+            pitch_class_name(target_pc)
+        );
+    }
+    if !cli.key_change.is_empty() {
+        apply_key_changes(&mut seq, &cli.key_change);
+    }
+    if let Some(amount) = cli.length_humanize {
+        humanize_lengths(&mut seq, amount, &cli.gen_config());
+    }
+    if let [depth, rate] = cli.tempo_wobble[..] {
+        let valid = (0.0..=1.0).contains(&depth) && rate > 0.0 && rate <= 16.0;
+        if !valid {
+            return Err("--tempo-wobble takes DEPTH in 0..1 beats and RATE in (0, 16]".into());
+        }
+        tempo_wobble(&mut seq, depth, rate, cli.seed, cli.rng);
+    }
+    if let Some(per_semitone) = cli.velocity_pitch_tilt {
+        tilt_velocities(&mut seq, cli.root.as_u8(), per_semitone);
+    }
+    if let Some(ratio) = cli.compress_velocity {
+        compress_velocities(&mut seq, ratio);
+    }
+    if let Some(factor) = stretch_factor(cli) {
+        let original_len = seq.total_ticks;
+        time_stretch(&mut seq, factor)?;
+        if cli.keep_length {
+            truncate_to(&mut seq, original_len);
+        }
+    }
+    if cli.legato && !loads_files {
+        apply_legato(&mut seq, cli.legato_max * (cli.ppqn as u32 / 4));
+    }
+    seq.notes.sort_by_key(|n| (n.start_tick, n.track));
+    if cli.drums {
+        let track = seq.notes.iter().map(|n| n.track).max().map_or(1, |t| t + 1);
+        let mut drums = drum_notes(
+            seq.total_ticks,
+            seq.ppqn,
+            seq.time_signature,
+            cli.seed,
+            cli.rng,
+            track,
+            cli.drum_groove,
+        );
+        let config = cli.gen_config();
+        swing_layer(&mut drums, &config, seq.ppqn, seq.total_ticks);
+        if let (Some(feel), true) = (cli.drum_feel, config.grooves(DRUM_CHANNEL)) {
+            apply_drum_feel(
+                &mut drums,
+                feel,
+                seq.ppqn,
+                seq.total_ticks,
+                cli.seed,
+                cli.rng,
+            );
+        }
+        humanize_layer(&mut drums, &config, seq.ppqn, seq.total_ticks);
+        seq.notes.extend(drums);
+    }
+    if cli.guide_track {
+        let track = seq.notes.iter().map(|n| n.track).max().map_or(1, |t| t + 1);
+        let guide = guide_notes(
+            seq.total_ticks,
+            seq.ppqn,
+            seq.time_signature,
+            cli.guide_channel,
+            track,
+        );
+        seq.notes.extend(guide);
+    }
+    if cli.drone {
+        let channel = cli.drone_channel.unwrap_or(cli.channel);
+        let drone = drone_notes(&seq, cli.root.as_u8(), cli.drone_fifth, channel);
+        seq.notes.extend(drone);
+    }
+    if let Some(numerals) = &cli.roman {
+        let scale = resolve_scale(&cli.scale, &cli.user_scales)?;
+        let chords = numerals
+            .split_whitespace()
+            .map(|numeral| parse_roman(numeral, cli.root, &scale))
+            .collect::<Result<Vec<_>, _>>()?;
+        if chords.is_empty() {
+            return Err("--roman needs at least one numeral, e.g. \"I vi IV V\"".into());
+        }
+        let chords = roman_notes(&seq, &chords, cli.channel);
+        seq.notes.extend(chords);
+    }
+    if cli.mpe {
+        assign_mpe_channels(&mut seq, cli.mpe_channels);
+    }
+    for note in &mut seq.notes {
+        note.end_tick = note.end_tick.max(note.start_tick + 1);
+    }
+    Ok(seq)
+}
+
+/// Program name at the start of every embedded command, used to find it again.
+const EMBED_PREFIX: &str = "midi-seed-gen";
+
+/// Wraps values containing whitespace or quotes in double quotes (escaping `"` and `\`).
+fn quote_arg(value: &str) -> String {
+    if value.is_empty() || value.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\') {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Every generation setting as a normalized command line: defaults spelled out,
+/// auto-length resolved into `--bars`, stretch shortcuts folded into `--time-stretch`.
+/// Output, GUI and batch options are left out since they don't affect the file.
+fn normalized_command(cli: &Cli) -> String {
+    let mut args = vec![
+        EMBED_PREFIX.to_string(),
+        format!("--seed={}", cli.seed),
+        format!("--bpm={}", cli.bpm),
+        format!("--bars={}", cli.bars),
+        format!("--ppqn={}", cli.ppqn),
+        format!("--time-signature={}", cli.time_signature),
+        format!("--root={}", note_to_string(cli.root.as_u8())),
+        format!("--scale={}", quote_arg(&cli.scale)),
+        format!("--channel={}", cli.channel),
+        format!("--program={}", cli.program),
+        format!("--density={}", cli.density),
+    ];
+    if cli.rng != RngAlgo::Chacha8 {
+        if let Some(algo) = cli.rng.to_possible_value() {
+            args.push(format!("--rng={}", algo.get_name()));
+        }
+    }
+    if let Some(note) = cli.min_note {
+        args.push(format!("--min-note={}", note_to_string(note.0)));
+    }
+    if let Some(note) = cli.max_note {
+        args.push(format!("--max-note={}", note_to_string(note.0)));
+    }
+    if cli.range_policy != RangePolicy::Fold {
+        if let Some(policy) = cli.range_policy.to_possible_value() {
+            args.push(format!("--range-policy={}", policy.get_name()));
+        }
+    }
+    if !cli.onset_weights.is_empty() {
+        let weights: Vec<String> = cli.onset_weights.iter().map(|w| w.to_string()).collect();
+        args.push(format!("--onset-weights={}", weights.join(",")));
+    }
+    if let Some(steps) = cli.fixed_length {
+        args.push(format!("--fixed-length={steps}"));
+    }
+    if !cli.durations.is_empty() {
+        let lengths: Vec<String> = cli.durations.iter().map(|d| d.to_string()).collect();
+        args.push(format!("--durations={}", lengths.join(",")));
+    }
+    if let Some(prob) = cli.empty_bar_prob {
+        args.push(format!("--empty-bar-prob={prob}"));
+    }
+    if let Some(path) = &cli.scales_file {
+        args.push(format!("--scales-file={}", quote_arg(path)));
+    }
+    if let Some(path) = &cli.corpus {
+        args.push(format!("--corpus={}", quote_arg(path)));
+    }
+    if let Some(path) = &cli.input {
+        args.push(format!("--input={}", quote_arg(path)));
+    }
+    for path in &cli.merge {
+        args.push(format!("--merge={}", quote_arg(path)));
+    }
+    for controller in &cli.cc {
+        args.push(format!("--cc={controller}"));
+    }
+    if !cli.cc.is_empty() {
+        args.push(format!("--cc-resolution={}", cli.cc_resolution));
+    }
+    if let Some(pc) = cli.transpose_to_key {
+        args.push(format!("--transpose-to-key={}", pitch_class_name(pc)));
+    }
+    if let Some(factor) = stretch_factor(cli) {
+        args.push(format!("--time-stretch={factor}"));
+        if cli.keep_length {
+            args.push("--keep-length".into());
+        }
+    }
+    if cli.drums {
+        args.push("--drums".into());
+    }
+    if let Some(groove) = cli.drum_groove.and_then(|g| g.to_possible_value()) {
+        args.push(format!("--drum-groove={}", groove.get_name()));
+    }
+    if let Some(feel) = cli.drum_feel.and_then(|f| f.to_possible_value()) {
+        args.push(format!("--drum-feel={}", feel.get_name()));
+    }
+    if cli.guide_track {
+        args.push("--guide-track".into());
+        args.push(format!("--guide-channel={}", cli.guide_channel));
+    }
+    if cli.drone {
+        args.push("--drone".into());
+    }
+    if cli.drone_fifth {
+        args.push("--drone-fifth".into());
+    }
+    if let Some(channel) = cli.drone_channel {
+        args.push(format!("--drone-channel={channel}"));
+    }
+    if let Some(numerals) = &cli.roman {
+        args.push(format!("--roman={}", quote_arg(numerals)));
+    }
+    if cli.sustain_from_chords {
+        args.push("--sustain-from-chords".into());
+    }
+    if !cli.pcset.is_empty() {
+        let pcs: Vec<String> = cli.pcset.iter().map(|pc| pc.to_string()).collect();
+        args.push(format!("--pcset={}", pcs.join(",")));
+    }
+    if !cli.avoid_pcs.is_empty() {
+        let pcs: Vec<String> = cli.avoid_pcs.iter().map(|pc| pc.to_string()).collect();
+        args.push(format!("--avoid-pcs={}", pcs.join(",")));
+    }
+    for change in &cli.key_change {
+        args.push(format!(
+            "--key-change={}:{:+}",
+            change.bar, change.semitones
+        ));
+    }
+    if let Some(amount) = cli.length_humanize {
+        args.push(format!("--length-humanize={amount}"));
+    }
+    if let Some(penalty) = cli.repeat_penalty {
+        args.push(format!("--repeat-penalty={penalty}"));
+    }
+    if let Some(amount) = cli.humanize {
+        args.push(format!("--humanize={amount}"));
+    }
+    if let Some(seed) = cli.timing_humanize_seed {
+        args.push(format!("--timing-humanize-seed={seed}"));
+    }
+    if let Some(seed) = cli.velocity_humanize_seed {
+        args.push(format!("--velocity-humanize-seed={seed}"));
+    }
+    if cli.humanize_correlation != 0.0 {
+        args.push(format!(
+            "--humanize-correlation={}",
+            cli.humanize_correlation
+        ));
+    }
+    if cli.humanize_dist != HumanizeDist::Uniform {
+        if let Some(dist) = cli.humanize_dist.to_possible_value() {
+            args.push(format!("--humanize-dist={}", dist.get_name()));
+        }
+    }
+    if let Some(ticks) = cli.humanize_max_ticks {
+        args.push(format!("--humanize-max-ticks={ticks}"));
+    }
+    if cli.preserve_order {
+        args.push("--preserve-order".into());
+    }
+    for entry in &cli.humanize_channel {
+        args.push(format!(
+            "--humanize-channel={}:{}",
+            entry.channel, entry.amount
+        ));
+    }
+    if cli.swing > 0 {
+        args.push(format!("--swing={}", cli.swing));
+    }
+    for channel in &cli.groove_scope {
+        args.push(format!("--groove-scope={channel}"));
+    }
+    if let [depth, rate] = cli.tempo_wobble[..] {
+        args.push(format!("--tempo-wobble {depth} {rate}"));
+    }
+    if let Some(per_semitone) = cli.velocity_pitch_tilt {
+        args.push(format!("--velocity-pitch-tilt={per_semitone}"));
+    }
+    if let Some(ratio) = cli.compress_velocity {
+        args.push(format!("--compress-velocity={ratio}"));
+    }
+    if let Some(prob) = cli.ratchet_prob {
+        args.push(format!("--ratchet-prob={prob}"));
+        args.push(format!("--ratchet-count={}", cli.ratchet_count));
+    }
+    if cli.meter_accents {
+        args.push("--meter-accents".into());
+    }
+    if cli.chords {
+        args.push("--chords".into());
+    }
+    if cli.chord_extensions != ChordExtensions::Triad {
+        if let Some(extensions) = cli.chord_extensions.to_possible_value() {
+            args.push(format!("--chord-extensions={}", extensions.get_name()));
+        }
+    }
+    if cli.reseed_per_bar {
+        args.push("--reseed-per-bar".into());
+    }
+    if let Some(accent) = &cli.degree_accent {
+        args.push(format!("--degree-accent={accent}"));
+    }
+    let default_velocity = (VelocityCurve::Linear, 55, 95);
+    if (cli.velocity_curve, cli.velocity_min, cli.velocity_max) != default_velocity {
+        if let Some(curve) = cli.velocity_curve.to_possible_value() {
+            args.push(format!("--velocity-curve={}", curve.get_name()));
+        }
+        args.push(format!("--velocity-min={}", cli.velocity_min));
+        args.push(format!("--velocity-max={}", cli.velocity_max));
+    }
+    if cli.safety_note_off {
+        args.push("--safety-note-off".into());
+    }
+    if cli.glide {
+        args.push("--glide".into());
+        args.push(format!("--bend-range={}", cli.bend_range));
+    }
+    if cli.legato {
+        args.push("--legato".into());
+        args.push(format!("--legato-max={}", cli.legato_max));
+    }
+    if cli.mpe {
+        args.push("--mpe".into());
+        args.push(format!("--mpe-channels={}", cli.mpe_channels));
+    }
+    if cli.voices > 1 {
+        args.push(format!("--voices={}", cli.voices));
+    }
+    if let Some(arp) = cli.arp.and_then(|a| a.to_possible_value()) {
+        args.push(format!("--arp={}", arp.get_name()));
+    }
+    if let Some(form) = &cli.repeat {
+        args.push(format!("--repeat={form}"));
+        args.push(format!("--section-bars={}", cli.section_bars));
+    }
+    for shift in &cli.octave_shift_sections {
+        args.push(format!("--octave-shift-sections={shift}"));
+    }
+    if let Some(exercise) = cli.exercise.and_then(|e| e.to_possible_value()) {
+        args.push(format!("--exercise={}", exercise.get_name()));
+        args.push(format!("--exercise-octaves={}", cli.exercise_octaves));
+    }
+    if let Some(title) = &cli.title {
+        args.push(format!("--title={}", quote_arg(title)));
+    }
+    if let Some(composer) = &cli.composer {
+        args.push(format!("--composer={}", quote_arg(composer)));
+    }
+    if cli.embed_command {
+        args.push("--embed-command".into());
+    }
+    args.join(" ")
+}
+
+/// Splits a normalized command back into arguments, honouring `quote_arg`'s quoting.
+fn split_command(command: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_arg = false;
+    let mut quoted = false;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_arg = true;
+            }
+            '\\' if quoted => current.extend(chars.next()),
+            c if c.is_whitespace() && !quoted => {
+                if in_arg {
+                    args.push(std::mem::take(&mut current));
+                    in_arg = false;
+                }
+            }
+            c => {
+                current.push(c);
+                in_arg = true;
+            }
+        }
+    }
+    if quoted {
+        return Err(format!("unterminated quote in embedded command: {command}"));
+    }
+    if in_arg {
+        args.push(current);
+    }
+    Ok(args)
+}
+
+/// The `--embed-command` text of a file, if it has one. Only the sequencer-specific meta
+/// is read, so a `--composer` or other text event that happens to start with the
+/// program name can't stand in for it.
+fn embedded_command(bytes: &[u8]) -> Result<Option<String>, Box<dyn Error>> {
+    let smf = Smf::parse(bytes)?;
+    let command = smf
+        .tracks
+        .iter()
+        .flatten()
+        .find_map(|event| match event.kind {
+            TrackEventKind::Meta(MetaMessage::SequencerSpecific(text)) => std::str::from_utf8(text)
+                .ok()
+                .filter(|t| t.starts_with(EMBED_PREFIX)),
+            _ => None,
+        });
+    Ok(command.map(str::to_string))
+}
+
+/// Regenerates `path` from its embedded command and checks the result is byte-identical.
+/// With `out`, the regenerated file is also written there.
+fn reproduce(path: &str, out: Option<&str>) -> Result<(), Box<dyn Error>> {
+    let original = fs::read(path)?;
+    let command = embedded_command(&original)?
+        .ok_or_else(|| format!("{path} has no embedded command (write it with --embed-command)"))?;
+
+    let mut cli = Cli::try_parse_from(split_command(&command)?)?;
+    load_cli_files(&mut cli)?;
+    let seq = generate_from_cli(&mut cli)?;
+    let regenerated = encode_sequence(&seq, &cli.gen_config())?;
+
+    if let Some(out_path) = out {
+        write_file(out_path, &regenerated)?;
+        eprintln!("Wrote {}", out_path);
+    }
+    if regenerated != original {
+        return Err(format!("{path} does not match its embedded command: {command}").into());
+    }
+    eprintln!("{path} reproduces exactly from: {command}");
+    Ok(())
+}
+
+/// Loads each file onto its own track (flattening any tracks it had), resampling to
+/// the first file's PPQN and keeping its tempo.
+fn merge_sequences(paths: &[String]) -> Result<MidiSequence, Box<dyn Error>> {
+    if paths.len() > 16 {
+        return Err("--merge takes at most 16 files".into());
+    }
+    let mut merged: Option<MidiSequence> = None;
+    for (index, path) in paths.iter().enumerate() {
+        let mut seq = load_sequence(path)?;
+        for note in &mut seq.notes {
+            note.track = index as u8;
+        }
+        let Some(target) = merged.as_mut() else {
+            merged = Some(seq);
+            continue;
+        };
+        if seq.ppqn != target.ppqn {
+            let factor = Ratio {
+                num: target.ppqn as u64,
+                den: seq.ppqn as u64,
+            };
+            time_stretch(&mut seq, factor).map_err(|e| format!("{path}: {e}"))?;
+        }
+        target.total_ticks = target.total_ticks.max(seq.total_ticks);
+        target.notes.extend(seq.notes);
+    }
+    let mut merged = merged.ok_or("--merge needs at least one file")?;
+    merged
+        .notes
+        .sort_by_key(|n| (n.start_tick, n.track, n.channel, n.pitch));
+    Ok(merged)
+}
+
+/// Reads a metrical-time .mid into a `MidiSequence`, pairing NoteOn/NoteOff per
+/// track, channel and key (first on, first off). Notes keep their source track and
+/// channel; the first tempo event sets `bpm`. Notes left hanging end at the last event.
+fn load_sequence(path: &str) -> Result<MidiSequence, Box<dyn Error>> {
+    let bytes = fs::read(path)?;
+    let smf = Smf::parse(&bytes).map_err(|e| format!("{path}: {e}"))?;
+    let ppqn = match smf.header.timing {
+        Timing::Metrical(ppqn) => ppqn.as_int(),
+        Timing::Timecode(..) => {
+            return Err(format!("{path}: SMPTE timecode files are not supported").into())
+        }
+    };
+
+    let mut notes = Vec::new();
+    let mut us_per_qn: Option<u32> = None;
+    let mut time_signature: Option<TimeSig> = None;
+    let mut total_ticks = 0u32;
+    for (track_index, track) in smf.tracks.iter().enumerate() {
+        let track_index =
+            u8::try_from(track_index).map_err(|_| format!("{path}: too many tracks"))?;
+        let mut tick = 0u32;
+        let mut open: BTreeMap<(u8, u8), Vec<(u32, u8)>> = BTreeMap::new();
+        for event in track {
+            tick = tick.saturating_add(event.delta.as_int());
+            match event.kind {
+                TrackEventKind::Meta(MetaMessage::Tempo(tempo)) => {
+                    us_per_qn.get_or_insert(tempo.as_int());
+                }
+                TrackEventKind::Meta(MetaMessage::TimeSignature(num, den_pow, _, _))
+                    if time_signature.is_none() =>
+                {
+                    time_signature = 1u8
+                        .checked_shl(den_pow as u32)
+                        .and_then(|den| TimeSig::new(num, den).ok());
+                }
+                TrackEventKind::Midi { channel, message } => {
+                    let channel = channel.as_int();
+                    match message {
+                        MidiMessage::NoteOn { key, vel } if vel.as_int() > 0 => {
+                            open.entry((channel, key.as_int()))
+                                .or_default()
+                                .push((tick, vel.as_int()));
+                        }
+                        MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
+                            let pending = open.entry((channel, key.as_int())).or_default();
+                            if !pending.is_empty() {
+                                let (start_tick, velocity) = pending.remove(0);
+                                notes.push(MidiNote {
+                                    pitch: key.as_int(),
+                                    start_tick,
+                                    end_tick: tick.max(start_tick + 1),
+                                    velocity,
+                                    channel,
+                                    track: track_index,
+                                    grid_tick: start_tick,
+                                    velocity_jitter: 0,
+                                });
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+        for ((channel, pitch), pending) in open {
+            for (start_tick, velocity) in pending {
+                notes.push(MidiNote {
+                    pitch,
+                    start_tick,
+                    end_tick: tick.max(start_tick + 1),
+                    velocity,
+                    channel,
+                    track: track_index,
+                    grid_tick: start_tick,
+                    velocity_jitter: 0,
+                });
+            }
+        }
+        total_ticks = total_ticks.max(tick);
+    }
+    notes.sort_by_key(|n| (n.start_tick, n.track, n.channel, n.pitch));
+
+    let bpm = 60_000_000 / us_per_qn.unwrap_or(500_000).max(1);
+    Ok(MidiSequence {
+        total_ticks: notes
+            .iter()
+            .map(|n| n.end_tick)
+            .max()
+            .unwrap_or(0)
+            .max(total_ticks),
+        notes,
+        bpm,
+        ppqn,
+        time_signature: time_signature.unwrap_or(TimeSig::COMMON),
+        key_changes: Vec::new(),
+    })
+}
+
+/// Ticks a reloaded note may differ by (zero-length notes come back one tick long).
+const ROUND_TRIP_TOLERANCE: u32 = 1;
+
+/// Per (track, channel, pitch): sorted (start, velocity) onsets and sorted end ticks.
+/// Ends are compared as a set because overlapping notes on one key pair up first in,
+/// first out when read back.
+type NoteKeys = BTreeMap<(u8, u8, u8), (Vec<(u32, u8)>, Vec<u32>)>;
+
+fn note_keys(seq: &MidiSequence) -> NoteKeys {
+    let mut keys = NoteKeys::new();
+    for note in &seq.notes {
+        let entry = keys
+            .entry((note.track, note.channel, note.pitch))
+            .or_default();
+        entry.0.push((note.start_tick, note.velocity));
+        entry.1.push(note.end_tick);
+    }
+    for (starts, ends) in keys.values_mut() {
+        starts.sort_unstable();
+        ends.sort_unstable();
+    }
+    keys
+}
+
+/// Reloads `path` and checks it holds the notes of `seq`: same pitches, channels,
+/// tracks and velocities, with start and end ticks within `ROUND_TRIP_TOLERANCE`.
+fn round_trip_check(seq: &MidiSequence, path: &str) -> Result<(), Box<dyn Error>> {
+    let reloaded = load_sequence(path)?;
+    let (expected, found) = (note_keys(seq), note_keys(&reloaded));
+    let close = |a: u32, b: u32| a.abs_diff(b) <= ROUND_TRIP_TOLERANCE;
+    for key in expected.keys().chain(found.keys()) {
+        let (track, channel, pitch) = *key;
+        let matches = match (expected.get(key), found.get(key)) {
+            (Some((starts_a, ends_a)), Some((starts_b, ends_b))) => {
+                starts_a.len() == starts_b.len()
+                    && ends_a.len() == ends_b.len()
+                    && starts_a
+                        .iter()
+                        .zip(starts_b)
+                        .all(|(a, b)| close(a.0, b.0) && a.1 == b.1)
+                    && ends_a.iter().zip(ends_b).all(|(&a, &b)| close(a, b))
+            }
+            _ => false,
+        };
+        if !matches {
+            return Err(format!(
+                "round-trip check failed for {path}: notes of pitch {} on track {track}, channel {channel} differ after reloading",
+                note_to_string(pitch)
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+// ============================================================================
+// LILYPOND EXPORT
+// ============================================================================
+
+/// LilyPond note names (sharps) and their letter index, C = 0 .. B = 6.
+const LY_NAMES: [(&str, i32); 12] = [
+    ("c", 0),
+    ("cis", 0),
+    ("d", 1),
+    ("dis", 1),
+    ("e", 2),
+    ("f", 3),
+    ("fis", 3),
+    ("g", 4),
+    ("gis", 4),
+    ("a", 5),
+    ("ais", 5),
+    ("b", 6),
+];
+
+/// Splits a length in sixteenths into LilyPond durations, longest first.
+fn ly_durations(mut sixteenths: u32) -> Vec<&'static str> {
+    const VALUES: [(u32, &str); 8] = [
+        (16, "1"),
+        (12, "2."),
+        (8, "2"),
+        (6, "4."),
+        (4, "4"),
+        (3, "8."),
+        (2, "8"),
+        (1, "16"),
+    ];
+    let mut out = Vec::new();
+    for (len, name) in VALUES {
+        while sixteenths >= len {
+            out.push(name);
+            sixteenths -= len;
+        }
+    }
+    out
+}
+
+/// Pitch name with `\relative` octave marks, given the previous note's diatonic
+/// position (`octave * 7 + letter`); returns the name and this note's position.
+fn ly_relative_pitch(pitch: u8, previous: i32) -> (String, i32) {
+    let (name, letter) = LY_NAMES[(pitch % 12) as usize];
+    let position = (pitch as i32 / 12 - 1) * 7 + letter;
+    let mut step = (letter - previous.rem_euclid(7)).rem_euclid(7);
+    if step > 3 {
+        step -= 7;
+    }
+    let marks = (position - (previous + step)) / 7;
+    let suffix = if marks > 0 { "'" } else { "," }.repeat(marks.unsigned_abs() as usize);
+    (format!("{name}{suffix}"), position)
+}
+
+/// Converts the melody track to LilyPond source on a sixteenth grid: the highest note
+/// at each onset, held until the next onset, with rests for gaps and ties across bar
+/// lines.
+fn to_lilypond(seq: &MidiSequence, root: u8, scale: &[i8], time_sig: TimeSig) -> String {
+    let step_ticks = (seq.ppqn as f64 / 4.0).max(1.0);
+    let to_step = |tick: u32| (tick as f64 / step_ticks).round() as u32;
+    let bar_steps = time_sig.steps_per_bar().max(1);
+    let total_steps = to_step(seq.total_ticks).max(1);
+
+    // Highest pitch per quantized onset, ending at the next onset at the latest.
+    let mut onsets: BTreeMap<u32, (u8, u32)> = BTreeMap::new();
+    let track = melody_track(seq);
+    for note in seq.notes.iter().filter(|n| n.track == track) {
+        let start = to_step(note.start_tick).min(total_steps - 1);
+        let end = to_step(note.end_tick).clamp(start + 1, total_steps);
+        let slot = onsets.entry(start).or_insert((note.pitch, end));
+        if note.pitch > slot.0 {
+            *slot = (note.pitch, end);
+        }
+    }
+    let starts: Vec<u32> = onsets.keys().copied().collect();
+
+    let mut events: Vec<(Option<u8>, u32, u32)> = Vec::new();
+    let mut cursor = 0;
+    for (i, &start) in starts.iter().enumerate() {
+        let (pitch, end) = onsets[&start];
+        let end = starts.get(i + 1).map_or(end, |&next| end.min(next));
+        if start > cursor {
+            events.push((None, cursor, start));
+        }
+        events.push((Some(pitch), start, end));
+        cursor = end;
+    }
+    if cursor < total_steps {
+        events.push((None, cursor, total_steps));
+    }
+
+    let mode = if scale_is_minor(scale) {
+        "minor"
+    } else {
+        "major"
+    };
+    let mut out = String::from("\\version \"2.24.0\"\n\n");
+    out.push_str("\\relative c' {\n");
+    out.push_str(&format!(
+        "  \\key {} \\{mode}\n",
+        LY_NAMES[(root % 12) as usize].0
+    ));
+    out.push_str(&format!("  \\time {time_sig}\n"));
+    out.push_str(&format!("  \\tempo 4 = {}\n ", seq.bpm));
+
+    let mut previous = 4 * 7; // c'
+    for (pitch, start, end) in events {
+        let mut pos = start;
+        let mut name = match pitch {
+            Some(p) => {
+                let (name, position) = ly_relative_pitch(p, previous);
+                previous = position;
+                name
+            }
+            None => "r".to_string(),
+        };
+        while pos < end {
+            let bar_end = (pos / bar_steps + 1) * bar_steps;
+            let piece_end = end.min(bar_end);
+            let durations = ly_durations(piece_end - pos);
+            for (j, duration) in durations.iter().enumerate() {
+                out.push_str(&format!(" {name}{duration}"));
+                let last_piece = piece_end == end && j + 1 == durations.len();
+                if pitch.is_some() && !last_piece {
+                    out.push('~');
+                }
+                if let Some(p) = pitch {
+                    // Tied continuations repeat the bare name: same letter, same octave.
+                    name = LY_NAMES[(p % 12) as usize].0.to_string();
+                }
+            }
+            pos = piece_end;
+            if pos % bar_steps == 0 {
+                out.push_str(" |\n ");
+            }
+        }
+    }
+    out.truncate(out.trim_end().len());
+    out.push_str("\n}\n");
+    out
+}
+
+// ============================================================================
+// GUI MODE
+// ============================================================================
+
+struct PlaybackState {
+    playing: bool,
+    current_tick: u32,
+    /// Set when the owning view closes so the thread releases the MIDI port
+    quit: bool,
+    /// What the thread plays; the view swaps in a new one on every regenerate or edit
+    seq: Arc<MidiSequence>,
+    tick_limit: u32,
+}
+
+fn list_midi_ports() -> Result<(), Box<dyn Error>> {
+    let midi_out = MidiOutput::new("MIDI Seed Gen")?;
+    let out_ports = midi_out.ports();
+    if out_ports.is_empty() {
+        println!("no ports found");
+    }
+    for (i, port) in out_ports.iter().enumerate() {
+        let name = midi_out
+            .port_name(port)
+            .unwrap_or_else(|_| "Unknown".to_string());
+        println!("{i}: {name}");
+    }
+    Ok(())
+}
+
+/// Connects to the output port at index `port`, or the first whose name contains it
+/// (case-insensitive); port 0 when `port` is None. Lists the ports when nothing matches.
+fn setup_midi_output(port: Option<&str>) -> Result<MidiOutputConnection, Box<dyn Error>> {
+    let midi_out = MidiOutput::new("MIDI Seed Gen")?;
+    let out_ports = midi_out.ports();
+
+    if out_ports.is_empty() {
+        return Err("No MIDI output ports available".into());
+    }
+
+    let names: Vec<String> = out_ports
+        .iter()
+        .map(|p| {
+            midi_out
+                .port_name(p)
+                .unwrap_or_else(|_| "Unknown".to_string())
+        })
+        .collect();
+    let index = match port {
+        None => Some(0),
+        Some(wanted) => match wanted.parse::<usize>() {
+            Ok(index) => (index < out_ports.len()).then_some(index),
+            Err(_) => {
+                let wanted = wanted.to_lowercase();
+                names
+                    .iter()
+                    .position(|name| name.to_lowercase().contains(&wanted))
+            }
+        },
+    };
+    let Some(index) = index else {
+        eprintln!("Available MIDI output ports:");
+        for (i, name) in names.iter().enumerate() {
+            eprintln!("  {i}: {name}");
+        }
+        return Err(format!("No MIDI output port matches '{}'", port.unwrap_or_default()).into());
+    };
+    println!("Connected to MIDI output: {}", names[index]);
+
+    let conn = midi_out.connect(&out_ports[index], "midi-gen-output")?;
+    Ok(conn)
+}
+
+/// Raw messages due at `tick` (NoteOffs before NoteOns). Nothing is scheduled at or past
+/// `tick_limit`; the player silences everything there instead.
+fn messages_at_tick(seq: &MidiSequence, tick: u32, tick_limit: u32) -> Vec<[u8; 3]> {
+    if tick >= tick_limit {
+        return Vec::new();
+    }
+    let offs = seq
+        .notes
+        .iter()
+        .filter(|n| n.end_tick == tick)
+        .map(|n| [0x80 | n.channel, n.pitch, 0]);
+    let ons = seq
+        .notes
+        .iter()
+        .filter(|n| n.start_tick == tick)
+        .map(|n| [0x90 | n.channel, n.pitch, n.velocity]);
+    offs.chain(ons).collect()
+}
+
+/// NoteOffs for every note sounding at `tick`, then All Notes Off on each channel for
+/// synths that ignore unmatched offs.
+fn silence(seq: &MidiSequence, tick: u32) -> Vec<[u8; 3]> {
+    let mut messages: Vec<[u8; 3]> = seq
+        .notes
+        .iter()
+        .filter(|n| n.start_tick <= tick && tick < n.end_tick)
+        .map(|n| [0x80 | n.channel, n.pitch, 0])
+        .collect();
+    messages.extend(all_notes_off(seq));
+    messages
+}
+
+/// All Notes Off (CC 123) for every channel the sequence uses.
+fn all_notes_off(seq: &MidiSequence) -> Vec<[u8; 3]> {
+    let mut channels: Vec<u8> = seq.notes.iter().map(|n| n.channel).collect();
+    channels.sort_unstable();
+    channels.dedup();
+    channels.into_iter().map(|ch| [0xB0 | ch, 123, 0]).collect()
+}
+
+/// Where playback loops back to 0: the song end, or the end of the first
+/// `preview_bars` bars when that is shorter.
+fn playback_tick_limit(seq: &MidiSequence, preview_bars: Option<u32>) -> u32 {
+    let bar_ticks = seq.bar_ticks();
+    preview_bars.map_or(seq.total_ticks, |bars| {
+        bars.saturating_mul(bar_ticks).min(seq.total_ticks)
+    })
+}
+
+fn spawn_playback_thread(port: Option<String>, state: Arc<Mutex<PlaybackState>>) {
+    thread::spawn(move || {
+        let mut midi_out = match setup_midi_output(port.as_deref()) {
+            Ok(m) => m,
+            Err(e) => {
+                eprintln!("Failed to setup MIDI: {}", e);
+                return;
+            }
+        };
+
+        // Last tick played while running, so stopping can release what it left sounding
+        let mut last_played: Option<u32> = None;
+        // Wall-clock anchor and ticks played since it; deadlines are absolute so loop
+        // overhead never accumulates into drift. Reset on every (re)start.
+        let mut clock: Option<(Instant, u64)> = None;
+        let mut seq = Arc::clone(&state.lock().unwrap().seq);
+        loop {
+            let (playing, current_tick, quit, latest, tick_limit) = {
+                let s = state.lock().unwrap();
+                (
+                    s.playing,
+                    s.current_tick,
+                    s.quit,
+                    Arc::clone(&s.seq),
+                    s.tick_limit,
+                )
+            };
+
+            // A swapped-in sequence releases the old one's notes and restarts the clock
+            if !Arc::ptr_eq(&seq, &latest) {
+                if let Some(tick) = last_played.take() {
+                    for message in silence(&seq, tick) {
+                        midi_out.send(&message).ok();
+                    }
+                }
+                seq = latest;
+                clock = None;
+            }
+            let seconds_per_tick =
+                bpm_to_us_per_quarter(seq.bpm) as f64 / seq.ppqn as f64 / 1_000_000.0;
+
+            if !playing || quit {
+                if let Some(tick) = last_played.take() {
+                    for message in silence(&seq, tick) {
+                        midi_out.send(&message).ok();
+                    }
+                }
+            }
+
+            if quit {
+                return;
+            }
+
+            if !playing {
+                clock = None;
+                thread::sleep(Duration::from_millis(50));
+                continue;
+            }
+
+            let (start, ticks_played) = clock.get_or_insert_with(|| (Instant::now(), 0));
+
+            // Play notes that start/end at current tick
+            for message in messages_at_tick(&seq, current_tick, tick_limit) {
+                midi_out.send(&message).ok();
+            }
+            last_played = Some(current_tick);
+
+            // Advance tick
+            let wrapped = {
+                let mut s = state.lock().unwrap();
+                s.current_tick += 1;
+                if s.current_tick >= tick_limit {
+                    s.current_tick = 0;
+                }
+                s.current_tick == 0
+            };
+
+            // Notes ending at the loop point (or cut by --preview-bars) would otherwise hang
+            if wrapped {
+                for message in silence(&seq, current_tick) {
+                    midi_out.send(&message).ok();
+                }
+                last_played = None;
+            }
+
+            // Sleep until the next tick's deadline; pause/stop is seen within one tick
+            *ticks_played += 1;
+            let deadline =
+                *start + Duration::from_secs_f64(*ticks_played as f64 * seconds_per_tick);
+            thread::sleep(deadline.saturating_duration_since(Instant::now()));
+        }
+    });
+}
+
+/// Hands the playback thread a new sequence, keeping the position while it still fits.
+fn swap_playback_seq(state: &Mutex<PlaybackState>, seq: &MidiSequence, preview_bars: Option<u32>) {
+    let mut s = state.lock().unwrap();
+    s.tick_limit = playback_tick_limit(seq, preview_bars);
+    if s.current_tick >= s.tick_limit {
+        s.current_tick = 0;
+    }
+    s.seq = Arc::new(seq.clone());
+}
+
+/// A `[-] label [+]` control at (`x`, `y`); returns -1 or +1 on the frame a button is
+/// clicked, otherwise 0.
+fn stepper(x: f32, y: f32, label: &str) -> i32 {
+    let (btn, h, text_w) = (25.0, 25.0, 110.0);
+    let plus_x = x + btn + text_w + 5.0;
+    let btn_color = Color::from_rgba(200, 200, 210, 255);
+    draw_rectangle(x, y, btn, h, btn_color);
+    draw_text("-", x + 8.0, y + 18.0, 22.0, BLACK);
+    draw_text(label, x + btn + 8.0, y + 18.0, 18.0, WHITE);
+    draw_rectangle(plus_x, y, btn, h, btn_color);
+    draw_text("+", plus_x + 7.0, y + 18.0, 22.0, BLACK);
+
+    if !is_mouse_button_pressed(MouseButton::Left) {
+        return 0;
+    }
+    let (mx, my) = mouse_position();
+    if my < y || my > y + h {
+        0
+    } else if mx >= x && mx <= x + btn {
+        -1
+    } else if mx >= plus_x && mx <= plus_x + btn {
+        1
+    } else {
+        0
+    }
+}
+
+/// Regenerates `cli`'s sequence with new swing and humanize amounts (0 for none). The
+/// seed is kept and the groove draws come from their own streams, so only timing and
+/// velocity move; the pitches are the same at any amount.
+fn regroove(cli: &mut Cli, swing: u32, humanize: u32) -> Result<MidiSequence, Box<dyn Error>> {
+    cli.swing = swing;
+    cli.humanize = (humanize > 0).then_some(humanize);
+    generate_from_cli(cli)
+}
+
+/// Piano roll view. With `can_go_back` a BACK button returns to the caller (the seed grid).
+async fn run_gui(mut cli: Cli, mut seq: MidiSequence, can_go_back: bool) {
+    let state = Arc::new(Mutex::new(PlaybackState {
+        playing: false,
+        current_tick: 0,
+        quit: false,
+        seq: Arc::new(seq.clone()),
+        tick_limit: playback_tick_limit(&seq, cli.preview_bars),
+    }));
+    spawn_playback_thread(cli.midi_port.clone(), Arc::clone(&state));
+
+    let mut go_back = false;
+    let mut grid_offsets = cli.humanize_visualize;
+    let mut view = RollView::FIT;
+    let mut drag_x: Option<f32> = None;
+    loop {
+        if go_back {
+            state.lock().unwrap().quit = true;
+            return;
+        }
+
+        clear_background(Color::from_rgba(15, 15, 20, 255));
+
+        // Calculate dimensions
+        let panel_height = 130.0;
+        let piano_roll_y = panel_height;
+        let piano_roll_height = screen_height() - panel_height;
+
+        // ===== CONTROL PANEL =====
+        draw_rectangle(0.0, 0.0, screen_width(), panel_height, Color::from_rgba(25, 25, 30, 255));
+
+        // Title
+        draw_text(
+            &format!("MIDI SEED GENERATOR - Seed: 0x{:X}", cli.seed),
+            20.0,
+            30.0,
+            24.0,
+            WHITE,
+        );
+        draw_text(
+            &format!(
+                "BPM: {} | Scale: {} | Root: {} | Length: {:.1}s, {} bars",
+                seq.bpm,
+                cli.scale,
+                cli.root.as_u8(),
+                sequence_seconds(&seq),
+                format!("{:.2}", sequence_bars(&seq))
+                    .trim_end_matches('0')
+                    .trim_end_matches('.')
+            ),
+            20.0,
+            55.0,
+            18.0,
+            LIGHTGRAY,
+        );
+
+        let hint = "Space: play/stop   R: regenerate   S: save   Wheel: zoom   Arrows/drag: scroll";
+        let hint_w = measure_text(hint, None, 16, 1.0).width;
+        draw_text(hint, screen_width() - hint_w - 20.0, 30.0, 16.0, GRAY);
+
+        // Buttons
+        let play_btn_x = 20.0;
+        let play_btn_y = 70.0;
+        let btn_w = 100.0;
+        let btn_h = 25.0;
+
+        let (playing, current_tick) = {
+            let s = state.lock().unwrap();
+            (s.playing, s.current_tick)
+        };
+
+        // Play/Stop button
+        let play_color = if playing {
+            Color::from_rgba(255, 60, 60, 255)
+        } else {
+            Color::from_rgba(0, 255, 128, 255)
+        };
+        draw_rectangle(play_btn_x, play_btn_y, btn_w, btn_h, play_color);
+        let play_text = if playing { "STOP" } else { "PLAY" };
+        draw_text(play_text, play_btn_x + 25.0, play_btn_y + 18.0, 20.0, BLACK);
+
+        // Keyboard shortcuts share the button actions below
+        let mut toggle_play = is_key_pressed(KeyCode::Space);
+        let mut regenerate = is_key_pressed(KeyCode::R);
+
+        if is_mouse_button_pressed(MouseButton::Left) {
+            let (mx, my) = mouse_position();
+            if mx >= play_btn_x && mx <= play_btn_x + btn_w && my >= play_btn_y && my <= play_btn_y + btn_h {
+                toggle_play = true;
+            }
+        }
+        if toggle_play {
+            let mut s = state.lock().unwrap();
+            s.playing = !s.playing;
+            if s.playing {
+                s.current_tick = 0;
+            }
+        }
+
+        // Regenerate button
+        let regen_btn_x = play_btn_x + btn_w + 10.0;
+        draw_rectangle(regen_btn_x, play_btn_y, btn_w + 20.0, btn_h, Color::from_rgba(60, 150, 255, 255));
+        draw_text("REGENERATE", regen_btn_x + 10.0, play_btn_y + 18.0, 18.0, BLACK);
+
+        if is_mouse_button_pressed(MouseButton::Left) {
+            let (mx, my) = mouse_position();
+            if mx >= regen_btn_x && mx <= regen_btn_x + btn_w + 20.0 && my >= play_btn_y && my <= play_btn_y + btn_h {
+                regenerate = true;
+            }
+        }
+        if regenerate {
+            cli.seed = ::rand::random();
+            match generate_from_cli(&mut cli) {
+                Ok(fresh) => {
+                    seq = fresh;
+                    {
+                        let mut s = state.lock().unwrap();
+                        s.playing = false;
+                        s.current_tick = 0;
+                    }
+                    swap_playback_seq(&state, &seq, cli.preview_bars);
+                }
+                Err(e) => eprintln!("Failed to regenerate: {}", e),
+            }
+        }
+
+        // Parameter steppers: regenerate from the same seed, so edits compare A/B
+        let steppers_x = 400.0;
+        let bpm_step = stepper(steppers_x, play_btn_y, &format!("BPM {}", cli.bpm));
+        let density_step = stepper(
+            steppers_x + 180.0,
+            play_btn_y,
+            &format!("DENS {:.2}", cli.density),
+        );
+        let root_step = stepper(
+            steppers_x + 360.0,
+            play_btn_y,
+            &format!("ROOT {}", note_to_string(cli.root.as_u8())),
+        );
+        if bpm_step != 0 || density_step != 0 || root_step != 0 {
+            cli.bpm = (cli.bpm as i32 + 5 * bpm_step).clamp(20, 300) as u32;
+            let density = (cli.density + 0.05 * density_step as f32).clamp(0.0, 1.0);
+            cli.density = (density * 100.0).round() / 100.0;
+            cli.root = Note((cli.root.as_u8() as i32 + root_step).clamp(0, 127) as u8);
+            match generate_from_cli(&mut cli) {
+                Ok(edited) => {
+                    seq = edited;
+                    swap_playback_seq(&state, &seq, cli.preview_bars);
+                }
+                Err(e) => eprintln!("Failed to regenerate: {}", e),
+            }
+        }
+
+        // Groove steppers: same seed and pitches, new feel
+        let groove_y = play_btn_y + btn_h + 5.0;
+        let humanize = cli.humanize.unwrap_or(0);
+        let swing_step = stepper(steppers_x, groove_y, &format!("SWING {}", cli.swing));
+        let humanize_step = stepper(steppers_x + 180.0, groove_y, &format!("HUM {}", humanize));
+        if swing_step != 0 || humanize_step != 0 {
+            let swing = (cli.swing as i32 + 10 * swing_step).clamp(0, 100) as u32;
+            let humanize = (humanize as i32 + 10 * humanize_step).clamp(0, 100) as u32;
+            match regroove(&mut cli, swing, humanize) {
+                Ok(grooved) => {
+                    seq = grooved;
+                    swap_playback_seq(&state, &seq, cli.preview_bars);
+                }
+                Err(e) => eprintln!("Failed to regroove: {}", e),
+            }
+        }
+
+        if is_key_pressed(KeyCode::S) {
+            let out_path = cli
+                .out
+                .clone()
+                .unwrap_or_else(|| default_out_path(cli.seed));
+            match save_sequence(&seq, &cli.gen_config(), &out_path) {
+                Ok(()) => eprintln!("Wrote {}", out_path),
+                Err(e) => eprintln!("Failed to save {}: {}", out_path, e),
+            }
+        }
+
+        // Back button (seed grid only)
+        if can_go_back {
+            let back_btn_x = regen_btn_x + btn_w + 30.0;
+            draw_rectangle(
+                back_btn_x,
+                play_btn_y,
+                btn_w,
+                btn_h,
+                Color::from_rgba(200, 200, 210, 255),
+            );
+            draw_text("BACK", back_btn_x + 28.0, play_btn_y + 18.0, 18.0, BLACK);
+
+            if is_mouse_button_pressed(MouseButton::Left) {
+                let (mx, my) = mouse_position();
+                if mx >= back_btn_x
+                    && mx <= back_btn_x + btn_w
+                    && my >= play_btn_y
+                    && my <= play_btn_y + btn_h
+                {
+                    go_back = true;
+                }
+            }
+        }
+
+        // Grid offsets toggle (right edge)
+        let grid_btn_x = screen_width() - btn_w - 20.0;
+        let grid_color = if grid_offsets {
+            Color::from_rgba(255, 210, 90, 255)
+        } else {
+            Color::from_rgba(90, 90, 100, 255)
+        };
+        draw_rectangle(grid_btn_x, play_btn_y, btn_w, btn_h, grid_color);
+        draw_text("GRID", grid_btn_x + 30.0, play_btn_y + 18.0, 18.0, BLACK);
+
+        if is_mouse_button_pressed(MouseButton::Left) {
+            let (mx, my) = mouse_position();
+            if mx >= grid_btn_x
+                && mx <= grid_btn_x + btn_w
+                && my >= play_btn_y
+                && my <= play_btn_y + btn_h
+            {
+                grid_offsets = !grid_offsets;
+            }
+        }
+
+        // ===== PIANO ROLL =====
+        // Wheel zooms around the cursor; arrow keys or dragging scroll.
+        let roll_area = Rect::new(0.0, piano_roll_y, screen_width(), piano_roll_height);
+        let key_width = 80.0;
+        let roll_w = roll_area.w - key_width - 20.0;
+        let (mx, my) = mouse_position();
+        let over_roll = my >= roll_area.y && mx >= key_width;
+        let wheel = mouse_wheel().1;
+        if over_roll && wheel != 0.0 {
+            let anchor = ((mx - key_width) / roll_w).clamp(0.0, 1.0);
+            let factor = if wheel > 0.0 { 1.25 } else { 0.8 };
+            view.zoom_at(factor, anchor, seq.total_ticks);
+        }
+        let ticks_per_px = view.visible_ticks(seq.total_ticks) / roll_w;
+        if is_key_down(KeyCode::Right) {
+            view.scroll_by(8.0 * ticks_per_px, seq.total_ticks);
+        }
+        if is_key_down(KeyCode::Left) {
+            view.scroll_by(-8.0 * ticks_per_px, seq.total_ticks);
+        }
+        if is_mouse_button_pressed(MouseButton::Left) && over_roll {
+            drag_x = Some(mx);
+        }
+        if !is_mouse_button_down(MouseButton::Left) {
+            drag_x = None;
+        }
+        if let Some(last_x) = drag_x {
+            view.scroll_by((last_x - mx) * ticks_per_px, seq.total_ticks);
+            drag_x = Some(mx);
+        }
+        view.clamp(seq.total_ticks);
+
+        let playhead = if playing { Some(current_tick) } else { None };
+        render_roll(
+            &seq,
+            roll_area,
+            key_width,
+            view,
+            playhead,
+            cli.accidentals,
+            grid_offsets,
+        );
+
+        next_frame().await
+    }
+}
+
+/// Horizontal zoom and scroll of the piano roll. Zoom 1 fits the whole song; the scroll
+/// is the tick at the left edge of the roll.
+#[derive(Debug, Clone, Copy)]
+struct RollView {
+    zoom: f32,
+    scroll: f32,
+}
+
+impl RollView {
+    const FIT: RollView = RollView {
+        zoom: 1.0,
+        scroll: 0.0,
+    };
+    const MAX_ZOOM: f32 = 64.0;
+
+    /// Ticks across the roll at this zoom.
+    fn visible_ticks(&self, total_ticks: u32) -> f32 {
+        total_ticks as f32 / self.zoom
+    }
+
+    /// Keeps the view inside the song: never before tick 0 or past its end.
+    fn clamp(&mut self, total_ticks: u32) {
+        self.zoom = self.zoom.clamp(1.0, Self::MAX_ZOOM);
+        let max_scroll = total_ticks as f32 - self.visible_ticks(total_ticks);
+        self.scroll = self.scroll.clamp(0.0, max_scroll.max(0.0));
+    }
+
+    /// Zooms by `factor`, holding the tick at `anchor` (0..1 across the roll) in place.
+    fn zoom_at(&mut self, factor: f32, anchor: f32, total_ticks: u32) {
+        let tick = self.scroll + anchor * self.visible_ticks(total_ticks);
+        self.zoom = (self.zoom * factor).clamp(1.0, Self::MAX_ZOOM);
+        self.scroll = tick - anchor * self.visible_ticks(total_ticks);
+        self.clamp(total_ticks);
+    }
+
+    /// Pans by `ticks` (positive moves later in the song).
+    fn scroll_by(&mut self, ticks: f32, total_ticks: u32) {
+        self.scroll += ticks;
+        self.clamp(total_ticks);
+    }
+}
+
+/// Draws `seq` as a piano roll inside `area`, zoomed and scrolled by `view`. A
+/// `key_width` of 0 skips the key column and note labels, which keeps the seed-grid
+/// thumbnails readable.
+fn render_roll(
+    seq: &MidiSequence,
+    area: Rect,
+    key_width: f32,
+    view: RollView,
+    playhead: Option<u32>,
+    accidentals: Accidentals,
+    grid_offsets: bool,
+) {
+    if seq.notes.is_empty() {
+        draw_rectangle(
+            area.x,
+            area.y,
+            area.w,
+            area.h,
+            Color::from_rgba(20, 20, 25, 255),
+        );
+        let (message, size) = if key_width > 0.0 {
+            ("no notes - regenerate", 24.0)
+        } else {
+            ("no notes", 16.0)
+        };
+        let width = measure_text(message, None, size as u16, 1.0).width;
+        let (x, y) = (area.x + (area.w - width) / 2.0, area.y + area.h / 2.0);
+        draw_text(message, x, y, size, GRAY);
+        return;
+    }
+
+    // Find pitch range, widened by two semitones each way where MIDI allows
+    let min_pitch = seq
+        .notes
+        .iter()
+        .map(|n| n.pitch)
+        .min()
+        .unwrap_or(60)
+        .saturating_sub(2);
+    let max_pitch = seq
+        .notes
+        .iter()
+        .map(|n| n.pitch)
+        .max()
+        .unwrap_or(72)
+        .saturating_add(2)
+        .min(127);
+    let pitch_range = (max_pitch - min_pitch + 1) as f32;
+    let row_height = area.h / pitch_range;
+
+    // Time scaling
+    let right_margin = if key_width > 0.0 { 20.0 } else { 0.0 };
+    let time_scale = (area.w - key_width - right_margin) / view.visible_ticks(seq.total_ticks);
+    let roll_x = area.x + key_width;
+    let first_tick = view.scroll;
+    let last_tick = view.scroll + view.visible_ticks(seq.total_ticks);
+    let tick_x = |tick: u32| roll_x + (tick as f32 - first_tick) * time_scale;
+
+    // Draw background
+    draw_rectangle(
+        area.x,
+        area.y,
+        area.w,
+        area.h,
+        Color::from_rgba(20, 20, 25, 255),
+    );
+
+    for pitch in min_pitch..=max_pitch {
+        let y = area.y + ((max_pitch - pitch) as f32 / pitch_range) * area.h;
+        draw_line(
+            area.x,
+            y,
+            area.x + area.w,
+            y,
+            1.0,
+            Color::from_rgba(40, 40, 45, 255),
+        );
+    }
+
+    // Draw time grid
+    let first_quarter = (first_tick / seq.ppqn as f32).ceil() as u32;
+    let last_quarter =
+        ((last_tick / seq.ppqn as f32).floor() as u32).min(seq.total_ticks / seq.ppqn as u32);
+    for q in first_quarter..=last_quarter {
+        let x = tick_x(q * seq.ppqn as u32);
+        let color = if q % 4 == 0 {
+            Color::from_rgba(80, 80, 90, 255)
+        } else {
+            Color::from_rgba(50, 50, 55, 255)
+        };
+        draw_line(x, area.y, x, area.y + area.h, 1.0, color);
+    }
+
+    // Draw notes, cut at the left edge of the roll when scrolled into
+    let inset = (row_height * 0.15).min(2.0);
+    let visible = seq
+        .notes
+        .iter()
+        .filter(|n| n.end_tick as f32 >= first_tick && n.start_tick as f32 <= last_tick);
+    for note in visible {
+        let y = area.y + ((max_pitch - note.pitch) as f32 / pitch_range) * area.h;
+        let x = tick_x(note.start_tick).max(roll_x);
+        let width = (tick_x(note.end_tick) - x).max(2.0);
+
+        // Velocity to opacity: 40% at velocity 0 up to fully opaque at 127
+        let alpha = ((note.velocity as f32 / 127.0) * 0.6 + 0.4) * 255.0;
+
+        let note_color = Color::from_rgba(0, 180, 255, alpha as u8);
+        draw_rectangle(x, y + inset, width, row_height - 2.0 * inset, note_color);
+        draw_rectangle_lines(
+            x,
+            y + inset,
+            width,
+            row_height - 2.0 * inset,
+            1.0,
+            Color::from_rgba(100, 200, 255, 200),
+        );
+
+        // Grid position tick and a line over to the actual onset
+        if grid_offsets && note.grid_offset() != 0 {
+            let grid_x = tick_x(note.grid_tick).max(roll_x);
+            let mid_y = y + row_height / 2.0;
+            let faint = Color::from_rgba(255, 210, 90, 160);
+            draw_line(grid_x, y, grid_x, y + row_height, 1.0, faint);
+            draw_line(grid_x, mid_y, x, mid_y, 1.0, faint);
+        }
+    }
+
+    // Draw piano keys (left side)
+    for pitch in min_pitch..=max_pitch {
+        let y = area.y + ((max_pitch - pitch) as f32 / pitch_range) * area.h;
+
+        if key_width > 0.0 {
+            // White/black key coloring
+            let note_class = pitch % 12;
+            let is_black = matches!(note_class, 1 | 3 | 6 | 8 | 10);
+            let key_color = if is_black {
+                Color::from_rgba(30, 30, 35, 255)
+            } else {
+                Color::from_rgba(45, 45, 50, 255)
+            };
+            draw_rectangle(area.x, y, key_width, row_height, key_color);
+
+            // Note name
+            let label = note_name(pitch, accidentals);
+            draw_text(
+                &label,
+                area.x + 10.0,
+                y + row_height / 2.0 + 5.0,
+                16.0,
+                LIGHTGRAY,
+            );
+        }
+    }
+
+    // Draw playhead
+    if let Some(tick) = playhead.filter(|&t| t as f32 >= first_tick && t as f32 <= last_tick) {
+        let playhead_x = tick_x(tick);
+        draw_line(
+            playhead_x,
+            area.y,
+            playhead_x,
+            area.y + area.h,
+            2.0,
+            Color::from_rgba(255, 60, 60, 255),
+        );
+    }
+}
+
+// ============================================================================
+// SEED GRID
+// ============================================================================
+
+/// `--seed-grid` layout, parsed from `<cols>x<rows>` (e.g. `4x3`).
+#[derive(Debug, Clone, Copy)]
+struct SeedGrid {
+    cols: u32,
+    rows: u32,
+}
+
+impl std::str::FromStr for SeedGrid {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let (cols, rows) = input
+            .trim()
+            .split_once(['x', 'X'])
+            .ok_or_else(|| format!("expected <cols>x<rows>, got '{input}'"))?;
+        let parse = |v: &str| match v.trim().parse::<u32>() {
+            Ok(n) if (1..=16).contains(&n) => Ok(n),
+            _ => Err(format!("grid size must be 1..16, got '{v}'")),
+        };
+        Ok(SeedGrid {
+            cols: parse(cols)?,
+            rows: parse(rows)?,
+        })
+    }
+}
+
+/// Seeds `base, base + 1, ...` laid out row-major.
+fn grid_seeds(base: u64, grid: SeedGrid) -> Vec<u64> {
+    (0..(grid.cols * grid.rows) as u64)
+        .map(|i| base.wrapping_add(i))
+        .collect()
+}
+
+/// One generated cell per grid seed, built once so frames only draw them. Cells that
+/// fail to generate are reported and left empty.
+fn grid_cells(cli: &Cli, grid: SeedGrid) -> Vec<(Cli, Option<MidiSequence>)> {
+    grid_seeds(cli.seed, grid)
+        .into_iter()
+        .map(|seed| {
+            let mut cell_cli = cli.clone();
+            cell_cli.seed = seed;
+            let seq = generate_from_cli(&mut cell_cli)
+                .map_err(|e| eprintln!("Seed 0x{seed:X} failed to generate: {}", e))
+                .ok();
+            (cell_cli, seq)
+        })
+        .collect()
+}
+
+async fn run_seed_grid(cli: Cli, grid: SeedGrid) {
+    let cells = grid_cells(&cli, grid);
+    let header_height = 40.0;
+
+    loop {
+        clear_background(Color::from_rgba(15, 15, 20, 255));
+        draw_text(
+            &format!("SEED GRID - 0x{:X}.. | click a cell to open it", cli.seed),
+            20.0,
+            28.0,
+            22.0,
+            WHITE,
+        );
+
+        let cell_w = screen_width() / grid.cols as f32;
+        let cell_h = (screen_height() - header_height) / grid.rows as f32;
+        let clicked = is_mouse_button_pressed(MouseButton::Left).then(mouse_position);
+        let mut opened = None;
+
+        for (i, (cell_cli, seq)) in cells.iter().enumerate() {
+            let cell = Rect::new(
+                (i as u32 % grid.cols) as f32 * cell_w,
+                header_height + (i as u32 / grid.cols) as f32 * cell_h,
+                cell_w,
+                cell_h,
+            );
+
+            if let Some(seq) = seq {
+                let roll = Rect::new(cell.x + 4.0, cell.y + 20.0, cell.w - 8.0, cell.h - 24.0);
+                render_roll(seq, roll, 0.0, RollView::FIT, None, cli.accidentals, false);
+                if clicked.is_some_and(|(mx, my)| cell.contains(vec2(mx, my))) {
+                    opened = Some((cell_cli.clone(), seq.clone()));
+                }
+            }
+            draw_text(
+                &format!("0x{:X}", cell_cli.seed),
+                cell.x + 6.0,
+                cell.y + 15.0,
+                16.0,
+                LIGHTGRAY,
+            );
+            draw_rectangle_lines(
+                cell.x,
+                cell.y,
+                cell.w,
+                cell.h,
+                1.0,
+                Color::from_rgba(60, 60, 70, 255),
+            );
+        }
+
+        next_frame().await;
+
+        if let Some((cell_cli, seq)) = opened {
+            run_gui(cell_cli, seq, true).await;
+        }
+    }
+}
+
+fn note_to_string(pitch: u8) -> String {
+    note_name(pitch, Accidentals::Sharp)
+}
+
+fn note_name(pitch: u8, accidentals: Accidentals) -> String {
+    let note_names = match accidentals {
+        Accidentals::Sharp => [
+            "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+        ],
+        Accidentals::Flat => [
+            "C", "Db", "D", "Eb", "E", "F", "Gb", "G", "Ab", "A", "Bb", "B",
+        ],
+    };
+    let octave = (pitch / 12) as i32 - 1;
+    let note = note_names[(pitch % 12) as usize];
+    format!("{}{}", note, octave)
+}
+
+// ============================================================================
+// BATCH MODE
+// ============================================================================
+
+/// Generates `cli.count` sequences for seeds `seed, seed + 1, ...` on up to `jobs` threads.
+///
+/// Each item depends only on its own seed, and results are sorted back into index order
+/// before returning, so the batch is identical for any thread count or scheduling.
+fn generate_batch(cli: &Cli, jobs: usize) -> Result<Vec<(Cli, MidiSequence)>, Box<dyn Error>> {
+    let items: Vec<(usize, Cli)> = (0..cli.count)
+        .map(|i| {
+            let mut item = cli.clone();
+            item.seed = cli.seed.wrapping_add(i as u64);
+            (i as usize, item)
+        })
+        .collect();
+
+    let jobs = jobs.clamp(1, items.len());
+    let chunk_len = items.len().div_ceil(jobs);
+    let mut results = thread::scope(|s| {
+        let handles: Vec<_> = items
+            .chunks(chunk_len)
+            .map(|chunk| {
+                s.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|(i, item)| {
+                            let mut item = item.clone();
+                            let seq = generate_from_cli(&mut item).map_err(|e| e.to_string());
+                            (*i, item, seq)
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|h| h.join().expect("batch worker panicked"))
+            .collect::<Vec<_>>()
+    });
+    results.sort_by_key(|(i, _, _)| *i);
+
+    results
+        .into_iter()
+        .map(|(_, item, seq)| Ok((item, seq?)))
+        .collect()
+}
+
+/// `out/melody.mid` + 3 => `out/melody_003.mid`
+fn indexed_out_path(base: &str, index: usize) -> String {
+    let path = std::path::Path::new(base);
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("out");
+    let ext = path.extension().and_then(|s| s.to_str()).unwrap_or("mid");
+    path.with_file_name(format!("{stem}_{index:03}.{ext}"))
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// One bar as a standalone clip: notes starting in it, rebased to tick 0 and cut at the
+/// bar line, with the key in force at its start carried over to tick 0.
+fn slice_bar(seq: &MidiSequence, bar_index: u32, bar_ticks: u32) -> MidiSequence {
+    let start = bar_index * bar_ticks;
+    let end = start + bar_ticks;
+    let notes = seq
+        .notes
+        .iter()
+        .filter(|n| n.start_tick >= start && n.start_tick < end)
+        .map(|n| MidiNote {
+            start_tick: n.start_tick - start,
+            end_tick: n.end_tick.min(end) - start,
+            grid_tick: n.grid_tick.saturating_sub(start),
+            ..n.clone()
+        })
+        .collect();
+
+    let mut key_changes: Vec<(u32, i8)> = seq
+        .key_changes
+        .iter()
+        .filter(|(tick, _)| *tick > start && *tick < end)
+        .map(|&(tick, shift)| (tick - start, shift))
+        .collect();
+    if let Some(&(_, shift)) = seq
+        .key_changes
+        .iter()
+        .rev()
+        .find(|(tick, _)| *tick <= start)
+    {
+        key_changes.insert(0, (0, shift));
+    }
+
+    MidiSequence {
+        notes,
+        bpm: seq.bpm,
+        ppqn: seq.ppqn,
+        total_ticks: bar_ticks,
+        time_signature: seq.time_signature,
+        key_changes,
+    }
+}
+
+fn save_split_bars(seq: &MidiSequence, cli: &Cli, base: &str) -> Result<(), Box<dyn Error>> {
+    let bar_ticks = seq.bar_ticks();
+    for bar in 0..seq.total_ticks.div_ceil(bar_ticks) {
+        let out_path = indexed_out_path(base, bar as usize + 1);
+        save_sequence(
+            &slice_bar(seq, bar, bar_ticks),
+            &cli.gen_config(),
+            &out_path,
+        )?;
+        eprintln!("Wrote {}", out_path);
+    }
+    Ok(())
+}
+
+/// `base` with `_chNN` added to the stem, for --split-channels.
+fn channel_out_path(base: &str, channel: u8) -> String {
+    let path = std::path::Path::new(base);
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("out");
+    let ext = path.extension().and_then(|s| s.to_str()).unwrap_or("mid");
+    path.with_file_name(format!("{stem}_ch{channel:02}.{ext}"))
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn save_split_channels(seq: &MidiSequence, cli: &Cli, base: &str) -> Result<(), Box<dyn Error>> {
+    let mut channels: Vec<u8> = seq.notes.iter().map(|n| n.channel).collect();
+    channels.sort_unstable();
+    channels.dedup();
+    for channel in channels {
+        let layer = MidiSequence {
+            notes: seq
+                .notes
+                .iter()
+                .filter(|n| n.channel == channel)
+                .map(|n| MidiNote {
+                    track: 0,
+                    ..n.clone()
+                })
+                .collect(),
+            ..seq.clone()
+        };
+        // The program change goes out on the layer's own channel.
+        let mut layer_cli = cli.clone();
+        layer_cli.channel = channel;
+        let out_path = channel_out_path(base, channel);
+        save_sequence(&layer, &layer_cli.gen_config(), &out_path)?;
+        eprintln!("Wrote {}", out_path);
+    }
+    Ok(())
+}
+
+/// Minimal JSON string literal (quotes, backslashes and control characters escaped).
+fn json_string(value: &str) -> String {
+    let mut out = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// CSV field, quoted (with doubled quotes) only when it needs to be.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// One line per written file with its seed and the parameters shared by the batch.
+fn batch_manifest(entries: &[(String, Cli)], csv: bool) -> String {
+    let mut out = String::new();
+    if csv {
+        out.push_str("file,seed,bpm,bars,root,scale\n");
+        for (path, item) in entries {
+            out.push_str(&format!(
+                "{},{},{},{},{},{}\n",
+                csv_field(path),
+                item.seed,
+                item.bpm,
+                item.bars,
+                note_to_string(item.root.as_u8()),
+                csv_field(&item.scale)
+            ));
+        }
     } else {
-        // CLI mode - just save file
-        let out_path = cli
-            .out
-            .clone()
-            .unwrap_or_else(|| default_out_path(cli.seed));
+        let rows: Vec<String> = entries
+            .iter()
+            .map(|(path, item)| {
+                format!(
+                    "  {{\"file\": {}, \"seed\": {}, \"bpm\": {}, \"bars\": {}, \"root\": {}, \"scale\": {}}}",
+                    json_string(path),
+                    item.seed,
+                    item.bpm,
+                    item.bars,
+                    json_string(&note_to_string(item.root.as_u8())),
+                    json_string(&item.scale)
+                )
+            })
+            .collect();
+        out.push_str(&format!("[\n{}\n]\n", rows.join(",\n")));
+    }
+    out
+}
+
+fn run_batch(cli: &Cli) -> Result<(), Box<dyn Error>> {
+    let mut written = Vec::new();
+    for (i, (item, seq)) in generate_batch(cli, cli.jobs)?.into_iter().enumerate() {
+        let out_path = match &cli.out {
+            Some(base) => indexed_out_path(base, i),
+            None => default_out_path(item.seed),
+        };
+        let out_path = save_output(&seq, &item, &out_path)?;
+        eprintln!("Wrote {}", out_path);
+        if cli.round_trip_check {
+            round_trip_check(&seq, &out_path)?;
+        }
+        written.push((out_path, item));
+    }
+    if let Some(path) = &cli.manifest {
+        let csv = path.to_ascii_lowercase().ends_with(".csv");
+        write_file(path, batch_manifest(&written, csv).as_bytes())?;
+        eprintln!("Wrote {}", path);
+    }
+    Ok(())
+}
+
+/// The --seed-from-time seed for `now` (its nanosecond timestamp) and the line logging
+/// it as a `--seed` to reuse.
+fn time_seed(now: chrono::DateTime<chrono::Utc>) -> Result<(u64, String), Box<dyn Error>> {
+    let nanos = now
+        .timestamp_nanos_opt()
+        .ok_or("system time is outside the representable range")?;
+    let seed = nanos as u64;
+    let log = format!(
+        "Seed from time {}: --seed {}",
+        now.to_rfc3339_opts(chrono::SecondsFormat::Nanos, true),
+        seed
+    );
+    Ok((seed, log))
+}
+
+/// Writes the .mid (or its split clips) to --out or a default name, unless --gui was
+/// given without --save.
+fn write_outputs(seq: &MidiSequence, cli: &Cli) -> Result<(), Box<dyn Error>> {
+    if cli.gui && !cli.save {
+        return Ok(());
+    }
+    let out_path = cli
+        .out
+        .clone()
+        .unwrap_or_else(|| default_out_path(cli.seed));
 
-        save_sequence(&seq, &cli, &out_path)?;
+    if cli.split_bars {
+        save_split_bars(seq, cli, &out_path)?;
+    } else if cli.split_channels {
+        save_split_channels(seq, cli, &out_path)?;
+    } else {
+        let out_path = save_output(seq, cli, &out_path)?;
         eprintln!("Wrote {}", out_path);
-        Ok(())
+        if cli.round_trip_check {
+            round_trip_check(seq, &out_path)?;
+            eprintln!("Round-trip check passed: {} notes", seq.notes.len());
+        }
+    }
+    Ok(())
+}
+
+// ============================================================================
+// MAIN
+// ============================================================================
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let matches = Cli::command().get_matches();
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if let Some(path) = &cli.watch {
+        return watch_params(&cli, &matches, path);
+    }
+    apply_config_file(&mut cli, &matches)?;
+    if let Some(program) = cli.instrument {
+        cli.program = program;
+    }
+
+    if cli.list_midi_ports {
+        return list_midi_ports();
+    }
+    if let Some(path) = &cli.reproduce {
+        return reproduce(path, cli.out.as_deref());
+    }
+
+    if cli.seed_from_time {
+        let (seed, log) = time_seed(chrono::Utc::now())?;
+        cli.seed = seed;
+        eprintln!("{}", log);
+    }
+    load_cli_files(&mut cli)?;
+    resolve_scale(&cli.scale, &cli.user_scales)?;
+    check_song_length(cli.bars, cli.ppqn, cli.time_signature)?;
+    if let Some(seconds) = cli.length_seconds {
+        if !(seconds > 0.0 && seconds.is_finite()) {
+            return Err(format!("--length-seconds {seconds} must be a positive number").into());
+        }
+        let bars = bars_for_seconds(seconds, cli.bpm, cli.time_signature);
+        check_song_length(bars, cli.ppqn, cli.time_signature)?;
+    }
+    if cli.manifest.is_some() && cli.count == 1 {
+        return Err("--manifest is written for --count batches only".into());
+    }
+    if cli.count > 1 {
+        if cli.gui {
+            return Err("--count cannot be combined with --gui".into());
+        }
+        return run_batch(&cli);
+    }
+
+    let seq = generate_from_cli(&mut cli)?;
+    if let Some(target) = cli.length_seconds {
+        let quarters = cli.bars as f64 * seq.bar_ticks() as f64 / seq.ppqn as f64;
+        eprintln!(
+            "Using {} bars for {}s: {:.2}s long",
+            cli.bars,
+            target,
+            quarters * 60.0 / cli.bpm as f64
+        );
+    }
+    if let Some(target) = cli.bars_from_notes {
+        eprintln!(
+            "Using {} bars to reach {} notes ({} generated)",
+            cli.bars,
+            target,
+            seq.notes.len()
+        );
+    }
+    if cli.poly_report {
+        let (voices, tick) = max_polyphony(&seq);
+        let bar_ticks = seq.bar_ticks();
+        eprintln!(
+            "Peak polyphony: {} voices at tick {} (bar {})",
+            voices,
+            tick,
+            tick / bar_ticks.max(1) + 1
+        );
+    }
+
+    if let Some(path) = &cli.humanize_report_json {
+        let mut bytes = serde_json::to_vec_pretty(&groove_report(&seq))?;
+        bytes.push(b'\n');
+        write_file(path, &bytes)?;
+        eprintln!("Wrote {}", path);
+    }
+
+    if cli.dry_run {
+        print_summary(&seq, cli.seed);
+        return Ok(());
+    }
+
+    if let Some(grid) = cli.seed_grid {
+        std::env::set_var("MACROQUAD_WINDOW_WIDTH", "1400");
+        std::env::set_var("MACROQUAD_WINDOW_HEIGHT", "700");
+
+        macroquad::Window::new("MIDI Seed Generator - Seed Grid", async move {
+            run_seed_grid(cli, grid).await;
+        });
+
+        return Ok(());
+    }
+
+    write_outputs(&seq, &cli)?;
+    if let Some(path) = &cli.export_ly {
+        let scale = resolve_scale(&cli.scale, &cli.user_scales)?;
+        let source = to_lilypond(&seq, cli.root.as_u8(), &scale, seq.time_signature);
+        write_file(path, source.as_bytes())?;
+        eprintln!("Wrote {}", path);
+    }
+    #[cfg(feature = "wav")]
+    if let Some(path) = &cli.render_wav {
+        random_midi_gen::render_wav(&seq, path)?;
+        eprintln!("Wrote {}", path);
+    }
+
+    if cli.gui {
+        // Launch GUI - macroquad::Window::new takes a label, not Conf
+        // We set window config via environment variables before launching
+        std::env::set_var("MACROQUAD_WINDOW_WIDTH", "1400");
+        std::env::set_var("MACROQUAD_WINDOW_HEIGHT", "700");
+
+        macroquad::Window::new("MIDI Seed Generator", async move {
+            run_gui(cli, seq, false).await;
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cli(args: &[&str]) -> Cli {
+        Cli::try_parse_from(std::iter::once("midi-seed-gen").chain(args.iter().copied())).unwrap()
+    }
+
+    #[test]
+    fn time_stretch_by_two_and_half_is_exact() {
+        let base = generate_sequence(&cli(&["--bars", "4"]).gen_config()).unwrap();
+        let mut doubled = base.clone();
+        time_stretch(&mut doubled, "2".parse().unwrap()).unwrap();
+        for (a, b) in base.notes.iter().zip(&doubled.notes) {
+            assert_eq!(
+                (b.start_tick, b.end_tick),
+                (a.start_tick * 2, a.end_tick * 2)
+            );
+        }
+        assert_eq!(doubled.total_ticks, base.total_ticks * 2);
+
+        time_stretch(&mut doubled, "0.5".parse().unwrap()).unwrap();
+        assert_eq!(doubled.notes, base.notes);
+        assert_eq!(doubled.total_ticks, base.total_ticks);
+    }
+
+    #[test]
+    fn stretch_factors_are_bounded() {
+        for bad in ["0", "65", "1/65", "18446744073709551615", "1/0", "x"] {
+            assert!(bad.parse::<Ratio>().is_err(), "{bad}");
+        }
+        for good in ["64", "1/64", "3/2", "0.25"] {
+            assert!(good.parse::<Ratio>().is_ok(), "{good}");
+        }
+        let factor = Ratio {
+            num: 1 << 40,
+            den: 1,
+        };
+        assert!(factor.scale(u32::MAX).is_err());
+    }
+
+    fn encode(args: &[&str]) -> Vec<u8> {
+        let mut cli = cli(args);
+        let seq = generate_from_cli(&mut cli).unwrap();
+        encode_sequence(&seq, &cli.gen_config()).unwrap()
+    }
+
+    #[test]
+    fn embedded_command_reproduces_byte_identical() {
+        let bytes = encode(&[
+            "--seed",
+            "77",
+            "--bars",
+            "4",
+            "--swing",
+            "30",
+            "--embed-command",
+        ]);
+        let path = std::env::temp_dir().join("midi-seed-gen-reproduce-test.mid");
+        fs::write(&path, &bytes).unwrap();
+        let result = reproduce(path.to_str().unwrap(), None);
+        fs::remove_file(&path).unwrap();
+        result.unwrap();
+    }
+
+    #[test]
+    fn composer_text_does_not_hijack_the_embedded_command() {
+        let composer = "midi-seed-gen --seed=1 --bars=64";
+        let plain = encode(&["--composer", composer]);
+        assert_eq!(embedded_command(&plain).unwrap(), None);
+
+        let embedded = encode(&["--composer", composer, "--embed-command"]);
+        let command = embedded_command(&embedded).unwrap().unwrap();
+        assert!(command.contains("--embed-command"), "{command}");
+        assert!(command.contains("--seed=12648430"), "{command}");
+    }
+
+    #[test]
+    fn bars_from_notes_is_minimal() {
+        let count = |cli: &Cli, bars: u32| {
+            let mut trial = cli.clone();
+            trial.bars = bars;
+            generate_sequence(&trial.gen_config()).unwrap().notes.len() as u32
+        };
+        for (density, target) in [("0.45", 50), ("0.1", 7), ("0.9", 300)] {
+            let cli = cli(&[
+                "--density",
+                density,
+                "--bars-from-notes",
+                &target.to_string(),
+            ]);
+            let bars = bars_for_note_count(&cli, target).unwrap();
+            assert!(count(&cli, bars) >= target);
+            assert!(bars == 1 || count(&cli, bars - 1) < target);
+        }
+        let sparse = cli(&["--density", "0"]);
+        assert!(bars_for_note_count(&sparse, 1).is_err());
+    }
+
+    #[test]
+    fn seed_grid_cells_are_distinct() {
+        let grid: SeedGrid = "4x3".parse().unwrap();
+        let seeds = grid_seeds(u64::MAX - 5, grid);
+        assert_eq!(seeds.len(), 12);
+        let cells = grid_cells(&cli(&["--bars", "2"]), grid);
+        assert_eq!(cells.len(), 12);
+        for (i, (a, seq_a)) in cells.iter().enumerate() {
+            assert_eq!(a.seed, cli(&[]).seed.wrapping_add(i as u64));
+            let seq_a = seq_a.as_ref().unwrap();
+            for (j, (_, seq_b)) in cells.iter().enumerate().skip(i + 1) {
+                assert_ne!(
+                    seq_a.notes,
+                    seq_b.as_ref().unwrap().notes,
+                    "cells {i} and {j}"
+                );
+                assert_ne!(seeds[i], seeds[j]);
+            }
+        }
+    }
+
+    #[test]
+    fn legato_runs_after_timing_passes() {
+        for seed in ["1", "2", "3", "4"] {
+            let args = format!(
+                "--seed {seed} --swing 60 --humanize 100 --length-humanize 0.5 \
+                 --tempo-wobble 0.5 2 --time-stretch 3/2 --legato --voices 2"
+            );
+            let mut cli = cli(&args.split_whitespace().collect::<Vec<_>>());
+            let seq = generate_from_cli(&mut cli).unwrap();
+            let mut lines: BTreeMap<(u8, u8), Vec<&MidiNote>> = BTreeMap::new();
+            for note in &seq.notes {
+                assert!(note.end_tick > note.start_tick, "seed {seed}: {note:?}");
+                lines
+                    .entry((note.track, note.channel))
+                    .or_default()
+                    .push(note);
+            }
+            for line in lines.values() {
+                for pair in line.windows(2) {
+                    assert!(pair[0].start_tick <= pair[1].start_tick);
+                    if pair[0].start_tick < pair[1].start_tick {
+                        assert!(
+                            pair[0].end_tick <= pair[1].start_tick,
+                            "seed {seed}: {pair:?}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn drum_grooves_place_their_signature_hits() {
+        let onsets = |kind, note| {
+            let lanes = drum_groove(kind, 16);
+            let (_, hits) = lanes.into_iter().find(|(n, _)| *n == note).unwrap();
+            (0..16).filter(|&step| hits[step]).collect::<Vec<_>>()
+        };
+        assert_eq!(onsets(DrumGroove::House, KICK), [0, 4, 8, 12]);
+        assert_eq!(onsets(DrumGroove::Dnb, SNARE), [4, 11]);
+        assert_eq!(drum_groove(DrumGroove::Rock, 12)[0].1.len(), 12);
+
+        let sig = TimeSig { num: 4, den: 4 };
+        let notes = drum_notes(
+            480 * 16,
+            480,
+            sig,
+            7,
+            RngAlgo::Chacha8,
+            1,
+            Some(DrumGroove::House),
+        );
+        let kicks: Vec<u32> = notes
+            .iter()
+            .filter(|n| n.pitch == KICK)
+            .map(|n| n.start_tick)
+            .collect();
+        assert_eq!(kicks, (0..16).map(|beat| beat * 480).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn drum_feel_leans_each_limb_differently() {
+        let sig = TimeSig { num: 4, den: 4 };
+        let total = 480 * 64;
+        for feel in [DrumFeel::Tight, DrumFeel::Loose, DrumFeel::Pushed] {
+            let mut notes = drum_notes(total, 480, sig, 3, RngAlgo::Chacha8, 1, None);
+            apply_drum_feel(&mut notes, feel, 480, total, 3, RngAlgo::Chacha8);
+            let lean = |pitch: u8| {
+                let offsets: Vec<i64> = notes
+                    .iter()
+                    .filter(|n| n.pitch == pitch && n.grid_tick > 0)
+                    .map(|n| n.grid_offset())
+                    .collect();
+                offsets.iter().sum::<i64>() as f64 / offsets.len() as f64
+            };
+            let (kick, hat) = (lean(KICK), lean(CLOSED_HAT));
+            let expected = |note| 120.0 * feel.limb(note).0 as f64 / 100.0;
+            assert!((kick - expected(KICK)).abs() < 3.0, "{feel:?} kick {kick}");
+            assert!(
+                (hat - expected(CLOSED_HAT)).abs() < 1.0,
+                "{feel:?} hat {hat}"
+            );
+            assert!(hat < kick - 2.0, "{feel:?}: hats {hat} vs kicks {kick}");
+        }
+    }
+
+    #[test]
+    fn humanize_channel_sets_each_layer_amount() {
+        let run = |args: &str| {
+            let mut cli = cli(&args.split_whitespace().collect::<Vec<_>>());
+            generate_from_cli(&mut cli).unwrap()
+        };
+        let seq = run("--drums --bars 8 --humanize-channel 9:5 --humanize-channel 0:80");
+        let spread = |channel: u8| {
+            let offsets = seq
+                .notes
+                .iter()
+                .filter(|n| n.channel == channel && n.grid_tick > 0);
+            offsets.map(|n| n.grid_offset().abs()).max().unwrap()
+        };
+        // A sixteenth is 120 ticks: 5 allows 1 tick either way, 80 allows 24.
+        assert_eq!(spread(DRUM_CHANNEL), 1);
+        assert!(spread(0) > 1 && spread(0) <= 24, "{}", spread(0));
+
+        // The drum jitter has streams of its own, so the melody matches a run without it.
+        let melody = |seq: &MidiSequence| -> Vec<MidiNote> {
+            seq.notes
+                .iter()
+                .filter(|n| n.channel == 0)
+                .cloned()
+                .collect()
+        };
+        let plain = run("--drums --bars 8 --humanize-channel 0:80");
+        assert_eq!(melody(&plain), melody(&seq));
+    }
+
+    #[test]
+    fn groove_scope_leaves_other_channels_straight() {
+        let run = |args: &str| {
+            let mut cli = cli(&args.split_whitespace().collect::<Vec<_>>());
+            generate_from_cli(&mut cli).unwrap()
+        };
+        let grooved = "--drums --bars 8 --swing 60 --humanize 80 --humanize-channel 9:50";
+        let seq = run(&format!("{grooved} --drum-feel loose --groove-scope 0"));
+        let step = seq.ppqn as u32 / 4;
+        let drums: Vec<_> = seq.notes.iter().filter(|n| n.channel == 9).collect();
+        assert!(!drums.is_empty());
+        assert!(drums.iter().all(|n| n.start_tick % step == 0));
+        assert!(seq
+            .notes
+            .iter()
+            .any(|n| n.channel == 0 && n.start_tick % step != 0));
+        // An empty scope is the same as naming no scope at all.
+        let all = run(&format!("{grooved} --drum-feel loose"));
+        assert!(all
+            .notes
+            .iter()
+            .any(|n| n.channel == 9 && n.start_tick % step != 0));
+        // Scoped to the drums alone, their off-beat sixteenths swing and the melody
+        // stays on the grid.
+        let drums_only = run("--drums --drum-groove funk --bars 8 --swing 60 --groove-scope 9");
+        let swing_ticks = step * 60 / 300;
+        let (off_beats, on_beats): (Vec<_>, Vec<_>) = drums_only
+            .notes
+            .iter()
+            .filter(|n| n.channel == 9)
+            .partition(|n| (n.grid_tick / step) % 2 == 1);
+        assert!(!off_beats.is_empty());
+        assert!(off_beats
+            .iter()
+            .all(|n| n.start_tick == n.grid_tick + swing_ticks));
+        assert!(on_beats.iter().all(|n| n.start_tick == n.grid_tick));
+        assert!(drums_only
+            .notes
+            .iter()
+            .filter(|n| n.channel == 0)
+            .all(|n| n.start_tick % step == 0));
+    }
+
+    #[test]
+    fn regroove_keeps_pitches_and_zero_restores_the_base() {
+        let mut base_cli = cli(&["--bars", "8", "--chords"]);
+        let base = generate_from_cli(&mut base_cli).unwrap();
+        let mut live = cli(&["--bars", "8", "--chords"]);
+        let pitches = |seq: &MidiSequence| {
+            let mut pitches: Vec<_> = seq.notes.iter().map(|n| (n.grid_tick, n.pitch)).collect();
+            pitches.sort();
+            pitches
+        };
+        let grooved = regroove(&mut live, 60, 80).unwrap();
+        assert_ne!(grooved.notes, base.notes);
+        assert_eq!(pitches(&grooved), pitches(&base));
+        let restored = regroove(&mut live, 0, 0).unwrap();
+        assert_eq!(restored.notes, base.notes);
+    }
+
+    #[test]
+    fn humanize_report_json_has_an_entry_per_note() {
+        let mut report_cli = cli(&["--bars", "8", "--drums", "--humanize", "100"]);
+        let seq = generate_from_cli(&mut report_cli).unwrap();
+        let json = serde_json::to_value(groove_report(&seq)).unwrap();
+        let entries = json.as_array().unwrap();
+        assert_eq!(entries.len(), seq.notes.len());
+        let max_offset = (seq.ppqn as i64 / 4) / 4;
+        for (entry, note) in entries.iter().zip(&seq.notes) {
+            let field = |name: &str| entry[name].as_i64().unwrap();
+            assert_eq!(entry.as_object().unwrap().len(), 4);
+            assert_eq!(field("original_tick"), note.grid_tick as i64);
+            assert_eq!(field("channel"), note.channel as i64);
+            assert!(field("timing_offset").abs() <= max_offset, "{entry}");
+            assert!(field("velocity_delta").abs() <= 12, "{entry}");
+        }
+        assert!(entries.iter().any(|e| e["timing_offset"] != 0));
+        assert!(entries.iter().any(|e| e["velocity_delta"] != 0));
+    }
+
+    #[test]
+    fn watch_debounces_changes_and_keeps_the_last_good_output() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mtime = |secs| Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+        let mut debounce = WatchDebounce::new(mtime(1), Duration::from_millis(300));
+        assert!(!debounce.observe(mtime(1), at(500)));
+        // A burst of saves regenerates once, after the last one settles.
+        assert!(!debounce.observe(mtime(2), at(1000)));
+        assert!(!debounce.observe(mtime(3), at(1200)));
+        assert!(!debounce.observe(mtime(3), at(1400)));
+        assert!(debounce.observe(mtime(3), at(1500)));
+        assert!(!debounce.observe(mtime(3), at(2000)));
+        assert!(!debounce.observe(None, at(2100)));
+        assert!(debounce.observe(None, at(2400)));
+
+        let dir = std::env::temp_dir().join("midi-seed-gen-watch-test");
+        fs::create_dir_all(&dir).unwrap();
+        let params = dir.join("params.toml");
+        let out = dir.join("out.mid");
+        let (params, out) = (params.to_str().unwrap(), out.to_str().unwrap());
+        let matches =
+            Cli::command().get_matches_from(["midi-seed-gen", "--watch", params, "-o", out]);
+        let cli = Cli::from_arg_matches(&matches).unwrap();
+        fs::write(params, "seed = 7\nbars = 2\n").unwrap();
+        regenerate_watched(&cli, &matches, params).unwrap();
+        let good = fs::read(out).unwrap();
+        fs::write(params, "seed = \"seven\"\n").unwrap();
+        assert!(regenerate_watched(&cli, &matches, params).is_err());
+        assert_eq!(fs::read(out).unwrap(), good);
+        fs::write(params, "seed = 8\nbars = 2\n").unwrap();
+        regenerate_watched(&cli, &matches, params).unwrap();
+        assert_ne!(fs::read(out).unwrap(), good);
+    }
+
+    /// A fresh directory under the system temp dir for one test's output files.
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("midi-seed-gen-{name}"));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn batch_output_is_independent_of_jobs() {
+        let files = |jobs: &str| {
+            let dir = temp_dir(&format!("batch-jobs-{jobs}"));
+            let out = dir.join("take.mid");
+            run_batch(&cli(&[
+                "--count",
+                "8",
+                "--jobs",
+                jobs,
+                "-o",
+                out.to_str().unwrap(),
+            ]))
+            .unwrap();
+            (0..8)
+                .map(|i| fs::read(indexed_out_path(out.to_str().unwrap(), i)).unwrap())
+                .collect::<Vec<_>>()
+        };
+        let serial = files("1");
+        assert_eq!(serial, files("4"));
+        assert_eq!(serial, files("8"));
+        assert_ne!(serial[0], serial[1]);
+    }
+
+    #[test]
+    fn scales_file_scales_resolve_by_name() {
+        let path = temp_dir("scales-file").join("scales.toml");
+        fs::write(&path, "hirajoshi = [0, 2, 3, 7, 8]\n").unwrap();
+        let args = format!(
+            "--scales-file {} --scale hirajoshi --bars 8",
+            path.display()
+        );
+        let mut cli = cli(&args.split_whitespace().collect::<Vec<_>>());
+        load_cli_files(&mut cli).unwrap();
+        assert_eq!(
+            resolve_scale("hirajoshi", &cli.user_scales).unwrap(),
+            [0, 2, 3, 7, 8]
+        );
+        let seq = generate_from_cli(&mut cli).unwrap();
+        assert!(!seq.notes.is_empty());
+        let root = cli.root.as_u8();
+        assert!(seq
+            .notes
+            .iter()
+            .all(|n| [0, 2, 3, 7, 8].contains(&((n.pitch as i16 - root as i16).rem_euclid(12)))));
+        assert!(resolve_scale("hirajoshi", &BTreeMap::new()).is_err());
+    }
+
+    #[test]
+    fn double_and_half_time_scale_onset_spacing() {
+        let gaps = |extra: &[&str]| {
+            let mut args = vec!["--seed", "5", "--bars", "4"];
+            args.extend(extra);
+            let seq = generate_from_cli(&mut cli(&args)).unwrap();
+            let starts: Vec<u32> = seq.notes.iter().map(|n| n.start_tick).collect();
+            starts.windows(2).map(|p| p[1] - p[0]).collect::<Vec<_>>()
+        };
+        let base = gaps(&[]);
+        assert!(base.iter().any(|&gap| gap > 0));
+        let halved: Vec<u32> = base.iter().map(|gap| gap / 2).collect();
+        let doubled: Vec<u32> = base.iter().map(|gap| gap * 2).collect();
+        assert_eq!(gaps(&["--double-time"]), halved);
+        assert_eq!(gaps(&["--half-time"]), doubled);
+    }
+
+    #[test]
+    fn guide_track_clicks_every_beat() {
+        for (sig, beats, beat_ticks) in [("4/4", 4, 480), ("6/8", 6, 240)] {
+            let args = ["--bars", "4", "--time-signature", sig, "--guide-track"];
+            let seq = generate_from_cli(&mut cli(&args)).unwrap();
+            let clicks: Vec<&MidiNote> = seq.notes.iter().filter(|n| n.channel == 9).collect();
+            assert_eq!(clicks.len(), 4 * beats, "{sig}");
+            for (beat, click) in clicks.iter().enumerate() {
+                assert_eq!(click.start_tick, beat as u32 * beat_ticks, "{sig}");
+                assert_eq!(click.pitch, if beat % beats == 0 { 76 } else { 77 });
+            }
+        }
+    }
+
+    #[test]
+    fn embedded_command_round_trips_with_the_seed() {
+        let args = [
+            "--seed",
+            "4242",
+            "--bpm",
+            "99",
+            "--title",
+            "Two words",
+            "--embed-command",
+        ];
+        let command = embedded_command(&encode(&args)).unwrap().unwrap();
+        assert_eq!(command, normalized_command(&cli(&args)));
+        assert!(command.contains("--seed=4242"), "{command}");
+        let parsed = Cli::try_parse_from(split_command(&command).unwrap()).unwrap();
+        assert_eq!(parsed.seed, 4242);
+        assert_eq!(parsed.title.as_deref(), Some("Two words"));
+        assert_eq!(normalized_command(&parsed), command);
+    }
+
+    #[test]
+    fn key_change_shifts_only_notes_from_its_bar() {
+        let base = generate_from_cli(&mut cli(&["--bars", "6"])).unwrap();
+        let changed_args = [
+            "--bars",
+            "6",
+            "--key-change",
+            "3:+5",
+            "--key-change",
+            "5:-2",
+        ];
+        let changed = generate_from_cli(&mut cli(&changed_args)).unwrap();
+        assert_eq!(base.notes.len(), changed.notes.len());
+        let bar = base.bar_ticks();
+        for (before, after) in base.notes.iter().zip(&changed.notes) {
+            let shift = match before.start_tick / bar {
+                0 | 1 => 0,
+                2 | 3 => 5,
+                _ => 3,
+            };
+            assert_eq!(
+                after.pitch as i16,
+                before.pitch as i16 + shift,
+                "{before:?}"
+            );
+            assert_eq!(after.start_tick, before.start_tick);
+        }
+        assert_eq!(changed.key_changes, [(2 * bar, 5), (4 * bar, 3)]);
+    }
+
+    #[test]
+    fn length_humanize_moves_only_ends() {
+        let base = generate_from_cli(&mut cli(&["--bars", "8"])).unwrap();
+        let args = ["--bars", "8", "--length-humanize", "0.5"];
+        let varied = generate_from_cli(&mut cli(&args)).unwrap();
+        assert_eq!(
+            varied.notes,
+            generate_from_cli(&mut cli(&args)).unwrap().notes
+        );
+        assert_eq!(base.notes.len(), varied.notes.len());
+        for (a, b) in base.notes.iter().zip(&varied.notes) {
+            assert_eq!(b.start_tick, a.start_tick);
+            assert_eq!(b.start_tick, b.grid_tick);
+            assert!(b.end_tick > b.start_tick && b.end_tick <= varied.total_ticks);
+        }
+        assert!(base
+            .notes
+            .iter()
+            .zip(&varied.notes)
+            .any(|(a, b)| a.end_tick != b.end_tick));
+    }
+
+    #[test]
+    fn preview_bars_stops_dispatch_at_the_limit() {
+        let seq = generate_from_cli(&mut cli(&["--bars", "8", "--density", "1"])).unwrap();
+        let bar = seq.bar_ticks();
+        let limit = playback_tick_limit(&seq, Some(2));
+        assert_eq!(limit, 2 * bar);
+        assert_eq!(playback_tick_limit(&seq, None), seq.total_ticks);
+        assert_eq!(playback_tick_limit(&seq, Some(100)), seq.total_ticks);
+        let sent = |ticks: std::ops::Range<u32>| {
+            ticks
+                .map(|tick| messages_at_tick(&seq, tick, limit).len())
+                .sum::<usize>()
+        };
+        assert!(sent(0..limit) > 0);
+        assert_eq!(sent(limit..seq.total_ticks + 1), 0);
+    }
+
+    #[test]
+    fn safety_note_off_ends_each_track_with_cc_123() {
+        let cc_123 = |kind: &TrackEventKind| match kind {
+            TrackEventKind::Midi {
+                channel,
+                message: MidiMessage::Controller { controller, .. },
+            } if controller.as_int() == 123 => Some(channel.as_int()),
+            _ => None,
+        };
+        let bytes = encode(&[
+            "--drums",
+            "--guide-track",
+            "--guide-channel",
+            "10",
+            "--safety-note-off",
+        ]);
+        let smf = Smf::parse(&bytes).unwrap();
+        let mut ended = 0;
+        for track in &smf.tracks {
+            let mut used: Vec<u8> = track
+                .iter()
+                .filter_map(|e| match e.kind {
+                    TrackEventKind::Midi { channel, .. } => Some(channel.as_int()),
+                    _ => None,
+                })
+                .collect();
+            used.sort_unstable();
+            used.dedup();
+            let (last, body) = track.split_last().unwrap();
+            assert_eq!(last.kind, TrackEventKind::Meta(MetaMessage::EndOfTrack));
+            let tail = &body[body.len() - used.len()..];
+            let mut closed: Vec<u8> = tail.iter().filter_map(|e| cc_123(&e.kind)).collect();
+            closed.sort_unstable();
+            assert_eq!(closed, used);
+            ended += used.len();
+        }
+        assert_eq!(ended, 3);
+        let plain = encode(&["--drums"]);
+        let plain = Smf::parse(&plain).unwrap();
+        assert!(plain
+            .tracks
+            .iter()
+            .flatten()
+            .all(|e| cc_123(&e.kind).is_none()));
+    }
+
+    /// A one-track, channel-0 sequence of `(pitch, start, end)` notes at 480 PPQN.
+    fn sequence_of(spans: &[(u8, u32, u32)]) -> MidiSequence {
+        MidiSequence {
+            notes: spans
+                .iter()
+                .map(|&(pitch, start_tick, end_tick)| MidiNote {
+                    pitch,
+                    start_tick,
+                    end_tick,
+                    velocity: 80,
+                    channel: 0,
+                    track: 0,
+                    grid_tick: start_tick,
+                    velocity_jitter: 0,
+                })
+                .collect(),
+            bpm: 120,
+            ppqn: 480,
+            total_ticks: spans.iter().map(|s| s.2).max().unwrap_or(0),
+            time_signature: TimeSig::COMMON,
+            key_changes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn transpose_to_key_moves_c_major_to_g() {
+        // A C major scale, then a long C major triad.
+        let mut spans: Vec<(u8, u32, u32)> = [60, 62, 64, 65, 67, 69, 71, 72]
+            .iter()
+            .enumerate()
+            .map(|(i, &pitch)| (pitch, i as u32 * 480, (i as u32 + 1) * 480))
+            .collect();
+        spans.extend([(48, 3840, 7680), (52, 3840, 7680), (55, 3840, 7680)]);
+        let original = sequence_of(&spans);
+        assert_eq!(estimate_key(&original), (0, false));
+
+        let mut moved = original.clone();
+        let (tonic, minor, shift) = transpose_to_key(&mut moved, 7);
+        assert_eq!((tonic, minor), (0, false));
+        assert_eq!(shift.rem_euclid(12), 7);
+        for (a, b) in original.notes.iter().zip(&moved.notes) {
+            assert_eq!(b.pitch as i16 - a.pitch as i16, shift);
+        }
+        assert_eq!(estimate_key(&moved), (7, false));
+    }
+
+    #[test]
+    fn merge_puts_each_file_on_its_own_track() {
+        let dir = temp_dir("merge");
+        let mut paths = Vec::new();
+        for seed in ["1", "2", "3"] {
+            let path = dir
+                .join(format!("{seed}.mid"))
+                .to_string_lossy()
+                .into_owned();
+            let mut source = cli(&["--seed", seed, "--bars", "2", "-o", &path]);
+            let seq = generate_from_cli(&mut source).unwrap();
+            save_sequence(&seq, &source.gen_config(), &path).unwrap();
+            paths.push(path);
+        }
+        let merged = merge_sequences(&paths).unwrap();
+        for (track, path) in paths.iter().enumerate() {
+            // Compared with each file as read back: overlapping repeats of one pitch pair
+            // up by the file's note-offs, not the generator's.
+            let source = load_sequence(path).unwrap();
+            let notes: Vec<_> = merged
+                .notes
+                .iter()
+                .filter(|n| n.track == track as u8)
+                .map(|n| (n.pitch, n.start_tick, n.end_tick, n.velocity))
+                .collect();
+            let expected: Vec<_> = source
+                .notes
+                .iter()
+                .map(|n| (n.pitch, n.start_tick, n.end_tick, n.velocity))
+                .collect();
+            assert_eq!(notes, expected, "track {track}");
+        }
+        let bytes = encode_sequence(&merged, &cli(&[]).gen_config()).unwrap();
+        let smf = Smf::parse(&bytes).unwrap();
+        let with_notes = smf
+            .tracks
+            .iter()
+            .filter(|t| {
+                t.iter()
+                    .any(|e| matches!(e.kind, TrackEventKind::Midi { .. }))
+            })
+            .count();
+        assert_eq!(with_notes, 3);
+    }
+
+    #[test]
+    fn max_polyphony_finds_the_first_peak() {
+        // Two voices from 0, a third joins at 240; the 480 note starts as one ends.
+        let seq = sequence_of(&[
+            (60, 0, 480),
+            (64, 0, 960),
+            (67, 240, 600),
+            (72, 480, 720),
+            (48, 960, 1200),
+        ]);
+        assert_eq!(max_polyphony(&seq), (3, 240));
+        assert_eq!(
+            max_polyphony(&sequence_of(&[(60, 0, 10), (62, 10, 20)])),
+            (1, 0)
+        );
+        assert_eq!(max_polyphony(&sequence_of(&[])), (0, 0));
+    }
+
+    #[test]
+    fn save_writes_the_file_alongside_the_gui() {
+        let dir = temp_dir("save-with-gui");
+        let written = |extra: &[&str], name: &str| {
+            let path = dir.join(name);
+            let mut args = vec!["-o", path.to_str().unwrap()];
+            args.extend(extra);
+            let mut cli = cli(&args);
+            let seq = generate_from_cli(&mut cli).unwrap();
+            write_outputs(&seq, &cli).unwrap();
+            path.exists()
+        };
+        assert!(written(&["--gui", "--save"], "saved.mid"));
+        assert!(!written(&["--gui"], "gui-only.mid"));
+        assert!(written(&[], "plain.mid"));
+        assert!(Cli::try_parse_from(["midi-seed-gen", "--save"]).is_err());
+    }
+
+    #[test]
+    fn split_bars_clips_are_one_bar_each() {
+        let seq = generate_from_cli(&mut cli(&["--bars", "4", "--density", "0.8"])).unwrap();
+        let bar = seq.bar_ticks();
+        let mut total = 0;
+        for index in 0..4 {
+            let clip = slice_bar(&seq, index, bar);
+            assert_eq!(clip.total_ticks, bar);
+            assert!(!clip.notes.is_empty());
+            assert!(clip
+                .notes
+                .iter()
+                .all(|n| n.start_tick < bar && n.end_tick <= bar));
+            let originals = seq
+                .notes
+                .iter()
+                .filter(|n| n.start_tick / bar == index)
+                .map(|n| n.start_tick - index * bar);
+            assert!(clip.notes.iter().map(|n| n.start_tick).eq(originals));
+            total += clip.notes.len();
+        }
+        assert_eq!(total, seq.notes.len());
+    }
+
+    #[test]
+    fn drone_holds_the_tonic_for_the_whole_song() {
+        let args = [
+            "--bars",
+            "4",
+            "--root",
+            "D4",
+            "--drone",
+            "--drone-channel",
+            "3",
+        ];
+        let seq = generate_from_cli(&mut cli(&args)).unwrap();
+        let drone: Vec<&MidiNote> = seq.notes.iter().filter(|n| n.channel == 3).collect();
+        assert_eq!(drone.len(), 1);
+        assert_eq!(
+            (drone[0].pitch, drone[0].start_tick, drone[0].end_tick),
+            (50, 0, seq.total_ticks)
+        );
+    }
+
+    #[test]
+    fn mpe_gives_overlapping_notes_their_own_channels() {
+        let args = ["--bars", "4", "--chords", "--mpe"];
+        let mut mpe_cli = cli(&args);
+        let seq = generate_from_cli(&mut mpe_cli).unwrap();
+        let melody: Vec<&MidiNote> = seq.notes.iter().filter(|n| n.track == 0).collect();
+        // Fewer notes at once than the 15 member channels, so none has to be stolen.
+        assert!(max_polyphony(&seq).0 <= 15);
+        assert!(melody.iter().all(|n| (1..=15).contains(&n.channel)));
+        let mut overlaps = 0;
+        for (i, a) in melody.iter().enumerate() {
+            for b in &melody[i + 1..] {
+                if a.start_tick < b.end_tick && b.start_tick < a.end_tick {
+                    assert_ne!(a.channel, b.channel, "{a:?} {b:?}");
+                    overlaps += 1;
+                }
+            }
+        }
+        assert!(overlaps > 0);
+
+        // RPN 0 (101/100 = 0) then data entry 48 on every member channel, all at tick 0.
+        let bytes = encode_sequence(&seq, &mpe_cli.gen_config()).unwrap();
+        let smf = Smf::parse(&bytes).unwrap();
+        let mut at_zero: BTreeMap<u8, Vec<(u8, u8)>> = BTreeMap::new();
+        let mut tick = 0;
+        for event in &smf.tracks[0] {
+            tick += event.delta.as_int();
+            if let (
+                0,
+                TrackEventKind::Midi {
+                    channel,
+                    message: MidiMessage::Controller { controller, value },
+                },
+            ) = (tick, event.kind)
+            {
+                at_zero
+                    .entry(channel.as_int())
+                    .or_default()
+                    .push((controller.as_int(), value.as_int()));
+            }
+        }
+        for member in 1..=15 {
+            let messages = &at_zero[&member];
+            let rpn = messages
+                .windows(3)
+                .any(|w| w == [(101, 0), (100, 0), (6, 48)]);
+            assert!(rpn, "channel {member}: {messages:?}");
+        }
+    }
+
+    #[test]
+    fn lilypond_writes_a_quarter_note_triad() {
+        let mut seq = sequence_of(&[(60, 0, 480), (64, 480, 960), (67, 960, 1440)]);
+        seq.total_ticks = 1920;
+        let major = resolve_scale("major", &BTreeMap::new()).unwrap();
+        let source = to_lilypond(&seq, 60, &major, TimeSig::COMMON);
+        assert!(source.starts_with("\\version"), "{source}");
+        assert!(source.contains("\\relative c' {"), "{source}");
+        assert!(source.contains("\\key c \\major"), "{source}");
+        assert!(source.contains("\\time 4/4"), "{source}");
+        assert!(source.contains("c4 e4 g4 r4"), "{source}");
+        assert_eq!(source.matches('{').count(), source.matches('}').count());
+    }
+
+    #[test]
+    fn compress_velocity_pulls_toward_the_mean() {
+        let base = generate_from_cli(&mut cli(&["--bars", "4"])).unwrap();
+        let velocities =
+            |seq: &MidiSequence| seq.notes.iter().map(|n| n.velocity).collect::<Vec<_>>();
+        let sum: u32 = base.notes.iter().map(|n| n.velocity as u32).sum();
+        let mean = (sum as f32 / base.notes.len() as f32).round() as u8;
+
+        let mut flat = base.clone();
+        compress_velocities(&mut flat, 0.0);
+        assert!(flat.notes.iter().all(|n| n.velocity == mean));
+
+        let mut unchanged = base.clone();
+        compress_velocities(&mut unchanged, 1.0);
+        assert_eq!(velocities(&unchanged), velocities(&base));
+
+        let mut half = base.clone();
+        compress_velocities(&mut half, 0.5);
+        for (a, b) in base.notes.iter().zip(&half.notes) {
+            assert!(
+                (b.velocity as i16 - mean as i16).abs() <= (a.velocity as i16 - mean as i16).abs()
+            );
+        }
+    }
+
+    #[test]
+    fn seed_from_time_logs_a_reusable_seed() {
+        let now = chrono::DateTime::from_timestamp(1_760_000_000, 123_456_789).unwrap();
+        let (seed, log) = time_seed(now).unwrap();
+        assert_eq!(seed, 1_760_000_000_123_456_789);
+        assert_eq!(
+            log,
+            "Seed from time 2025-10-09T08:53:20.123456789Z: --seed 1760000000123456789"
+        );
+        let printed = log.rsplit(' ').next().unwrap();
+        let mut timed = cli(&["--seed-from-time", "--bars", "4"]);
+        timed.seed = seed;
+        let again = generate_from_cli(&mut cli(&["--seed", printed, "--bars", "4"])).unwrap();
+        assert_eq!(generate_from_cli(&mut timed).unwrap().notes, again.notes);
+    }
+
+    #[test]
+    fn tempo_wobble_follows_its_sine() {
+        // Quarter notes over 8 bars of 4/4 at 480 PPQN.
+        let spans: Vec<(u8, u32, u32)> = (0..32).map(|i| (60, i * 480, i * 480 + 60)).collect();
+        let mut seq = sequence_of(&spans);
+        seq.total_ticks = 8 * 1920;
+        let original = seq.clone();
+        tempo_wobble(&mut seq, 0.25, 0.5, 9, RngAlgo::default());
+
+        let phase = RngAlgo::default()
+            .sub_rng(9, TEMPO_WOBBLE_STREAM)
+            .gen_range(0.0..std::f64::consts::TAU);
+        let mut peak: f64 = 0.0;
+        for (before, after) in original.notes.iter().zip(&seq.notes) {
+            let bars = before.start_tick as f64 / 1920.0;
+            let expected = 120.0 * (std::f64::consts::TAU * 0.5 * bars + phase).sin();
+            assert_eq!(
+                wobble_offset(before.start_tick, 1920, 120.0, 0.5, phase),
+                expected
+            );
+            let moved = (before.start_tick as f64 + expected).round().max(0.0);
+            assert_eq!(after.start_tick as f64, moved, "{before:?}");
+            assert_eq!(after.end_tick - after.start_tick, 60);
+            peak = peak.max(expected.abs());
+        }
+        // Beats sample every eighth of a cycle, landing within cos(pi/8) of the peak.
+        assert!(peak > 110.0 && peak <= 120.0);
+    }
+
+    #[test]
+    fn split_channels_writes_one_file_per_channel() {
+        let dir = temp_dir("split-channels");
+        let base = dir.join("take.mid").to_string_lossy().into_owned();
+        let args = [
+            "--drums",
+            "--drone",
+            "--drone-channel",
+            "3",
+            "--split-channels",
+            "-o",
+            &base,
+        ];
+        let mut split_cli = cli(&args);
+        let seq = generate_from_cli(&mut split_cli).unwrap();
+        write_outputs(&seq, &split_cli).unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 3);
+        for channel in [0, 3, 9] {
+            let layer = load_sequence(&channel_out_path(&base, channel)).unwrap();
+            assert!(layer.notes.iter().all(|n| n.channel == channel));
+            let expected = seq.notes.iter().filter(|n| n.channel == channel).count();
+            assert_eq!(layer.notes.len(), expected, "channel {channel}");
+            assert!(expected > 0);
+        }
+    }
+
+    #[test]
+    fn batch_manifest_lists_each_written_file() {
+        let dir = temp_dir("manifest");
+        let out = dir.join("take.mid").to_string_lossy().into_owned();
+        let manifest = dir.join("m.json").to_string_lossy().into_owned();
+        let args = [
+            "--count",
+            "3",
+            "--seed",
+            "40",
+            "--manifest",
+            &manifest,
+            "-o",
+            &out,
+        ];
+        run_batch(&cli(&args)).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&manifest).unwrap()).unwrap();
+        let entries = json.as_array().unwrap();
+        assert_eq!(entries.len(), 3);
+        for (i, entry) in entries.iter().enumerate() {
+            let file = entry["file"].as_str().unwrap();
+            assert_eq!(file, indexed_out_path(&out, i));
+            assert_eq!(entry["seed"], 40 + i as u64);
+            // The file holds what that seed generates.
+            let mut item = cli(&["--seed", &(40 + i).to_string()]);
+            let seq = generate_from_cli(&mut item).unwrap();
+            assert_eq!(
+                fs::read(file).unwrap(),
+                encode_sequence(&seq, &item.gen_config()).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn round_trip_check_passes_clean_saves_and_catches_corruption() {
+        let dir = temp_dir("round-trip");
+        let path = dir.join("take.mid").to_string_lossy().into_owned();
+        let mut take = cli(&["--bars", "4", "--chords", "--humanize", "40"]);
+        let seq = generate_from_cli(&mut take).unwrap();
+        save_output(&seq, &take, &path).unwrap();
+        round_trip_check(&seq, &path).unwrap();
+
+        // Nudge the first note-on's key and write the file back.
+        let bytes = fs::read(&path).unwrap();
+        let mut smf = Smf::parse(&bytes).unwrap();
+        let key = smf
+            .tracks
+            .iter_mut()
+            .flatten()
+            .find_map(|e| match &mut e.kind {
+                TrackEventKind::Midi {
+                    message: MidiMessage::NoteOn { key, .. },
+                    ..
+                } => Some(key),
+                _ => None,
+            })
+            .unwrap();
+        *key = (key.as_int() + 1).into();
+        smf.save(&path).unwrap();
+        let err = round_trip_check(&seq, &path).unwrap_err().to_string();
+        assert!(err.contains("round-trip check failed"), "{err}");
+    }
+
+    #[test]
+    fn positive_pitch_tilt_makes_high_notes_louder() {
+        let args = ["--bars", "16", "--velocity-pitch-tilt", "2"];
+        let seq = generate_from_cli(&mut cli(&args)).unwrap();
+        let root = cli(&[]).root.as_u8();
+        let mean = |high: bool| {
+            let velocities: Vec<f32> = seq
+                .notes
+                .iter()
+                .filter(|n| (n.pitch > root) == high && n.pitch != root)
+                .map(|n| n.velocity as f32)
+                .collect();
+            assert!(!velocities.is_empty());
+            velocities.iter().sum::<f32>() / velocities.len() as f32
+        };
+        assert!(mean(true) > mean(false) + 10.0);
+
+        let mut tilted = sequence_of(&[(48, 0, 10), (60, 10, 20), (72, 20, 30)]);
+        tilt_velocities(&mut tilted, 60, 2.0);
+        let velocities: Vec<u8> = tilted.notes.iter().map(|n| n.velocity).collect();
+        assert_eq!(velocities, [56, 80, 104]);
+    }
+
+    #[test]
+    fn roman_holds_a_chord_per_bar() {
+        let plain = generate_from_cli(&mut cli(&["--bars", "6", "--scale", "major"])).unwrap();
+        let args = ["--bars", "6", "--scale", "major", "--roman", "I vi IV V"];
+        let seq = generate_from_cli(&mut cli(&args)).unwrap();
+        let mut chords: Vec<&MidiNote> = seq
+            .notes
+            .iter()
+            .filter(|n| !plain.notes.contains(n))
+            .collect();
+        assert_eq!(chords.len(), 6 * 3);
+        chords.sort_by_key(|n| (n.start_tick, n.pitch));
+        let bar = seq.bar_ticks();
+        let expected: [[u8; 3]; 4] = [[60, 64, 67], [69, 72, 76], [65, 69, 72], [67, 71, 74]];
+        for (i, chord) in chords.chunks(3).enumerate() {
+            let start = i as u32 * bar;
+            assert!(chord
+                .iter()
+                .all(|n| (n.start_tick, n.end_tick, n.channel) == (start, start + bar, 0)));
+            let pitches: Vec<u8> = chord.iter().map(|n| n.pitch).collect();
+            assert_eq!(pitches, expected[i % 4]);
+        }
+        assert!(generate_from_cli(&mut cli(&["--roman", "I IX"])).is_err());
     }
 }